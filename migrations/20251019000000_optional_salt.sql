@@ -0,0 +1,6 @@
+-- Password hashes are PHC strings that embed their salt, so the salt table is redundant.
+-- The previous release reads salt_id as required, so this release still gives new users
+-- a salt row and keeps the table, letting both run side by side during a rolling deploy
+-- or after a rollback. salt_id only becomes nullable here; the next release stops
+-- writing it and drops the table in its own migration.
+ALTER TABLE "user" ALTER COLUMN salt_id DROP NOT NULL;
@@ -0,0 +1,14 @@
+-- Usernames and emails are compared by their NFKC case folded form, kept in their own
+-- columns so they can be unique. Existing rows are backfilled with the closest Postgres
+-- equivalent here; on startup the service re-folds every row with the same full case
+-- folding it writes for new rows, and only then adds the unique indexes, so users that
+-- collide are reported instead of failing the migration.
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS username_normalized TEXT;
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS email_normalized TEXT;
+
+UPDATE "user" SET
+    username_normalized = lower(normalize(btrim(username), NFKC)),
+    email_normalized = lower(normalize(btrim(email), NFKC));
+
+ALTER TABLE "user" ALTER COLUMN username_normalized SET NOT NULL;
+ALTER TABLE "user" ALTER COLUMN email_normalized SET NOT NULL;
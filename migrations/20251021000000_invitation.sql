@@ -0,0 +1,23 @@
+-- Invitation codes for invite-only registration. Only the SHA-256 of a code is stored.
+CREATE TABLE IF NOT EXISTS "invitation" (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    code_hash TEXT NOT NULL UNIQUE,
+    created_by UUID NOT NULL,
+    email TEXT NULL,
+    role TEXT NULL,
+    max_uses INT NOT NULL CHECK (max_uses > 0),
+    uses INT NOT NULL DEFAULT 0,
+    expires_at TIMESTAMPTZ NULL,
+    date_created TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- One row per registration that used an invitation
+CREATE TABLE IF NOT EXISTS "invitation_use" (
+    invitation_id UUID NOT NULL REFERENCES "invitation" (id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES "user" (id) ON DELETE CASCADE,
+    date_used TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (invitation_id, user_id)
+);
+
+-- Role pre-assigned by the invitation the user registered with
+ALTER TABLE "user" ADD COLUMN IF NOT EXISTS role TEXT NULL;
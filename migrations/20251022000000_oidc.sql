@@ -0,0 +1,21 @@
+-- Sign-ins started at an external OpenID Connect provider and not come back yet
+CREATE TABLE IF NOT EXISTS "oidc_login" (
+    state TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    nonce TEXT NOT NULL,
+    pkce_verifier TEXT NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+-- External identities linked to users, one per provider subject
+CREATE TABLE IF NOT EXISTS "user_identity" (
+    provider TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    user_id UUID NOT NULL REFERENCES "user" (id) ON DELETE CASCADE,
+    email TEXT NULL,
+    date_created TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_login TIMESTAMPTZ NULL,
+    PRIMARY KEY (provider, subject)
+);
+
+CREATE INDEX IF NOT EXISTS user_identity_user_id_idx ON "user_identity" (user_id);
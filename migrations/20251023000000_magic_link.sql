@@ -0,0 +1,9 @@
+-- Passwordless login links sent by email. Only the SHA-256 of a token is stored.
+CREATE TABLE IF NOT EXISTS "magic_link" (
+    token_hash TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES "user" (id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    date_created TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IF NOT EXISTS magic_link_user_id_idx ON "magic_link" (user_id);
@@ -0,0 +1,13 @@
+-- Device authorization grants (RFC 8628) waiting for a user to approve them, or for
+-- the device to pick up its token
+CREATE TABLE IF NOT EXISTS "device_authorization" (
+    device_code_hash TEXT PRIMARY KEY,
+    user_code TEXT NOT NULL UNIQUE,
+    client_id TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    user_id UUID NULL REFERENCES "user" (id) ON DELETE CASCADE,
+    poll_interval INTEGER NOT NULL,
+    last_polled_at TIMESTAMPTZ NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    date_created TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
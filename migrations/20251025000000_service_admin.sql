@@ -0,0 +1,3 @@
+-- Administrative endpoints only accept service accounts flagged as admin. No account is
+-- an admin until one is granted with `icarus_auth service admin --id <id>`.
+ALTER TABLE "passphrase" ADD COLUMN IF NOT EXISTS is_admin BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,262 @@
+//! Users in an LDAP or Active Directory server. The user's entry is searched for
+//! with the configured service account, then the password is checked by binding as
+//! the entry. Users get a shadow user on their first successful login.
+
+use super::{BoxFuture, DirectoryUser, Error, Outcome};
+use crate::repo;
+
+pub const NAME: &str = "ldap";
+
+/// Result code of a bind with a wrong password, see RFC 4511
+const INVALID_CREDENTIALS: u32 = 49;
+
+pub struct Ldap {
+    settings: crate::config::Ldap,
+    pool: sqlx::PgPool,
+    hasher: std::sync::Arc<crate::hashing::workers::Hasher>,
+}
+
+/// Search filter for the identifier, escaped so it cannot change the filter
+pub fn filter(template: &str, identifier: &str) -> String {
+    template.replace("{identifier}", &ldap3::ldap_escape(identifier))
+}
+
+/// Reads the configured attributes of an entry. Attributes the entry does not have are
+/// left empty, except the username which is required
+pub fn map_entry(
+    attributes: &crate::config::LdapAttributes,
+    entry: &ldap3::SearchEntry,
+) -> Result<DirectoryUser, String> {
+    let get = |name: &str| -> String {
+        entry
+            .attrs
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .and_then(|(_, values)| values.first())
+            .cloned()
+            .unwrap_or_default()
+    };
+
+    let username = get(&attributes.username);
+    if username.is_empty() {
+        return Err(format!(
+            "{} has no `{}` attribute",
+            entry.dn, attributes.username
+        ));
+    }
+
+    let mut subject = get(&attributes.subject);
+    if subject.is_empty() {
+        subject = entry.dn.clone();
+    }
+
+    Ok(DirectoryUser {
+        subject,
+        username,
+        email: get(&attributes.email),
+        firstname: get(&attributes.firstname),
+        lastname: get(&attributes.lastname),
+        phone: get(&attributes.phone),
+    })
+}
+
+fn unavailable(err: ldap3::LdapError) -> Error {
+    Error::Unavailable(err.to_string())
+}
+
+impl Ldap {
+    pub fn new(
+        settings: crate::config::Ldap,
+        pool: sqlx::PgPool,
+        hasher: std::sync::Arc<crate::hashing::workers::Hasher>,
+    ) -> Self {
+        Ldap {
+            settings,
+            pool,
+            hasher,
+        }
+    }
+
+    fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.settings.timeout)
+    }
+
+    async fn connect(&self) -> Result<ldap3::Ldap, Error> {
+        let settings = ldap3::LdapConnSettings::new()
+            .set_conn_timeout(self.timeout())
+            .set_starttls(self.settings.starttls);
+        let (conn, ldap) = ldap3::LdapConnAsync::with_settings(settings, &self.settings.url)
+            .await
+            .map_err(unavailable)?;
+        ldap3::drive!(conn);
+
+        Ok(ldap)
+    }
+
+    /// The user's entry. `None` when there is no entry, or more than one
+    async fn find(&self, identifier: &str) -> Result<Option<ldap3::SearchEntry>, Error> {
+        let mut ldap = self.connect().await?;
+
+        if !self.settings.bind_dn.is_empty() {
+            ldap.with_timeout(self.timeout())
+                .simple_bind(&self.settings.bind_dn, &self.settings.bind_password)
+                .await
+                .and_then(|result| result.success())
+                .map_err(unavailable)?;
+        }
+
+        let attributes = &self.settings.attributes;
+        let (entries, _result) = ldap
+            .with_timeout(self.timeout())
+            .search(
+                &self.settings.base_dn,
+                ldap3::Scope::Subtree,
+                &filter(&self.settings.user_filter, identifier),
+                vec![
+                    attributes.subject.as_str(),
+                    attributes.username.as_str(),
+                    attributes.email.as_str(),
+                    attributes.firstname.as_str(),
+                    attributes.lastname.as_str(),
+                    attributes.phone.as_str(),
+                ],
+            )
+            .await
+            .and_then(|result| result.success())
+            .map_err(unavailable)?;
+        let _ = ldap.unbind().await;
+
+        if entries.len() > 1 {
+            tracing::warn!(
+                matches = entries.len(),
+                "LDAP filter matched more than one entry"
+            );
+            return Ok(None);
+        }
+
+        Ok(entries
+            .into_iter()
+            .next()
+            .map(ldap3::SearchEntry::construct))
+    }
+
+    /// Whether the directory accepts the password for the entry
+    async fn bind_as(&self, dn: &str, password: &str) -> Result<bool, Error> {
+        let mut ldap = self.connect().await?;
+        let result = ldap
+            .with_timeout(self.timeout())
+            .simple_bind(dn, password)
+            .await
+            .map_err(unavailable)?;
+        let _ = ldap.unbind().await;
+
+        match result.rc {
+            0 => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            _ => Err(unavailable(ldap3::LdapError::LdapResult { result })),
+        }
+    }
+
+    async fn check(&self, identifier: &str, password: &str) -> Result<Outcome, Error> {
+        // An empty password makes a bind unauthenticated, which servers accept
+        if password.is_empty() {
+            return Ok(Outcome::UnknownUser);
+        }
+
+        let Some(entry) = self.find(identifier).await? else {
+            return Ok(Outcome::UnknownUser);
+        };
+        let identifier = repo::user::identifier::kind(identifier);
+
+        if !self.bind_as(&entry.dn, password).await? {
+            return Ok(Outcome::InvalidPassword { identifier });
+        }
+
+        let directory_user =
+            map_entry(&self.settings.attributes, &entry).map_err(Error::Unavailable)?;
+        let user = super::shadow(&self.pool, &self.hasher, NAME, &directory_user).await?;
+
+        Ok(Outcome::Authenticated {
+            user: Box::new(user),
+            identifier,
+        })
+    }
+}
+
+impl super::Backend for Ldap {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        identifier: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Outcome, Error>> {
+        Box::pin(self.check(identifier, password))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        assert_eq!(
+            filter("(|(uid={identifier})(mail={identifier}))", "bob"),
+            "(|(uid=bob)(mail=bob))"
+        );
+        // Cannot widen the search
+        assert_eq!(
+            filter("(uid={identifier})", "*)(uid=*"),
+            "(uid=\\2a\\29\\28uid=\\2a)"
+        );
+    }
+
+    #[test]
+    fn test_map_entry() {
+        let attributes = crate::config::LdapAttributes::default();
+        let mut entry = ldap3::SearchEntry {
+            dn: String::from("uid=carol,ou=people,dc=example,dc=com"),
+            attrs: std::collections::HashMap::from([
+                (String::from("uid"), vec![String::from("carol")]),
+                (
+                    String::from("mail"),
+                    vec![
+                        String::from("carol@example.com"),
+                        String::from("c@example.com"),
+                    ],
+                ),
+                (String::from("givenname"), vec![String::from("Carol")]),
+                (String::from("sn"), vec![String::from("Danvers")]),
+            ]),
+            bin_attrs: std::collections::HashMap::new(),
+        };
+
+        let user = map_entry(&attributes, &entry).unwrap();
+        assert_eq!(
+            user,
+            DirectoryUser {
+                subject: entry.dn.clone(),
+                username: String::from("carol"),
+                email: String::from("carol@example.com"),
+                firstname: String::from("Carol"),
+                lastname: String::from("Danvers"),
+                phone: String::new(),
+            }
+        );
+
+        entry.attrs.insert(
+            String::from("entryUUID"),
+            vec![String::from("5f0c4a2e-9a43-4f5e-a0b4-3c6f7ad1e1c2")],
+        );
+        assert_eq!(
+            map_entry(&attributes, &entry).unwrap().subject,
+            "5f0c4a2e-9a43-4f5e-a0b4-3c6f7ad1e1c2"
+        );
+
+        entry.attrs.remove("uid");
+        assert!(map_entry(&attributes, &entry).is_err());
+    }
+}
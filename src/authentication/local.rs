@@ -0,0 +1,73 @@
+//! Users and Argon2 password hashes kept in the database
+
+use super::{BoxFuture, Error, Outcome};
+use crate::hashing;
+use crate::repo;
+
+pub const NAME: &str = "local";
+
+pub struct Local {
+    pool: sqlx::PgPool,
+    hasher: std::sync::Arc<hashing::workers::Hasher>,
+}
+
+impl Local {
+    pub fn new(pool: sqlx::PgPool, hasher: std::sync::Arc<hashing::workers::Hasher>) -> Self {
+        Local { pool, hasher }
+    }
+
+    async fn check(&self, identifier: &str, password: &str) -> Result<Outcome, Error> {
+        match repo::user::get_by_identifier(&self.pool, identifier).await {
+            Ok((user, identifier)) => {
+                let verified = match self
+                    .hasher
+                    .verify(password.to_string(), user.password.clone())
+                    .await
+                {
+                    Ok(verified) => verified,
+                    Err(hashing::workers::Error::Saturated) => return Err(Error::Busy),
+                    Err(err) => {
+                        tracing::error!(error = %err, "Could not verify password");
+                        false
+                    }
+                };
+
+                if verified {
+                    Ok(Outcome::Authenticated {
+                        user: Box::new(user),
+                        identifier,
+                    })
+                } else {
+                    Ok(Outcome::InvalidPassword { identifier })
+                }
+            }
+            Err(sqlx::Error::RowNotFound) => {
+                // Spend as long as a wrong password would so response times do not tell
+                // whether the user exists
+                if let Err(hashing::workers::Error::Saturated) =
+                    self.hasher.verify_dummy(password.to_string()).await
+                {
+                    return Err(Error::Busy);
+                }
+
+                Ok(Outcome::UnknownUser)
+            }
+            // The database being down is not a wrong password, so it must not look like one
+            Err(err) => Err(Error::Db(err)),
+        }
+    }
+}
+
+impl super::Backend for Local {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        identifier: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Outcome, Error>> {
+        Box::pin(self.check(identifier, password))
+    }
+}
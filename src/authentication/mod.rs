@@ -0,0 +1,246 @@
+//! Backends that check login credentials. The configured backends are tried in order
+//! until one accepts the credentials. Backends other than `local` keep a shadow user
+//! in the database for everyone who logs in through them, so tokens, roles and
+//! statuses work the same for every user.
+
+use rand::Rng;
+
+pub mod ldap;
+pub mod local;
+
+/// Future returned by [`Backend::authenticate`]
+pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
+
+/// What a backend made of the credentials
+pub enum Outcome {
+    Authenticated {
+        user: Box<icarus_models::user::User>,
+        /// How the user was looked up, see `repo::user::identifier`
+        identifier: &'static str,
+    },
+    /// The backend knows the user but the password is wrong
+    InvalidPassword { identifier: &'static str },
+    /// The backend does not know the user
+    UnknownUser,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Too many concurrent password hashes
+    Busy,
+    /// The backend could not be asked, e.g. its server is down
+    Unavailable(String),
+    /// The user cannot be given a shadow user because a local user has the same
+    /// username or email
+    Conflict,
+    Db(sqlx::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Busy => write!(f, "Server is busy, try again later"),
+            Error::Unavailable(err) => write!(f, "Authentication backend unavailable: {err}"),
+            Error::Conflict => write!(f, "A local user with the same username or email exists"),
+            Error::Db(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<sqlx::Error> for Error {
+    fn from(err: sqlx::Error) -> Self {
+        if crate::repo::is_unique_violation(&err) {
+            Error::Conflict
+        } else {
+            Error::Db(err)
+        }
+    }
+}
+
+impl From<crate::hashing::workers::Error> for Error {
+    fn from(err: crate::hashing::workers::Error) -> Self {
+        match err {
+            crate::hashing::workers::Error::Saturated => Error::Busy,
+            err => Error::Unavailable(err.to_string()),
+        }
+    }
+}
+
+pub trait Backend: Send + Sync {
+    /// Used in logs, and as the provider shadow users are linked under
+    fn name(&self) -> &'static str;
+
+    fn authenticate<'a>(
+        &'a self,
+        identifier: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, Result<Outcome, Error>>;
+}
+
+/// The backends in the order they are tried
+pub struct Chain {
+    backends: Vec<Box<dyn Backend>>,
+}
+
+impl Chain {
+    pub fn new(backends: Vec<Box<dyn Backend>>) -> Self {
+        Chain { backends }
+    }
+
+    /// The backends listed in the configuration
+    pub fn from_config(
+        config: &crate::config::Config,
+        pool: &sqlx::PgPool,
+        hasher: &std::sync::Arc<crate::hashing::workers::Hasher>,
+    ) -> Self {
+        let backends = config
+            .authentication
+            .backends
+            .iter()
+            .map(|backend| -> Box<dyn Backend> {
+                match backend {
+                    crate::config::Backend::Local => {
+                        Box::new(local::Local::new(pool.clone(), hasher.clone()))
+                    }
+                    crate::config::Backend::Ldap => Box::new(ldap::Ldap::new(
+                        config.ldap.clone(),
+                        pool.clone(),
+                        hasher.clone(),
+                    )),
+                }
+            })
+            .collect();
+
+        Chain::new(backends)
+    }
+
+    /// Outcome of the first backend that accepts the credentials, with its name. A
+    /// backend that is down is skipped, and only reported when no other backend
+    /// accepts the credentials or rejects the password
+    pub async fn authenticate(
+        &self,
+        identifier: &str,
+        password: &str,
+    ) -> Result<(Outcome, &'static str), Error> {
+        let mut result = Ok((Outcome::UnknownUser, "none"));
+
+        for backend in &self.backends {
+            match backend.authenticate(identifier, password).await {
+                Ok(outcome @ Outcome::Authenticated { .. }) => {
+                    return Ok((outcome, backend.name()));
+                }
+                Ok(outcome @ Outcome::InvalidPassword { .. }) => {
+                    result = Ok((outcome, backend.name()));
+                }
+                Ok(Outcome::UnknownUser) => {}
+                Err(Error::Unavailable(err)) => {
+                    tracing::warn!(backend = backend.name(), error = %err, "Authentication backend unavailable");
+                    if let Ok((Outcome::UnknownUser, _)) = result {
+                        result = Err(Error::Unavailable(err));
+                    }
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        result
+    }
+}
+
+/// What a directory says about a user
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DirectoryUser {
+    /// Stable id of the user in the directory
+    pub subject: String,
+    pub username: String,
+    pub email: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub phone: String,
+}
+
+/// The local user standing in for a directory user. It is created the first time the
+/// user logs in and its contact details and names follow the directory on every login
+/// after that. Its password is random, so it can only log in through the directory
+pub async fn shadow(
+    pool: &sqlx::PgPool,
+    hasher: &crate::hashing::workers::Hasher,
+    source: &str,
+    directory: &DirectoryUser,
+) -> Result<icarus_models::user::User, Error> {
+    // Values the directory has that would not pass registration are left out
+    let email = crate::validation::email(&directory.email).unwrap_or_default();
+    let phone = crate::validation::phone(&directory.phone).unwrap_or_default();
+    let firstname = crate::validation::name(&directory.firstname).unwrap_or_default();
+    let lastname = crate::validation::name(&directory.lastname).unwrap_or_default();
+
+    match crate::repo::oidc::get_linked_user(pool, source, &directory.subject).await {
+        Ok(id) => {
+            let user = crate::repo::user::get_by_id(pool, &id).await?;
+            if user.email == email
+                && user.phone == phone
+                && user.firstname == firstname
+                && user.lastname == lastname
+            {
+                return Ok(user);
+            }
+
+            let updated = icarus_models::user::User {
+                email,
+                phone,
+                firstname,
+                lastname,
+                ..user
+            };
+            return Ok(crate::repo::user::update_profile(pool, &updated).await?);
+        }
+        Err(sqlx::Error::RowNotFound) => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let username = crate::validation::username(&directory.username).map_err(|err| {
+        Error::Unavailable(format!(
+            "Directory username `{}`: {err}",
+            directory.username
+        ))
+    })?;
+    let unusable_password: String = rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    let salt_string =
+        crate::hashing::generate_salt().map_err(|err| Error::Unavailable(err.to_string()))?;
+
+    let mut user = icarus_models::user::User {
+        username,
+        password: hasher.hash(unusable_password, salt_string).await?,
+        email,
+        phone,
+        firstname,
+        lastname,
+        status: String::from(crate::repo::user::status::ACTIVE),
+        email_verified: true,
+        ..Default::default()
+    };
+
+    let mut tx = pool.begin().await?;
+    let (id, date_created) = crate::repo::user::insert(&mut *tx, &user).await?;
+    crate::repo::oidc::link(
+        &mut *tx,
+        source,
+        &directory.subject,
+        &id,
+        Some(user.email.as_str()).filter(|email| !email.is_empty()),
+    )
+    .await?;
+    tx.commit().await?;
+
+    user.id = id;
+    user.date_created = date_created;
+    tracing::info!(user_id = %user.id, source, "Created shadow user");
+
+    Ok(user)
+}
@@ -0,0 +1,147 @@
+use axum::http::StatusCode;
+
+use crate::token_stuff;
+
+/// Token from the `Authorization: Bearer` header
+fn bearer_token(parts: &axum::http::request::Parts) -> Option<String> {
+    parts
+        .headers
+        .get(axum::http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(String::from)
+}
+
+/// Whether the service account is flagged as an admin
+async fn is_admin(
+    state: &crate::state::AppState,
+    id: &uuid::Uuid,
+) -> Result<bool, (StatusCode, &'static str)> {
+    match crate::repo::service::is_admin(&state.pool, id).await {
+        Ok(is_admin) => Ok(is_admin),
+        Err(sqlx::Error::RowNotFound) => Ok(false),
+        Err(_err) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")),
+    }
+}
+
+/// Caller authenticated with the service token of an admin account, required by
+/// administrative endpoints
+pub struct Admin {
+    pub id: uuid::Uuid,
+}
+
+impl axum::extract::FromRequestParts<crate::state::AppState> for Admin {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &crate::state::AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = (StatusCode::UNAUTHORIZED, "Unauthorized");
+        let token = bearer_token(parts).ok_or(unauthorized)?;
+        let claims = match token_stuff::decode(&state.keys.secret, &token, &state.config.token) {
+            Ok(claims) => claims,
+            Err(_err) => {
+                crate::telemetry::token_verification_failure();
+                return Err(unauthorized);
+            }
+        };
+
+        if token_stuff::is_token_type_valid(&claims.token_type)
+            && is_admin(state, &claims.sub).await?
+        {
+            Ok(Admin { id: claims.sub })
+        } else {
+            Err((StatusCode::FORBIDDEN, "Forbidden"))
+        }
+    }
+}
+
+/// Rejects tokens of users that were disabled or removed after the token was issued
+async fn require_active(
+    state: &crate::state::AppState,
+    id: &uuid::Uuid,
+) -> Result<(), (StatusCode, &'static str)> {
+    match crate::repo::user::get_by_id(&state.pool, id).await {
+        Ok(user) if user.status == crate::repo::user::status::ACTIVE => Ok(()),
+        Ok(_) => Err((StatusCode::FORBIDDEN, "Forbidden")),
+        Err(sqlx::Error::RowNotFound) => Err((StatusCode::UNAUTHORIZED, "Unauthorized")),
+        Err(_err) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")),
+    }
+}
+
+/// Caller authenticated with the app token of an active user
+pub struct AppUser {
+    pub id: uuid::Uuid,
+}
+
+impl axum::extract::FromRequestParts<crate::state::AppState> for AppUser {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &crate::state::AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = (StatusCode::UNAUTHORIZED, "Unauthorized");
+        let token = bearer_token(parts).ok_or(unauthorized)?;
+        let claims = match token_stuff::decode(&state.keys.secret, &token, &state.config.token) {
+            Ok(claims) => claims,
+            Err(_err) => {
+                crate::telemetry::token_verification_failure();
+                return Err(unauthorized);
+            }
+        };
+
+        if claims.token_type == token_stuff::APP_TOKEN_TYPE {
+            require_active(state, &claims.sub).await?;
+            Ok(AppUser { id: claims.sub })
+        } else {
+            Err((StatusCode::FORBIDDEN, "Forbidden"))
+        }
+    }
+}
+
+/// Caller authenticated with either the service token of an admin account or the app
+/// token of an active user
+pub enum Caller {
+    Admin(uuid::Uuid),
+    User(uuid::Uuid),
+}
+
+impl Caller {
+    pub fn id(&self) -> uuid::Uuid {
+        match self {
+            Caller::Admin(id) | Caller::User(id) => *id,
+        }
+    }
+}
+
+impl axum::extract::FromRequestParts<crate::state::AppState> for Caller {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(
+        parts: &mut axum::http::request::Parts,
+        state: &crate::state::AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let unauthorized = (StatusCode::UNAUTHORIZED, "Unauthorized");
+        let token = bearer_token(parts).ok_or(unauthorized)?;
+        let claims = match token_stuff::decode(&state.keys.secret, &token, &state.config.token) {
+            Ok(claims) => claims,
+            Err(_err) => {
+                crate::telemetry::token_verification_failure();
+                return Err(unauthorized);
+            }
+        };
+
+        if token_stuff::is_token_type_valid(&claims.token_type)
+            && is_admin(state, &claims.sub).await?
+        {
+            Ok(Caller::Admin(claims.sub))
+        } else if claims.token_type == token_stuff::APP_TOKEN_TYPE {
+            require_active(state, &claims.sub).await?;
+            Ok(Caller::User(claims.sub))
+        } else {
+            Err((StatusCode::FORBIDDEN, "Forbidden"))
+        }
+    }
+}
@@ -0,0 +1,139 @@
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Response {
+        pub message: String,
+        /// Users without their password hash
+        pub data: Vec<icarus_models::user::User>,
+    }
+}
+
+/// Module for the registration approval endpoints
+pub mod endpoint {
+    use axum::{
+        Json,
+        extract::{Path, State},
+        http::StatusCode,
+    };
+
+    use super::response;
+    use crate::callers::admin::Admin;
+    use crate::notify;
+    use crate::repo;
+
+    fn reply(
+        status: StatusCode,
+        message: String,
+        mut data: Vec<icarus_models::user::User>,
+    ) -> (StatusCode, Json<response::Response>) {
+        for user in &mut data {
+            user.password.clear();
+        }
+
+        (status, Json(response::Response { message, data }))
+    }
+
+    /// Endpoint to list registrations waiting for approval
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::PENDING_REGISTRATIONS,
+        responses(
+            (status = 200, description = "Pending users, oldest first", body = response::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 403, description = "Not the service token of an admin account")
+        )
+    )]
+    pub async fn list(
+        _admin: Admin,
+        State(state): State<crate::state::AppState>,
+    ) -> (StatusCode, Json<response::Response>) {
+        match repo::user::list_by_status(&state.pool, repo::user::status::PENDING).await {
+            Ok(users) => reply(
+                StatusCode::OK,
+                format!("{} pending registrations", users.len()),
+                users,
+            ),
+            Err(err) => reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.to_string(),
+                Vec::new(),
+            ),
+        }
+    }
+
+    /// Endpoint to approve a pending registration so the user can log in
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::APPROVE_REGISTRATION,
+        params(("id" = uuid::Uuid, Path, description = "User id")),
+        responses(
+            (status = 200, description = "User approved", body = response::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 403, description = "Not the service token of an admin account"),
+            (status = 404, description = "No pending user with the id", body = response::Response)
+        )
+    )]
+    pub async fn approve(
+        _admin: Admin,
+        State(state): State<crate::state::AppState>,
+        Path(id): Path<uuid::Uuid>,
+    ) -> (StatusCode, Json<response::Response>) {
+        match repo::user::approve(&state.pool, &id).await {
+            Ok(user) => {
+                state
+                    .notifier
+                    .registration(notify::Event::RegistrationApproved, &user);
+                reply(StatusCode::OK, String::from("User approved"), vec![user])
+            }
+            Err(sqlx::Error::RowNotFound) => reply(
+                StatusCode::NOT_FOUND,
+                String::from("No pending user with that id"),
+                Vec::new(),
+            ),
+            Err(err) => reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.to_string(),
+                Vec::new(),
+            ),
+        }
+    }
+
+    /// Endpoint to reject a pending registration. The user is deleted so the username
+    /// and email can be used again
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::REJECT_REGISTRATION,
+        params(("id" = uuid::Uuid, Path, description = "User id")),
+        responses(
+            (status = 200, description = "User rejected", body = response::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 403, description = "Not the service token of an admin account"),
+            (status = 404, description = "No pending user with the id", body = response::Response)
+        )
+    )]
+    pub async fn reject(
+        _admin: Admin,
+        State(state): State<crate::state::AppState>,
+        Path(id): Path<uuid::Uuid>,
+    ) -> (StatusCode, Json<response::Response>) {
+        match repo::user::reject(&state.pool, &id).await {
+            Ok(user) => {
+                state
+                    .notifier
+                    .registration(notify::Event::RegistrationRejected, &user);
+                reply(StatusCode::OK, String::from("User rejected"), vec![user])
+            }
+            Err(sqlx::Error::RowNotFound) => reply(
+                StatusCode::NOT_FOUND,
+                String::from("No pending user with that id"),
+                Vec::new(),
+            ),
+            Err(err) => reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.to_string(),
+                Vec::new(),
+            ),
+        }
+    }
+}
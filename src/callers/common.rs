@@ -9,7 +9,7 @@ pub mod response {
 
 pub mod endpoint {
     use super::*;
-    use axum::{Extension, Json, http::StatusCode};
+    use axum::{Json, extract::State, http::StatusCode};
 
     /// Endpoint to hit the root
     /// basic handler that responds with a static string
@@ -24,31 +24,56 @@ pub mod endpoint {
         "Hello, World!"
     }
 
+    /// Endpoint exposing metrics in the Prometheus text format
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::METRICS,
+        responses(
+            (status = 200, description = "Metrics", body = String, content_type = "text/plain"),
+        )
+    )]
+    pub async fn metrics(
+        State(state): State<crate::state::AppState>,
+    ) -> ([(axum::http::HeaderName, &'static str); 1], String) {
+        crate::telemetry::pool(&state.pool, state.config.database.max_connections);
+
+        (
+            [(
+                axum::http::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4",
+            )],
+            crate::telemetry::handle().render(),
+        )
+    }
+
     /// Endpoint to do a database ping
     #[utoipa::path(
         get,
         path = super::super::endpoints::DBTEST,
         responses(
             (status = 200, description = "Successful ping of the db", body = super::response::TestResult),
-            (status = 400, description = "Failure in pinging the db", body = super::response::TestResult)
+            (status = 503, description = "Failure in pinging the db", body = super::response::TestResult)
         )
     )]
     pub async fn db_ping(
-        Extension(pool): Extension<sqlx::PgPool>,
+        State(state): State<crate::state::AppState>,
     ) -> (StatusCode, Json<response::TestResult>) {
-        match sqlx::query("SELECT 1").execute(&pool).await {
+        match sqlx::query("SELECT 1").execute(&state.pool).await {
             Ok(_) => {
                 let tr = response::TestResult {
                     message: String::from("This works"),
                 };
                 (StatusCode::OK, Json(tr))
             }
-            Err(e) => (
-                StatusCode::BAD_REQUEST,
-                Json(response::TestResult {
-                    message: e.to_string(),
-                }),
-            ),
+            Err(e) => {
+                tracing::error!(error = ?e, "Error pinging the db");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    Json(response::TestResult {
+                        message: String::from("Database is unavailable"),
+                    }),
+                )
+            }
         }
     }
 }
@@ -0,0 +1,448 @@
+use rand::Rng;
+
+/// `grant_type` devices poll the token endpoint with
+pub const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// Characters in a device code
+pub const DEVICE_CODE_LENGTH: usize = 43;
+/// Letters in a user code. No vowels so codes do not spell words, and none that are
+/// easily mistaken for each other, see RFC 8628 section 6.1
+const USER_CODE_ALPHABET: &[u8] = b"BCDFGHJKLMNPQRSTVWXZ";
+const USER_CODE_LENGTH: usize = 8;
+
+pub mod request {
+    use serde::{Deserialize, Serialize};
+
+    /// Sent form encoded, see RFC 8628 section 3.1
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Request {
+        #[schema(example = "icarus-tv")]
+        pub client_id: String,
+        /// Accepted for compatibility, tokens are not scoped
+        #[serde(default)]
+        pub scope: Option<String>,
+    }
+
+    pub mod token {
+        use serde::{Deserialize, Serialize};
+
+        /// Sent form encoded, see RFC 8628 section 3.4
+        #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+        pub struct Request {
+            #[schema(example = "urn:ietf:params:oauth:grant-type:device_code")]
+            pub grant_type: String,
+            pub device_code: String,
+            pub client_id: String,
+        }
+    }
+
+    pub mod approve {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+        pub struct Request {
+            /// Code shown on the device, with or without the dash
+            #[schema(example = "WDJB-MJHT")]
+            pub user_code: String,
+            /// `false` to deny the device
+            pub approve: bool,
+        }
+    }
+}
+
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Response {
+        pub device_code: String,
+        /// Code the user enters at `verification_uri`
+        pub user_code: String,
+        pub verification_uri: String,
+        /// `verification_uri` with the user code filled in, e.g. for a QR code
+        pub verification_uri_complete: String,
+        /// Seconds until the codes expire
+        pub expires_in: i64,
+        /// Seconds to wait between polls
+        pub interval: i32,
+    }
+
+    /// Error as defined by RFC 6749 section 5.2 and RFC 8628 section 3.5
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Error {
+        #[schema(example = "authorization_pending")]
+        pub error: String,
+        pub error_description: String,
+    }
+
+    pub mod token {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+        pub struct Response {
+            pub access_token: String,
+            #[schema(example = "Bearer")]
+            pub token_type: String,
+            /// Seconds until the token expires
+            pub expires_in: i64,
+        }
+    }
+
+    pub mod approve {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+        pub struct Response {
+            pub message: String,
+            /// Client the device authenticated as
+            pub data: Vec<String>,
+        }
+    }
+}
+
+/// Random device code
+pub fn generate_device_code() -> String {
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(DEVICE_CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Random user code, as stored
+pub fn generate_user_code() -> String {
+    let mut rng = rand::rng();
+    (0..USER_CODE_LENGTH)
+        .map(|_| char::from(USER_CODE_ALPHABET[rng.random_range(0..USER_CODE_ALPHABET.len())]))
+        .collect()
+}
+
+/// User code as shown to the user, e.g. `WDJB-MJHT`
+pub fn display_user_code(user_code: &str) -> String {
+    let (first, second) = user_code.split_at(user_code.len() / 2);
+    format!("{first}-{second}")
+}
+
+/// User code as entered, made comparable with the stored one. Dashes, spaces and case
+/// do not matter
+pub fn normalize_user_code(user_code: &str) -> String {
+    user_code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+/// Module for device authorization endpoints
+pub mod endpoint {
+    use axum::{
+        Form, Json,
+        extract::State,
+        http::StatusCode,
+        response::{IntoResponse, Response},
+    };
+
+    use super::request;
+    use super::response;
+    use crate::callers::admin::AppUser;
+    use crate::repo;
+    use crate::telemetry;
+    use crate::token_stuff;
+
+    /// Token responses must not be cached, see RFC 6749 section 5.1
+    const NO_STORE: [(axum::http::HeaderName, &str); 1] =
+        [(axum::http::header::CACHE_CONTROL, "no-store")];
+
+    fn error(status: StatusCode, error: &str, description: &str) -> Response {
+        (
+            status,
+            NO_STORE,
+            Json(response::Error {
+                error: String::from(error),
+                error_description: String::from(description),
+            }),
+        )
+            .into_response()
+    }
+
+    fn reply(status: StatusCode, message: &str) -> (StatusCode, Json<response::approve::Response>) {
+        (
+            status,
+            Json(response::approve::Response {
+                message: String::from(message),
+                data: Vec::new(),
+            }),
+        )
+    }
+
+    /// Endpoint for a device to start the device authorization grant (RFC 8628)
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::DEVICE_CODE,
+        request_body(
+            content = request::Request,
+            description = "Client the device authenticates as",
+            content_type = "application/x-www-form-urlencoded"
+        ),
+        responses(
+            (status = 200, description = "Codes for the device to show and poll with", body = response::Response),
+            (status = 400, description = "Missing client id", body = response::Error)
+        )
+    )]
+    pub async fn code(
+        State(state): State<crate::state::AppState>,
+        Form(payload): Form<request::Request>,
+    ) -> Response {
+        let client_id = payload.client_id.trim();
+        if client_id.is_empty() {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                "client_id is required",
+            );
+        }
+
+        let settings = &state.config.device;
+        let device_code = super::generate_device_code();
+        let expires_at = time::OffsetDateTime::now_utc() + time::Duration::seconds(settings.ttl);
+
+        // User codes are short, so a clash with a pending one gets a new code
+        let mut attempts = 0;
+        let user_code = loop {
+            let user_code = super::generate_user_code();
+            match repo::device::insert(
+                &state.pool,
+                &super::super::invitation::hash_code(&device_code),
+                &user_code,
+                client_id,
+                settings.interval,
+                &expires_at,
+            )
+            .await
+            {
+                Ok(()) => break user_code,
+                Err(err) if repo::is_unique_violation(&err) && attempts < 3 => attempts += 1,
+                Err(_err) => {
+                    return error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "server_error",
+                        "Could not start the device authorization",
+                    );
+                }
+            }
+        };
+
+        let user_code = super::display_user_code(&user_code);
+        let mut verification_uri_complete = reqwest::Url::parse(&settings.verification_url)
+            .expect("verification_url is validated when the configuration is loaded");
+        verification_uri_complete
+            .query_pairs_mut()
+            .append_pair("user_code", &user_code);
+
+        (
+            StatusCode::OK,
+            NO_STORE,
+            Json(response::Response {
+                device_code,
+                user_code,
+                verification_uri: settings.verification_url.clone(),
+                verification_uri_complete: verification_uri_complete.to_string(),
+                expires_in: settings.ttl,
+                interval: settings.interval,
+            }),
+        )
+            .into_response()
+    }
+
+    /// Endpoint for a logged in user to approve or deny the device showing the user code
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::DEVICE_APPROVE,
+        request_body(
+            content = request::approve::Request,
+            description = "User code shown on the device",
+            content_type = "application/json"
+        ),
+        responses(
+            (status = 200, description = "Device approved or denied", body = response::approve::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 403, description = "Not a user token"),
+            (status = 404, description = "Unknown, expired or already used user code", body = response::approve::Response)
+        )
+    )]
+    pub async fn approve(
+        app_user: AppUser,
+        State(state): State<crate::state::AppState>,
+        Json(payload): Json<request::approve::Request>,
+    ) -> (StatusCode, Json<response::approve::Response>) {
+        let user_code = super::normalize_user_code(&payload.user_code);
+        match repo::device::decide(&state.pool, &user_code, &app_user.id, payload.approve).await {
+            Ok(client_id) => {
+                tracing::info!(user_id = %app_user.id, client_id, approved = payload.approve, "Device authorization decided");
+                let message = if payload.approve {
+                    "Device approved"
+                } else {
+                    "Device denied"
+                };
+                (
+                    StatusCode::OK,
+                    Json(response::approve::Response {
+                        message: String::from(message),
+                        data: vec![client_id],
+                    }),
+                )
+            }
+            Err(sqlx::Error::RowNotFound) => {
+                reply(StatusCode::NOT_FOUND, "Unknown or expired user code")
+            }
+            Err(err) => reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        }
+    }
+
+    /// Endpoint a device polls until the user has approved or denied it
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::DEVICE_TOKEN,
+        request_body(
+            content = request::token::Request,
+            description = "Device code from the device code endpoint",
+            content_type = "application/x-www-form-urlencoded"
+        ),
+        responses(
+            (status = 200, description = "Approved, the token is handed out once", body = response::token::Response),
+            (status = 400, description = "`authorization_pending`, `slow_down`, `expired_token`, `access_denied`, `invalid_grant` or `unsupported_grant_type`", body = response::Error)
+        )
+    )]
+    pub async fn token(
+        State(state): State<crate::state::AppState>,
+        Form(payload): Form<request::token::Request>,
+    ) -> Response {
+        if payload.grant_type != super::GRANT_TYPE {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+                "Only the device code grant is supported",
+            );
+        }
+
+        let device_code_hash = super::super::invitation::hash_code(&payload.device_code);
+        let user_id =
+            match repo::device::poll(&state.pool, &device_code_hash, &payload.client_id).await {
+                Ok(repo::device::Poll::Approved(user_id)) => user_id,
+                Ok(repo::device::Poll::Pending) => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "authorization_pending",
+                        "The user has not approved the device yet",
+                    );
+                }
+                Ok(repo::device::Poll::SlowDown) => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "slow_down",
+                        "Polling too fast, wait 5 more seconds between polls",
+                    );
+                }
+                Ok(repo::device::Poll::Expired) => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "expired_token",
+                        "The device code has expired",
+                    );
+                }
+                Ok(repo::device::Poll::Denied) => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "access_denied",
+                        "The user denied the device",
+                    );
+                }
+                Err(sqlx::Error::RowNotFound) => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_grant",
+                        "Unknown device code",
+                    );
+                }
+                Err(err) => {
+                    return error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "server_error",
+                        &err.to_string(),
+                    );
+                }
+            };
+
+        // The user may have been disabled since approving
+        let user = match repo::user::get_by_id(&state.pool, &user_id).await {
+            Ok(user) if user.status == repo::user::status::ACTIVE => user,
+            Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                telemetry::login(
+                    telemetry::FAILURE,
+                    "disabled",
+                    repo::user::identifier::DEVICE,
+                );
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "access_denied",
+                    "The user cannot log in",
+                );
+            }
+            Err(err) => {
+                return error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    &err.to_string(),
+                );
+            }
+        };
+
+        let (access_token, expiration) =
+            match token_stuff::create_token(&state.keys.secret, &user.id, &state.config.token) {
+                Ok(token) => token,
+                Err(err) => {
+                    telemetry::login(
+                        telemetry::FAILURE,
+                        "token_error",
+                        repo::user::identifier::DEVICE,
+                    );
+                    return error(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "server_error",
+                        &err.to_string(),
+                    );
+                }
+            };
+
+        let current_time = time::OffsetDateTime::now_utc();
+        let _ = repo::user::update_last_login(&state.pool, &user, &current_time).await;
+        telemetry::login(telemetry::SUCCESS, "none", repo::user::identifier::DEVICE);
+
+        (
+            StatusCode::OK,
+            NO_STORE,
+            Json(response::token::Response {
+                access_token,
+                token_type: String::from("Bearer"),
+                expires_in: expiration - current_time.unix_timestamp(),
+            }),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_code() {
+        let user_code = generate_user_code();
+        assert_eq!(user_code.len(), USER_CODE_LENGTH);
+        assert!(user_code.bytes().all(|c| USER_CODE_ALPHABET.contains(&c)));
+
+        let shown = display_user_code(&user_code);
+        assert_eq!(shown.len(), USER_CODE_LENGTH + 1);
+        assert_eq!(normalize_user_code(&shown), user_code);
+        assert_eq!(normalize_user_code(" wdjb-mjht "), "WDJBMJHT");
+    }
+}
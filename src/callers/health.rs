@@ -0,0 +1,206 @@
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Check {
+        pub name: String,
+        pub healthy: bool,
+    }
+
+    pub mod readiness {
+        #[derive(Debug, Default, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+        pub struct Response {
+            pub message: String,
+            pub data: Vec<super::Check>,
+        }
+    }
+
+    pub mod report {
+        #[derive(Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+        pub struct Pool {
+            pub size: u32,
+            pub idle: usize,
+            pub max_connections: u32,
+        }
+
+        #[derive(Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+        pub struct Report {
+            pub checks: Vec<super::Check>,
+            pub pool: Pool,
+            pub migration_version: Option<i64>,
+            pub expected_migration_version: Option<i64>,
+        }
+
+        #[derive(Debug, Default, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+        pub struct Response {
+            pub message: String,
+            pub data: Vec<Report>,
+        }
+    }
+}
+
+/// Module for health endpoints
+pub mod endpoint {
+    use axum::{Json, extract::State, http::StatusCode};
+
+    use super::response;
+    use crate::db;
+
+    const READY: &str = "Ready";
+    const NOT_READY: &str = "Not ready";
+
+    /// Runs the readiness checks and returns them with the applied migration version
+    async fn run_checks(state: &crate::state::AppState) -> (Vec<response::Check>, Option<i64>) {
+        let database = match sqlx::query("SELECT 1").execute(&state.pool).await {
+            Ok(_) => true,
+            Err(err) => {
+                tracing::error!(error = ?err, "Database check failed");
+                false
+            }
+        };
+
+        let applied = if database {
+            match db::init::applied_migration_version(&state.pool).await {
+                Ok(version) => version,
+                Err(err) => {
+                    tracing::error!(error = ?err, "Migration check failed");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let migrations = match (applied, db::init::latest_migration_version()) {
+            (Some(applied), Some(latest)) => applied >= latest,
+            (_, None) => true,
+            (None, Some(_)) => false,
+        };
+        let identity_indexes = database
+            && match db::init::identity_indexes_exist(&state.pool).await {
+                Ok(exist) => exist,
+                Err(err) => {
+                    tracing::error!(error = ?err, "Identity index check failed");
+                    false
+                }
+            };
+
+        let keys = !state.keys.secret.is_empty();
+        let accepting = !state.is_draining();
+
+        let checks = vec![
+            response::Check {
+                name: String::from("database"),
+                healthy: database,
+            },
+            response::Check {
+                name: String::from("migrations"),
+                healthy: migrations,
+            },
+            response::Check {
+                name: String::from("identity_indexes"),
+                healthy: identity_indexes,
+            },
+            response::Check {
+                name: String::from("signing_keys"),
+                healthy: keys,
+            },
+            response::Check {
+                name: String::from("accepting_traffic"),
+                healthy: accepting,
+            },
+        ];
+
+        (checks, applied)
+    }
+
+    fn is_ready(checks: &[response::Check]) -> bool {
+        checks.iter().all(|check| check.healthy)
+    }
+
+    /// Liveness probe, succeeds as long as the process can serve requests
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::HEALTHZ,
+        responses(
+            (status = 200, description = "Process is alive", body = &str),
+        )
+    )]
+    pub async fn healthz() -> &'static str {
+        "OK"
+    }
+
+    /// Readiness probe, fails while the service cannot handle traffic
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::READYZ,
+        responses(
+            (status = 200, description = "Ready to serve traffic", body = response::readiness::Response),
+            (status = 503, description = "Not ready to serve traffic", body = response::readiness::Response)
+        )
+    )]
+    pub async fn readyz(
+        State(state): State<crate::state::AppState>,
+    ) -> (StatusCode, Json<response::readiness::Response>) {
+        let (checks, _applied) = run_checks(&state).await;
+
+        if is_ready(&checks) {
+            (
+                StatusCode::OK,
+                Json(response::readiness::Response {
+                    message: String::from(READY),
+                    data: checks,
+                }),
+            )
+        } else {
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(response::readiness::Response {
+                    message: String::from(NOT_READY),
+                    data: checks,
+                }),
+            )
+        }
+    }
+
+    /// Detailed health report. Requires the service token of an admin account
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::HEALTH_REPORT,
+        responses(
+            (status = 200, description = "Service is healthy", body = response::report::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 403, description = "Not the service token of an admin account"),
+            (status = 503, description = "Service is not healthy", body = response::report::Response)
+        )
+    )]
+    pub async fn report(
+        _admin: crate::callers::admin::Admin,
+        State(state): State<crate::state::AppState>,
+    ) -> (StatusCode, Json<response::report::Response>) {
+        let (checks, applied) = run_checks(&state).await;
+        let (status, message) = if is_ready(&checks) {
+            (StatusCode::OK, READY)
+        } else {
+            (StatusCode::SERVICE_UNAVAILABLE, NOT_READY)
+        };
+
+        let report = response::report::Report {
+            checks,
+            pool: response::report::Pool {
+                size: state.pool.size(),
+                idle: state.pool.num_idle(),
+                max_connections: state.config.database.max_connections,
+            },
+            migration_version: applied,
+            expected_migration_version: db::init::latest_migration_version(),
+        };
+
+        (
+            status,
+            Json(response::report::Response {
+                message: String::from(message),
+                data: vec![report],
+            }),
+        )
+    }
+}
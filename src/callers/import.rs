@@ -0,0 +1,159 @@
+use crate::hashing;
+use crate::repo;
+use crate::validation;
+
+pub mod request {
+    use serde::{Deserialize, Serialize};
+
+    /// A user exported from another deployment along with its existing password hash
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct User {
+        pub username: String,
+        /// Argon2, bcrypt, PBKDF2 or scrypt hash. Replaced with Argon2id on the next login
+        pub password_hash: String,
+        pub email: String,
+        #[serde(default)]
+        pub phone: String,
+        #[serde(default)]
+        pub firstname: String,
+        #[serde(default)]
+        pub lastname: String,
+        #[serde(default)]
+        pub email_verified: bool,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Request {
+        pub users: Vec<User>,
+    }
+}
+
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Outcome {
+        pub username: String,
+        /// Set when the user was created
+        pub id: Option<uuid::Uuid>,
+        /// Why the user was skipped
+        pub error: Option<String>,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Response {
+        pub message: String,
+        pub data: Vec<Outcome>,
+    }
+}
+
+/// Creates the users in order, skipping those with invalid fields, whose username or
+/// email is taken or whose hash cannot be verified. Shared by the endpoint and `icarus_auth user import`
+pub async fn import_users(pool: &sqlx::PgPool, users: &[request::User]) -> Vec<response::Outcome> {
+    let mut outcomes = Vec::with_capacity(users.len());
+
+    for imported in users {
+        let result = import_user(pool, imported).await;
+        if let Err(err) = &result {
+            tracing::warn!(username = %imported.username, error = %err, "Skipped imported user");
+        }
+        outcomes.push(response::Outcome {
+            username: imported.username.clone(),
+            id: result.as_ref().ok().copied(),
+            error: result.err(),
+        });
+    }
+
+    outcomes
+}
+
+async fn import_user(pool: &sqlx::PgPool, imported: &request::User) -> Result<uuid::Uuid, String> {
+    let username = validation::username(&imported.username)?;
+    let email = validation::email(&imported.email)?;
+    if hashing::scheme(&imported.password_hash).is_none() {
+        return Err(String::from("Unsupported password hash"));
+    }
+    match repo::user::exists(pool, &username, &email).await {
+        Ok(false) => {}
+        Ok(true) => return Err(String::from("User already exists")),
+        Err(err) => return Err(err.to_string()),
+    }
+
+    let user = icarus_models::user::User {
+        username,
+        password: imported.password_hash.clone(),
+        email,
+        phone: validation::phone(&imported.phone)?,
+        firstname: validation::name(&imported.firstname)?,
+        lastname: validation::name(&imported.lastname)?,
+        email_verified: imported.email_verified,
+        status: String::from(repo::user::status::ACTIVE),
+        ..Default::default()
+    };
+
+    repo::user::insert(pool, &user)
+        .await
+        .map(|(id, _date_created)| id)
+        .map_err(|err| {
+            if repo::is_unique_violation(&err) {
+                String::from("User already exists")
+            } else {
+                err.to_string()
+            }
+        })
+}
+
+/// Module for the user import endpoint
+pub mod endpoint {
+    use axum::{Json, extract::State, http::StatusCode};
+
+    use super::request;
+    use super::response;
+    use crate::callers::admin::Admin;
+
+    /// Endpoint to import users with their existing password hashes
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::IMPORT_USERS,
+        request_body(
+            content = request::Request,
+            description = "Users to import",
+            content_type = "application/json"
+        ),
+        responses(
+            (status = 200, description = "Import finished, see each outcome", body = response::Response),
+            (status = 400, description = "No users to import", body = response::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 403, description = "Not the service token of an admin account")
+        )
+    )]
+    pub async fn import(
+        _admin: Admin,
+        State(state): State<crate::state::AppState>,
+        Json(payload): Json<request::Request>,
+    ) -> (StatusCode, Json<response::Response>) {
+        if payload.users.is_empty() {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(response::Response {
+                    message: String::from("No users to import"),
+                    data: Vec::new(),
+                }),
+            );
+        }
+
+        let outcomes = super::import_users(&state.pool, &payload.users).await;
+        let imported = outcomes
+            .iter()
+            .filter(|outcome| outcome.id.is_some())
+            .count();
+
+        (
+            StatusCode::OK,
+            Json(response::Response {
+                message: format!("Imported {imported} of {} users", outcomes.len()),
+                data: outcomes,
+            }),
+        )
+    }
+}
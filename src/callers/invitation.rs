@@ -0,0 +1,272 @@
+use rand::Rng;
+use sha2::Digest;
+
+/// Characters in a generated invitation code
+pub const CODE_LENGTH: usize = 24;
+
+pub mod request {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Request {
+        /// Registrations the code allows, 1 when not given. Users can only issue single-use codes
+        #[serde(default)]
+        pub max_uses: Option<i32>,
+        /// Seconds the code stays valid, the configured invitation TTL when not given. Users
+        /// cannot go past that TTL, admins past the configured maximum
+        #[serde(default)]
+        pub expires_in: Option<i64>,
+        /// Only this email can register with the code
+        #[serde(default)]
+        pub email: Option<String>,
+        /// Role given to users registering with the code. Only service accounts can set it
+        #[serde(default)]
+        pub role: Option<String>,
+    }
+}
+
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Created {
+        /// The code to hand out. It is not stored and cannot be shown again
+        pub code: String,
+        pub invitation: crate::repo::invitation::Invitation,
+    }
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Response {
+        pub message: String,
+        pub data: Vec<Created>,
+    }
+
+    pub mod list {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+        pub struct Response {
+            pub message: String,
+            pub data: Vec<crate::repo::invitation::Invitation>,
+        }
+    }
+}
+
+/// Random code handed out to the invitee
+pub fn generate_code() -> String {
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(CODE_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// SHA-256 of the code, which is what gets stored and looked up. Codes are random
+/// enough that a slow hash is not needed
+pub fn hash_code(code: &str) -> String {
+    sha2::Sha256::digest(code.trim().as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Module for invitation endpoints
+pub mod endpoint {
+    use axum::{
+        Json,
+        extract::{Path, State},
+        http::StatusCode,
+    };
+
+    use super::request;
+    use super::response;
+    use crate::callers::admin::Caller;
+    use crate::repo;
+
+    fn reply(status: StatusCode, message: String) -> (StatusCode, Json<response::Response>) {
+        (
+            status,
+            Json(response::Response {
+                message,
+                data: Vec::new(),
+            }),
+        )
+    }
+
+    /// Endpoint to issue an invitation code
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::INVITATIONS,
+        request_body(
+            content = request::Request,
+            description = "Uses, expiry and restrictions of the invitation",
+            content_type = "application/json"
+        ),
+        responses(
+            (status = 201, description = "Invitation created", body = response::Response),
+            (status = 400, description = "Invalid uses, email, or expiry past the allowed maximum", body = response::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 403, description = "Users can only issue single-use invitations without a role", body = response::Response)
+        )
+    )]
+    pub async fn create(
+        caller: Caller,
+        State(state): State<crate::state::AppState>,
+        Json(payload): Json<request::Request>,
+    ) -> (StatusCode, Json<response::Response>) {
+        let max_uses = payload.max_uses.unwrap_or(1);
+        let expires_in = payload
+            .expires_in
+            .unwrap_or(state.config.registration.invitation_ttl);
+
+        if let Caller::User(_) = caller
+            && (max_uses != 1 || payload.role.is_some())
+        {
+            return reply(
+                StatusCode::FORBIDDEN,
+                String::from("Users can only issue single-use invitations without a role"),
+            );
+        }
+        if max_uses < 1 {
+            return reply(
+                StatusCode::BAD_REQUEST,
+                String::from("max_uses must be at least 1"),
+            );
+        }
+        if expires_in <= 0 {
+            return reply(
+                StatusCode::BAD_REQUEST,
+                String::from("expires_in must be greater than 0"),
+            );
+        }
+        let max_expires_in = match caller {
+            Caller::User(_) => state.config.registration.invitation_ttl,
+            Caller::Admin(_) => state.config.registration.max_invitation_ttl,
+        };
+        if expires_in > max_expires_in {
+            return reply(
+                StatusCode::BAD_REQUEST,
+                format!("expires_in must be at most {max_expires_in}"),
+            );
+        }
+        let email = match payload.email.as_deref().map(crate::validation::email) {
+            Some(Ok(email)) => Some(email),
+            Some(Err(message)) => return reply(StatusCode::BAD_REQUEST, message),
+            None => None,
+        };
+        let role = payload
+            .role
+            .map(|role| role.trim().to_string())
+            .filter(|role| !role.is_empty());
+
+        let Some(expires_at) =
+            time::OffsetDateTime::now_utc().checked_add(time::Duration::seconds(expires_in))
+        else {
+            return reply(
+                StatusCode::BAD_REQUEST,
+                String::from("expires_in is out of range"),
+            );
+        };
+        let code = super::generate_code();
+
+        match repo::invitation::insert(
+            &state.pool,
+            &super::hash_code(&code),
+            &caller.id(),
+            email.as_deref(),
+            role.as_deref(),
+            max_uses,
+            Some(expires_at),
+        )
+        .await
+        {
+            Ok((id, date_created)) => (
+                StatusCode::CREATED,
+                Json(response::Response {
+                    message: String::from("Invitation created"),
+                    data: vec![response::Created {
+                        code,
+                        invitation: repo::invitation::Invitation {
+                            id,
+                            created_by: caller.id(),
+                            email,
+                            role,
+                            max_uses,
+                            uses: 0,
+                            expires_at: Some(expires_at),
+                            date_created,
+                            used_by: Vec::new(),
+                        },
+                    }],
+                }),
+            ),
+            Err(err) => reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        }
+    }
+
+    /// Endpoint to list invitations with their usage. Users only see their own
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::INVITATIONS,
+        responses(
+            (status = 200, description = "Invitations", body = response::list::Response),
+            (status = 401, description = "Missing or invalid token")
+        )
+    )]
+    pub async fn list(
+        caller: Caller,
+        State(state): State<crate::state::AppState>,
+    ) -> (StatusCode, Json<response::list::Response>) {
+        let created_by = match &caller {
+            Caller::Admin(_) => None,
+            Caller::User(id) => Some(id),
+        };
+
+        match repo::invitation::list(&state.pool, created_by).await {
+            Ok(invitations) => (
+                StatusCode::OK,
+                Json(response::list::Response {
+                    message: format!("{} invitations", invitations.len()),
+                    data: invitations,
+                }),
+            ),
+            Err(err) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(response::list::Response {
+                    message: err.to_string(),
+                    data: Vec::new(),
+                }),
+            ),
+        }
+    }
+
+    /// Endpoint to revoke an invitation. Users can only revoke their own
+    #[utoipa::path(
+        delete,
+        path = super::super::endpoints::INVITATION,
+        params(("id" = uuid::Uuid, Path, description = "Invitation id")),
+        responses(
+            (status = 200, description = "Invitation revoked", body = response::Response),
+            (status = 401, description = "Missing or invalid token"),
+            (status = 404, description = "No such invitation", body = response::Response)
+        )
+    )]
+    pub async fn revoke(
+        caller: Caller,
+        State(state): State<crate::state::AppState>,
+        Path(id): Path<uuid::Uuid>,
+    ) -> (StatusCode, Json<response::Response>) {
+        let created_by = match &caller {
+            Caller::Admin(_) => None,
+            Caller::User(id) => Some(id),
+        };
+
+        match repo::invitation::delete(&state.pool, &id, created_by).await {
+            Ok(()) => reply(StatusCode::OK, String::from("Invitation revoked")),
+            Err(sqlx::Error::RowNotFound) => {
+                reply(StatusCode::NOT_FOUND, String::from("No such invitation"))
+            }
+            Err(err) => reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        }
+    }
+}
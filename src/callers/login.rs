@@ -3,6 +3,9 @@ pub mod request {
 
     #[derive(Default, Deserialize, Serialize, utoipa::ToSchema)]
     pub struct Request {
+        /// Username or email, matched case-insensitively. Also accepted as `identifier` or `email`
+        #[serde(alias = "identifier", alias = "email")]
+        #[schema(example = "bob@example.com")]
         pub username: String,
         pub password: String,
     }
@@ -52,13 +55,19 @@ pub mod response {
 pub mod endpoint {
     use axum::{Json, http::StatusCode};
 
+    use crate::authentication;
     use crate::hashing;
     use crate::repo;
+    use crate::telemetry;
     use crate::token_stuff;
 
     use super::request;
     use super::response;
 
+    /// Same message for unknown users and wrong passwords so the response does not
+    /// tell which one it was
+    pub const INVALID_CREDENTIALS: &str = "Invalid username or password";
+
     async fn not_found(message: &str) -> (StatusCode, Json<response::Response>) {
         (
             StatusCode::NOT_FOUND,
@@ -69,60 +78,180 @@ pub mod endpoint {
         )
     }
 
+    /// Replaces a password hash stored with outdated parameters. Failures are logged
+    /// and do not affect the login
+    async fn rehash_if_outdated(
+        state: &crate::state::AppState,
+        user: &icarus_models::user::User,
+        password: &str,
+    ) {
+        let settings = &state.config.hashing;
+        match hashing::needs_rehash(&user.password, settings) {
+            Ok(false) => return,
+            Ok(true) => {}
+            Err(err) => {
+                tracing::warn!(error = %err, "Could not read password hash parameters");
+                return;
+            }
+        }
+
+        let salt_string = match hashing::generate_salt() {
+            Ok(salt) => salt,
+            Err(err) => {
+                tracing::error!(error = %err, "Could not generate salt");
+                return;
+            }
+        };
+        let hashed = match state.hasher.hash(password.to_string(), salt_string).await {
+            Ok(hashed) => hashed,
+            Err(err) => {
+                tracing::error!(error = %err, "Could not rehash password");
+                return;
+            }
+        };
+
+        match repo::user::update_password(&state.pool, &user.id, &hashed).await {
+            Ok(()) => tracing::info!(user_id = %user.id, "Rehashed password"),
+            Err(err) => tracing::error!(error = %err, "Could not store rehashed password"),
+        }
+    }
+
     /// Endpoint to login
     #[utoipa::path(
         post,
         path = super::super::endpoints::LOGIN,
         request_body(
             content = request::Request,
-            description = "Data required to login",
+            description = "Username or email and password",
             content_type = "application/json"
         ),
         responses(
             (status = 200, description = "Successfully logged in", body = response::Response),
-            (status = 404, description = "Could not login with credentials", body = response::Response)
+            (status = 403, description = "User is disabled or their registration is pending approval", body = response::Response),
+            (status = 404, description = "Unknown user or wrong password, the response is the same for both", body = response::Response),
+            (status = 409, description = "Directory user clashes with a local user", body = response::Response),
+            (status = 503, description = "Too many concurrent password hashes, or a directory is unavailable", body = response::Response)
         )
     )]
     pub async fn login(
-        axum::Extension(pool): axum::Extension<sqlx::PgPool>,
+        axum::extract::State(state): axum::extract::State<crate::state::AppState>,
         Json(payload): Json<request::Request>,
     ) -> (StatusCode, Json<response::Response>) {
-        // Check if user exists
-        match repo::user::get(&pool, &payload.username).await {
-            Ok(user) => {
-                if hashing::verify_password(&payload.password, user.password.clone()).unwrap() {
-                    // Create token
-                    let key = icarus_envy::environment::get_secret_key().await.value;
-                    let (token_literal, duration) =
-                        token_stuff::create_token(&key, &user.id).unwrap();
-
-                    if token_stuff::verify_token(&key, &token_literal) {
-                        let current_time = time::OffsetDateTime::now_utc();
-                        let _ = repo::user::update_last_login(&pool, &user, &current_time).await;
-
+        let (outcome, backend) = match state
+            .authentication
+            .authenticate(&payload.username, &payload.password)
+            .await
+        {
+            Ok(result) => result,
+            Err(err) => {
+                let (status, reason, message) = match &err {
+                    authentication::Error::Busy => (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "busy",
+                        "Server is busy, try again later",
+                    ),
+                    authentication::Error::Unavailable(_) => (
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "backend_unavailable",
+                        "Could not check the credentials, try again later",
+                    ),
+                    authentication::Error::Conflict => (
+                        StatusCode::CONFLICT,
+                        "conflict",
+                        "A local user with the same username or email exists",
+                    ),
+                    authentication::Error::Db(err) => {
+                        tracing::error!(error = %err, "Could not authenticate user");
                         (
-                            StatusCode::OK,
-                            Json(response::Response {
-                                message: String::from("Successful"),
-                                data: vec![icarus_models::login_result::LoginResult {
-                                    id: user.id,
-                                    username: user.username.clone(),
-                                    token: token_literal,
-                                    token_type: String::from(icarus_models::token::TOKEN_TYPE),
-                                    expiration: duration,
-                                }],
-                            }),
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "error",
+                            "Could not authenticate user",
                         )
-                    } else {
-                        return not_found("Could not verify token").await;
                     }
-                } else {
-                    return not_found("Error Hashing").await;
-                }
+                };
+                telemetry::login(
+                    telemetry::FAILURE,
+                    reason,
+                    repo::user::identifier::kind(&payload.username),
+                );
+                return (
+                    status,
+                    Json(response::Response {
+                        message: String::from(message),
+                        data: Vec::new(),
+                    }),
+                );
             }
-            Err(err) => {
-                return not_found(&err.to_string()).await;
+        };
+
+        let (user, identifier_type) = match outcome {
+            authentication::Outcome::Authenticated { user, identifier } => (*user, identifier),
+            authentication::Outcome::InvalidPassword { identifier } => {
+                telemetry::login(telemetry::FAILURE, "invalid_password", identifier);
+                return not_found(INVALID_CREDENTIALS).await;
             }
+            authentication::Outcome::UnknownUser => {
+                telemetry::login(
+                    telemetry::FAILURE,
+                    "unknown_user",
+                    repo::user::identifier::kind(&payload.username),
+                );
+                return not_found(INVALID_CREDENTIALS).await;
+            }
+        };
+
+        if user.status == repo::user::status::DISABLED {
+            telemetry::login(telemetry::FAILURE, "disabled", identifier_type);
+            return (
+                StatusCode::FORBIDDEN,
+                Json(response::Response {
+                    message: String::from("User is disabled"),
+                    data: Vec::new(),
+                }),
+            );
+        }
+        if user.status == repo::user::status::PENDING {
+            telemetry::login(telemetry::FAILURE, "pending", identifier_type);
+            return (
+                StatusCode::FORBIDDEN,
+                Json(response::Response {
+                    message: String::from("Registration is pending approval"),
+                    data: Vec::new(),
+                }),
+            );
+        }
+
+        // Only hashes this service checked itself are its to upgrade
+        if backend == authentication::local::NAME {
+            rehash_if_outdated(&state, &user, &payload.password).await;
+        }
+
+        // Create token
+        let key = &state.keys.secret;
+        let (token_literal, duration) =
+            token_stuff::create_token(key, &user.id, &state.config.token).unwrap();
+
+        if token_stuff::verify_token(key, &token_literal, &state.config.token) {
+            let current_time = time::OffsetDateTime::now_utc();
+            let _ = repo::user::update_last_login(&state.pool, &user, &current_time).await;
+            telemetry::login(telemetry::SUCCESS, "none", identifier_type);
+
+            (
+                StatusCode::OK,
+                Json(response::Response {
+                    message: String::from("Successful"),
+                    data: vec![icarus_models::login_result::LoginResult {
+                        id: user.id,
+                        username: user.username.clone(),
+                        token: token_literal,
+                        token_type: String::from(icarus_models::token::TOKEN_TYPE),
+                        expiration: duration,
+                    }],
+                }),
+            )
+        } else {
+            telemetry::login(telemetry::FAILURE, "token_error", identifier_type);
+            not_found("Could not verify token").await
         }
     }
 
@@ -141,7 +270,7 @@ pub mod endpoint {
         )
     )]
     pub async fn service_login(
-        axum::Extension(pool): axum::Extension<sqlx::PgPool>,
+        axum::extract::State(state): axum::extract::State<crate::state::AppState>,
         axum::Json(payload): axum::Json<request::service_login::Request>,
     ) -> (
         axum::http::StatusCode,
@@ -149,13 +278,13 @@ pub mod endpoint {
     ) {
         let mut response = response::service_login::Response::default();
 
-        match repo::service::valid_passphrase(&pool, &payload.passphrase).await {
+        match repo::service::valid_passphrase(&state.pool, &payload.passphrase).await {
             Ok((id, username, _date_created)) => {
-                let key = icarus_envy::environment::get_secret_key().await.value;
+                let key = &state.keys.secret;
                 let (token_literal, duration) =
-                    token_stuff::create_service_token(&key, &id).unwrap();
+                    token_stuff::create_service_token(key, &id, &state.config.token).unwrap();
 
-                if token_stuff::verify_token(&key, &token_literal) {
+                if token_stuff::verify_token(key, &token_literal, &state.config.token) {
                     let login_result = icarus_models::login_result::LoginResult {
                         id,
                         username,
@@ -166,13 +295,16 @@ pub mod endpoint {
 
                     response.data.push(login_result);
                     response.message = String::from("Successful");
+                    telemetry::service_login(telemetry::SUCCESS);
 
                     (axum::http::StatusCode::OK, axum::Json(response))
                 } else {
+                    telemetry::service_login(telemetry::FAILURE);
                     (axum::http::StatusCode::OK, axum::Json(response))
                 }
             }
             Err(err) => {
+                telemetry::service_login(telemetry::FAILURE);
                 response.message = err.to_string();
                 (axum::http::StatusCode::BAD_REQUEST, axum::Json(response))
             }
@@ -191,74 +323,106 @@ pub mod endpoint {
         responses(
             (status = 200, description = "Refresh token generated", body = response::Response),
             (status = 400, description = "Error verifying token", body = response::Response),
+            (status = 401, description = "Session has reached its maximum lifetime", body = response::Response),
             (status = 404, description = "Could not validate token", body = response::Response),
             (status = 500, description = "Error extracting token", body = response::Response)
         )
     )]
     pub async fn refresh_token(
-        axum::Extension(pool): axum::Extension<sqlx::PgPool>,
+        axum::extract::State(state): axum::extract::State<crate::state::AppState>,
         axum::Json(payload): axum::Json<request::refresh_token::Request>,
     ) -> (
         axum::http::StatusCode,
         axum::Json<response::refresh_token::Response>,
+    ) {
+        let (status, response) = refresh(&state, &payload).await;
+
+        let reason = match status {
+            axum::http::StatusCode::OK => "none",
+            axum::http::StatusCode::BAD_REQUEST => "invalid_token",
+            axum::http::StatusCode::UNAUTHORIZED => "session_expired",
+            axum::http::StatusCode::NOT_FOUND => "invalid_token_type",
+            _ => "error",
+        };
+        if status.is_success() {
+            telemetry::token_refresh(telemetry::SUCCESS, reason);
+        } else {
+            telemetry::token_refresh(telemetry::FAILURE, reason);
+        }
+
+        (status, response)
+    }
+
+    async fn refresh(
+        state: &crate::state::AppState,
+        payload: &request::refresh_token::Request,
+    ) -> (
+        axum::http::StatusCode,
+        axum::Json<response::refresh_token::Response>,
     ) {
         let mut response = response::refresh_token::Response::default();
-        let key = icarus_envy::environment::get_secret_key().await.value;
-
-        if token_stuff::verify_token(&key, &payload.access_token) {
-            let token_type = token_stuff::get_token_type(&key, &payload.access_token).unwrap();
-
-            if token_stuff::is_token_type_valid(&token_type) {
-                // Get passphrase record with id
-                match token_stuff::extract_id_from_token(&key, &payload.access_token) {
-                    Ok(id) => match repo::service::get_passphrase(&pool, &id).await {
-                        Ok((username, _, _)) => {
-                            match token_stuff::create_service_refresh_token(&key, &id) {
-                                Ok((access_token, exp_dur)) => {
-                                    let login_result = icarus_models::login_result::LoginResult {
-                                        id,
-                                        token: access_token,
-                                        expiration: exp_dur,
-                                        token_type: String::from(icarus_models::token::TOKEN_TYPE),
-                                        username,
-                                    };
-                                    response.message = String::from("Successful");
-                                    response.data.push(login_result);
-
-                                    (axum::http::StatusCode::OK, axum::Json(response))
-                                }
-                                Err(err) => {
-                                    response.message = err.to_string();
-                                    (
-                                        axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                                        axum::Json(response),
-                                    )
-                                }
-                            }
-                        }
-                        Err(err) => {
-                            response.message = err.to_string();
-                            (
-                                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                                axum::Json(response),
-                            )
-                        }
-                    },
-                    Err(err) => {
-                        response.message = err.to_string();
-                        (
-                            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-                            axum::Json(response),
-                        )
-                    }
-                }
-            } else {
-                response.message = String::from("Invalid token type");
-                (axum::http::StatusCode::NOT_FOUND, axum::Json(response))
+        let key = &state.keys.secret;
+
+        // Decoded once, so the token cannot expire between reading its claims
+        let claims = match token_stuff::decode(key, &payload.access_token, &state.config.token) {
+            Ok(claims) => claims,
+            Err(_err) => {
+                response.message = String::from("Could not verify token");
+                return (axum::http::StatusCode::BAD_REQUEST, axum::Json(response));
+            }
+        };
+        if !token_stuff::is_token_type_valid(&claims.token_type) {
+            response.message = String::from("Invalid token type");
+            return (axum::http::StatusCode::NOT_FOUND, axum::Json(response));
+        }
+
+        let id = claims.sub;
+        let username = match repo::service::get_passphrase(&state.pool, &id).await {
+            Ok((username, _, _)) => username,
+            Err(err) => {
+                response.message = err.to_string();
+                return (
+                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    axum::Json(response),
+                );
+            }
+        };
+
+        let session_start = claims.auth_time;
+        let current_time = time::OffsetDateTime::now_utc();
+        let expiration = match token_stuff::get_refresh_expiration(
+            &state.config.token,
+            &session_start,
+            &current_time,
+        ) {
+            Some(expiration) => expiration,
+            None => {
+                response.message = String::from("Session has expired");
+                return (axum::http::StatusCode::UNAUTHORIZED, axum::Json(response));
+            }
+        };
+
+        match token_stuff::create_service_refresh_token(key, &id, &session_start, &expiration) {
+            Ok((access_token, exp_dur)) => {
+                let login_result = icarus_models::login_result::LoginResult {
+                    id,
+                    token: access_token,
+                    expiration: exp_dur,
+                    token_type: String::from(icarus_models::token::TOKEN_TYPE),
+                    username,
+                };
+                response.message = String::from("Successful");
+                response.data.push(login_result);
+
+                (axum::http::StatusCode::OK, axum::Json(response))
+            }
+            Err(err) => {
+                response.message = err.to_string();
+                (
+                    axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                    axum::Json(response),
+                )
             }
-        } else {
-            response.message = String::from("Could not verify token");
-            (axum::http::StatusCode::BAD_REQUEST, axum::Json(response))
         }
     }
 }
@@ -0,0 +1,325 @@
+use rand::Rng;
+
+/// Characters in a login token
+pub const TOKEN_LENGTH: usize = 43;
+/// Same answer whether or not the email belongs to a user
+pub const REQUESTED: &str = "If the email belongs to a user, a login link has been sent to it";
+pub const TOO_MANY_REQUESTS: &str = "Too many login links asked for, try again later";
+
+pub mod request {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Request {
+        #[schema(example = "bob@example.com")]
+        pub email: String,
+    }
+
+    pub mod consume {
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+        pub struct Request {
+            /// Token from the login link
+            pub token: String,
+        }
+    }
+}
+
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Response {
+        pub message: String,
+        pub data: Vec<String>,
+    }
+}
+
+/// Random token put in the link
+pub fn generate_token() -> String {
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Email with the login link
+pub fn message(to: &str, link: &str, ttl: i64) -> crate::mail::Message {
+    crate::mail::Message {
+        to: String::from(to),
+        subject: String::from("Your Icarus login link"),
+        body: format!(
+            "Use this link to log in to Icarus. It works once and expires in {} minutes.\n\n{link}\n\nIf you did not ask for it, you can ignore this email.\n",
+            (ttl + 59) / 60
+        ),
+    }
+}
+
+/// Module for magic link endpoints
+pub mod endpoint {
+    use axum::{
+        Json,
+        extract::{ConnectInfo, State},
+        http::{Extensions, StatusCode},
+    };
+
+    use super::request;
+    use super::response;
+    use crate::callers::login::response as login_response;
+    use crate::repo;
+    use crate::telemetry;
+    use crate::token_stuff;
+
+    fn reply(status: StatusCode, message: &str) -> (StatusCode, Json<response::Response>) {
+        (
+            status,
+            Json(response::Response {
+                message: String::from(message),
+                data: Vec::new(),
+            }),
+        )
+    }
+
+    fn login_reply(
+        status: StatusCode,
+        message: &str,
+    ) -> (StatusCode, Json<login_response::Response>) {
+        (
+            status,
+            Json(login_response::Response {
+                message: String::from(message),
+                data: Vec::new(),
+            }),
+        )
+    }
+
+    /// Endpoint to ask for a login link by email. The answer is the same, and takes as
+    /// long, whether or not the email belongs to a user. Requests are limited per email
+    /// and per client address
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::MAGIC_LINK,
+        request_body(
+            content = request::Request,
+            description = "Email to send the link to",
+            content_type = "application/json"
+        ),
+        responses(
+            (status = 202, description = "Link sent if the email belongs to a user", body = response::Response),
+            (status = 406, description = "Magic link login is not enabled", body = response::Response),
+            (status = 422, description = "Not a valid email", body = response::Response),
+            (status = 429, description = "Too many links asked for the email or from the client", body = response::Response)
+        )
+    )]
+    pub async fn request_link(
+        State(state): State<crate::state::AppState>,
+        extensions: Extensions,
+        Json(payload): Json<request::Request>,
+    ) -> (StatusCode, Json<response::Response>) {
+        if !state.config.magic_link.enabled {
+            return reply(
+                StatusCode::NOT_ACCEPTABLE,
+                "Magic link login is not enabled",
+            );
+        }
+        let email = match crate::validation::email(&payload.email) {
+            Ok(email) => email,
+            Err(message) => return reply(StatusCode::UNPROCESSABLE_ENTITY, &message),
+        };
+
+        // Keyed by what was asked for, so being throttled says nothing about the user
+        let settings = &state.config.magic_link;
+        let client = extensions
+            .get::<ConnectInfo<std::net::SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+        let throttle = &state.magic_link_throttle;
+        if client.is_some_and(|ip| !throttle.allow(&format!("ip:{ip}"), settings.per_ip_limit))
+            || !throttle.allow(
+                &format!("email:{}", crate::validation::fold(&email)),
+                settings.per_email_limit,
+            )
+        {
+            tracing::warn!(client = ?client, "Throttled magic link request");
+            return reply(StatusCode::TOO_MANY_REQUESTS, super::TOO_MANY_REQUESTS);
+        }
+
+        // Looking the user up and sending the email happen after answering, so the
+        // response time does not tell whether the user exists
+        tokio::spawn(async move { send_link(&state, &email).await });
+
+        reply(StatusCode::ACCEPTED, super::REQUESTED)
+    }
+
+    async fn send_link(state: &crate::state::AppState, email: &str) {
+        let user = match repo::user::get_by_identifier(&state.pool, email).await {
+            Ok((user, repo::user::identifier::EMAIL))
+                if user.status == repo::user::status::ACTIVE =>
+            {
+                user
+            }
+            Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                tracing::debug!("No active user for the magic link request");
+                return;
+            }
+            Err(err) => {
+                tracing::error!(error = %err, "Could not look up user for magic link");
+                return;
+            }
+        };
+
+        let settings = &state.config.magic_link;
+        let token = super::generate_token();
+        let expires_at = time::OffsetDateTime::now_utc() + time::Duration::seconds(settings.ttl);
+        if repo::magic_link::insert(
+            &state.pool,
+            &super::super::invitation::hash_code(&token),
+            &user.id,
+            &expires_at,
+        )
+        .await
+        .is_err()
+        {
+            return;
+        }
+
+        let link = settings.link_url.replace("{token}", &token);
+        match state
+            .mailer
+            .send(&super::message(&user.email, &link, settings.ttl))
+            .await
+        {
+            Ok(()) => tracing::info!(user_id = %user.id, "Sent magic link"),
+            Err(err) => {
+                tracing::error!(user_id = %user.id, error = %err, "Could not send magic link")
+            }
+        }
+    }
+
+    /// Endpoint to log in with the token from a login link. Each link works once
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::MAGIC_LINK_CONSUME,
+        request_body(
+            content = request::consume::Request,
+            description = "Token from the login link",
+            content_type = "application/json"
+        ),
+        responses(
+            (status = 200, description = "Successfully logged in", body = login_response::Response),
+            (status = 400, description = "Link is invalid, used or expired", body = login_response::Response),
+            (status = 403, description = "User is disabled or their registration is pending approval", body = login_response::Response),
+            (status = 406, description = "Magic link login is not enabled", body = login_response::Response)
+        )
+    )]
+    pub async fn consume(
+        State(state): State<crate::state::AppState>,
+        Json(payload): Json<request::consume::Request>,
+    ) -> (StatusCode, Json<login_response::Response>) {
+        if !state.config.magic_link.enabled {
+            return login_reply(
+                StatusCode::NOT_ACCEPTABLE,
+                "Magic link login is not enabled",
+            );
+        }
+
+        let token_hash = super::super::invitation::hash_code(&payload.token);
+        let user = match repo::magic_link::consume(&state.pool, &token_hash).await {
+            Ok(user_id) => repo::user::get_by_id(&state.pool, &user_id).await,
+            Err(err) => Err(err),
+        };
+        let user = match user {
+            Ok(user) => user,
+            Err(sqlx::Error::RowNotFound) => {
+                telemetry::login(
+                    telemetry::FAILURE,
+                    "invalid_link",
+                    repo::user::identifier::MAGIC_LINK,
+                );
+                return login_reply(StatusCode::BAD_REQUEST, "Login link is invalid or expired");
+            }
+            Err(err) => return login_reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        };
+
+        if user.status == repo::user::status::DISABLED {
+            telemetry::login(
+                telemetry::FAILURE,
+                "disabled",
+                repo::user::identifier::MAGIC_LINK,
+            );
+            return login_reply(StatusCode::FORBIDDEN, "User is disabled");
+        }
+        if user.status == repo::user::status::PENDING {
+            telemetry::login(
+                telemetry::FAILURE,
+                "pending",
+                repo::user::identifier::MAGIC_LINK,
+            );
+            return login_reply(StatusCode::FORBIDDEN, "Registration is pending approval");
+        }
+
+        let (token_literal, duration) =
+            match token_stuff::create_token(&state.keys.secret, &user.id, &state.config.token) {
+                Ok(token) => token,
+                Err(err) => {
+                    telemetry::login(
+                        telemetry::FAILURE,
+                        "token_error",
+                        repo::user::identifier::MAGIC_LINK,
+                    );
+                    return login_reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+                }
+            };
+
+        let current_time = time::OffsetDateTime::now_utc();
+        let _ = repo::user::update_last_login(&state.pool, &user, &current_time).await;
+        telemetry::login(
+            telemetry::SUCCESS,
+            "none",
+            repo::user::identifier::MAGIC_LINK,
+        );
+
+        (
+            StatusCode::OK,
+            Json(login_response::Response {
+                message: String::from("Successful"),
+                data: vec![icarus_models::login_result::LoginResult {
+                    id: user.id,
+                    username: user.username.clone(),
+                    token: token_literal,
+                    token_type: String::from(icarus_models::token::TOKEN_TYPE),
+                    expiration: duration,
+                }],
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let message = message(
+            "bob@example.com",
+            "https://soaricarus.com/login/magic?token=abc",
+            900,
+        );
+        assert_eq!(message.to, "bob@example.com");
+        assert!(
+            message
+                .body
+                .contains("https://soaricarus.com/login/magic?token=abc")
+        );
+        assert!(message.body.contains("15 minutes"));
+    }
+
+    #[test]
+    fn test_generate_token() {
+        let token = generate_token();
+        assert_eq!(token.len(), TOKEN_LENGTH);
+        assert_ne!(token, generate_token());
+    }
+}
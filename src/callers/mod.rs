@@ -1,6 +1,16 @@
+pub mod admin;
+pub mod approval;
 pub mod common;
+pub mod device;
+pub mod health;
+pub mod import;
+pub mod invitation;
 pub mod login;
+pub mod magic_link;
+pub mod oidc;
+pub mod password;
 pub mod register;
+pub mod token_exchange;
 
 pub mod endpoints {
     pub const ROOT: &str = "/";
@@ -9,4 +19,23 @@ pub mod endpoints {
     pub const LOGIN: &str = "/api/v2/login";
     pub const SERVICE_LOGIN: &str = "/api/v2/service/login";
     pub const REFRESH_TOKEN: &str = "/api/v2/token/refresh";
+    pub const TOKEN_EXCHANGE: &str = "/api/v2/token/exchange";
+    pub const CHANGE_PASSWORD: &str = "/api/v2/password/change";
+    pub const INVITATIONS: &str = "/api/v2/invitations";
+    pub const INVITATION: &str = "/api/v2/invitations/{id}";
+    pub const MAGIC_LINK: &str = "/api/v2/login/magic";
+    pub const MAGIC_LINK_CONSUME: &str = "/api/v2/login/magic/consume";
+    pub const DEVICE_CODE: &str = "/api/v2/device/code";
+    pub const DEVICE_TOKEN: &str = "/api/v2/device/token";
+    pub const DEVICE_APPROVE: &str = "/api/v2/device/approve";
+    pub const OIDC_AUTHORIZE: &str = "/api/v2/oidc/{provider}/authorize";
+    pub const OIDC_CALLBACK: &str = "/api/v2/oidc/{provider}/callback";
+    pub const HEALTHZ: &str = "/healthz";
+    pub const METRICS: &str = "/metrics";
+    pub const READYZ: &str = "/readyz";
+    pub const HEALTH_REPORT: &str = "/api/v2/admin/health";
+    pub const IMPORT_USERS: &str = "/api/v2/admin/users/import";
+    pub const PENDING_REGISTRATIONS: &str = "/api/v2/admin/registrations";
+    pub const APPROVE_REGISTRATION: &str = "/api/v2/admin/registrations/{id}/approve";
+    pub const REJECT_REGISTRATION: &str = "/api/v2/admin/registrations/{id}/reject";
 }
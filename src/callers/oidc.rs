@@ -0,0 +1,465 @@
+/// Tries at making a free username from the provider's before giving up
+const USERNAME_ATTEMPTS: usize = 5;
+
+pub mod request {
+    use serde::{Deserialize, Serialize};
+
+    /// Query the provider redirects back with
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::IntoParams)]
+    pub struct Callback {
+        #[serde(default)]
+        pub code: Option<String>,
+        #[serde(default)]
+        pub state: Option<String>,
+        /// Set instead of the code when the sign-in failed or was cancelled
+        #[serde(default)]
+        pub error: Option<String>,
+        #[serde(default)]
+        pub error_description: Option<String>,
+    }
+}
+
+/// Username for a new user signing in through a provider: the provider's preferred
+/// username or the local part of the email, reduced to the characters usernames allow
+pub fn username_from(claims: &crate::oidc::Claims) -> String {
+    let source = claims
+        .preferred_username
+        .as_deref()
+        .or_else(|| claims.email.as_deref().and_then(|e| e.split('@').next()))
+        .unwrap_or_default();
+
+    let mut username: String = crate::validation::normalize(source)
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'))
+        .skip_while(|c| !c.is_alphanumeric())
+        .take(crate::validation::USERNAME_MAX_LENGTH - 5)
+        .collect();
+
+    if username.chars().count() < crate::validation::USERNAME_MIN_LENGTH {
+        username = format!("user{username}");
+    }
+
+    username
+}
+
+/// Module for OpenID Connect sign-in endpoints
+pub mod endpoint {
+    use axum::{
+        Json,
+        extract::{Path, Query, State},
+        http::StatusCode,
+        response::{IntoResponse, Redirect},
+    };
+    use rand::Rng;
+
+    use super::request;
+    use crate::callers::login::response;
+    use crate::hashing;
+    use crate::notify;
+    use crate::oidc;
+    use crate::repo;
+    use crate::telemetry;
+    use crate::token_stuff;
+
+    fn reply(status: StatusCode, message: &str) -> (StatusCode, Json<response::Response>) {
+        (
+            status,
+            Json(response::Response {
+                message: String::from(message),
+                data: Vec::new(),
+            }),
+        )
+    }
+
+    fn unknown_provider() -> (StatusCode, Json<response::Response>) {
+        reply(StatusCode::NOT_FOUND, "Unknown identity provider")
+    }
+
+    /// Endpoint to start signing in with a provider. Redirects to the provider
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::OIDC_AUTHORIZE,
+        params(("provider" = String, Path, description = "Provider name from the configuration")),
+        responses(
+            (status = 303, description = "Redirect to the provider's sign-in page"),
+            (status = 404, description = "Unknown provider", body = response::Response),
+            (status = 502, description = "Provider metadata could not be fetched", body = response::Response)
+        )
+    )]
+    pub async fn authorize(
+        State(state): State<crate::state::AppState>,
+        Path(provider): Path<String>,
+    ) -> axum::response::Response {
+        let Some(settings) = state.config.oidc.provider(&provider) else {
+            return unknown_provider().into_response();
+        };
+
+        let login = repo::oidc::LoginState {
+            provider: settings.name.clone(),
+            nonce: oidc::random_string(),
+            pkce_verifier: oidc::random_string(),
+        };
+        let login_state = oidc::random_string();
+
+        let url = match state
+            .oidc
+            .authorization_url(settings, &login_state, &login.nonce, &login.pkce_verifier)
+            .await
+        {
+            Ok(url) => url,
+            Err(err) => {
+                tracing::warn!(provider, error = %err, "Could not start OIDC sign-in");
+                return reply(StatusCode::BAD_GATEWAY, &err.to_string()).into_response();
+            }
+        };
+
+        let expires_at =
+            time::OffsetDateTime::now_utc() + time::Duration::seconds(state.config.oidc.state_ttl);
+        if let Err(err) =
+            repo::oidc::insert_login_state(&state.pool, &login_state, &login, &expires_at).await
+        {
+            return reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()).into_response();
+        }
+
+        Redirect::to(&url).into_response()
+    }
+
+    /// Endpoint the provider redirects back to. Signs in the user linked to the
+    /// provider identity, creating one when registration is open
+    #[utoipa::path(
+        get,
+        path = super::super::endpoints::OIDC_CALLBACK,
+        params(
+            ("provider" = String, Path, description = "Provider name from the configuration"),
+            request::Callback
+        ),
+        responses(
+            (status = 200, description = "Successfully logged in", body = response::Response),
+            (status = 400, description = "Missing, invalid or expired state, or the provider reported an error", body = response::Response),
+            (status = 401, description = "ID token failed validation", body = response::Response),
+            (status = 403, description = "User is disabled, pending approval, or registration is not open", body = response::Response),
+            (status = 404, description = "Unknown provider", body = response::Response),
+            (status = 409, description = "A user with the email exists and is not linked to the identity", body = response::Response),
+            (status = 502, description = "Provider could not be reached or refused the code", body = response::Response)
+        )
+    )]
+    pub async fn callback(
+        State(state): State<crate::state::AppState>,
+        Path(provider): Path<String>,
+        Query(params): Query<request::Callback>,
+    ) -> (StatusCode, Json<response::Response>) {
+        let Some(settings) = state.config.oidc.provider(&provider) else {
+            return unknown_provider();
+        };
+
+        // The state is spent whatever happens next
+        let login = match &params.state {
+            Some(login_state) => repo::oidc::take_login_state(&state.pool, login_state).await,
+            None => Err(sqlx::Error::RowNotFound),
+        };
+
+        if let Some(error) = &params.error {
+            tracing::info!(provider, error, description = ?params.error_description, "Provider refused sign-in");
+            telemetry::login(
+                telemetry::FAILURE,
+                "provider_error",
+                repo::user::identifier::OIDC,
+            );
+            return reply(
+                StatusCode::BAD_REQUEST,
+                &format!("Provider returned an error: {error}"),
+            );
+        }
+
+        let login = match login {
+            Ok(login) if login.provider == settings.name => login,
+            Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                telemetry::login(
+                    telemetry::FAILURE,
+                    "invalid_state",
+                    repo::user::identifier::OIDC,
+                );
+                return reply(
+                    StatusCode::BAD_REQUEST,
+                    "Sign-in state is invalid or expired",
+                );
+            }
+            Err(err) => return reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()),
+        };
+        let Some(code) = params.code.as_deref().filter(|code| !code.is_empty()) else {
+            return reply(StatusCode::BAD_REQUEST, "Missing authorization code");
+        };
+
+        let claims = match state
+            .oidc
+            .sign_in(settings, code, &login.pkce_verifier, &login.nonce)
+            .await
+        {
+            Ok(claims) => claims,
+            Err(err @ oidc::Error::InvalidToken(_)) => {
+                tracing::warn!(provider, error = %err, "Rejected ID token");
+                telemetry::login(
+                    telemetry::FAILURE,
+                    "invalid_token",
+                    repo::user::identifier::OIDC,
+                );
+                return reply(StatusCode::UNAUTHORIZED, &err.to_string());
+            }
+            Err(err) => {
+                tracing::warn!(provider, error = %err, "OIDC sign-in failed");
+                telemetry::login(
+                    telemetry::FAILURE,
+                    "provider_error",
+                    repo::user::identifier::OIDC,
+                );
+                return reply(StatusCode::BAD_GATEWAY, &err.to_string());
+            }
+        };
+
+        let user = match find_or_create_user(&state, settings, &claims).await {
+            Ok(user) => user,
+            Err(response) => return response,
+        };
+
+        if user.status == repo::user::status::DISABLED {
+            telemetry::login(telemetry::FAILURE, "disabled", repo::user::identifier::OIDC);
+            return reply(StatusCode::FORBIDDEN, "User is disabled");
+        }
+        if user.status == repo::user::status::PENDING {
+            telemetry::login(telemetry::FAILURE, "pending", repo::user::identifier::OIDC);
+            return reply(StatusCode::FORBIDDEN, "Registration is pending approval");
+        }
+
+        let (token_literal, duration) =
+            match token_stuff::create_token(&state.keys.secret, &user.id, &state.config.token) {
+                Ok(token) => token,
+                Err(err) => {
+                    telemetry::login(
+                        telemetry::FAILURE,
+                        "token_error",
+                        repo::user::identifier::OIDC,
+                    );
+                    return reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+                }
+            };
+
+        let current_time = time::OffsetDateTime::now_utc();
+        let _ = repo::user::update_last_login(&state.pool, &user, &current_time).await;
+        telemetry::login(telemetry::SUCCESS, "none", repo::user::identifier::OIDC);
+
+        (
+            StatusCode::OK,
+            Json(response::Response {
+                message: String::from("Successful"),
+                data: vec![icarus_models::login_result::LoginResult {
+                    id: user.id,
+                    username: user.username.clone(),
+                    token: token_literal,
+                    token_type: String::from(icarus_models::token::TOKEN_TYPE),
+                    expiration: duration,
+                }],
+            }),
+        )
+    }
+
+    /// User linked to the identity. An unlinked identity is linked to the user with
+    /// the same verified email when the provider allows it, otherwise a new user is
+    /// created for it
+    async fn find_or_create_user(
+        state: &crate::state::AppState,
+        settings: &crate::config::OidcProvider,
+        claims: &oidc::Claims,
+    ) -> Result<icarus_models::user::User, (StatusCode, Json<response::Response>)> {
+        let internal_error =
+            |err: sqlx::Error| reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string());
+
+        match repo::oidc::get_linked_user(&state.pool, &settings.name, &claims.sub).await {
+            Ok(user_id) => {
+                return repo::user::get_by_id(&state.pool, &user_id)
+                    .await
+                    .map_err(internal_error);
+            }
+            Err(sqlx::Error::RowNotFound) => {}
+            Err(err) => return Err(internal_error(err)),
+        }
+
+        let email = claims
+            .email
+            .as_deref()
+            .and_then(|email| crate::validation::email(email).ok())
+            .unwrap_or_default();
+
+        if settings.link_by_email
+            && let Some(verified) = claims.verified_email()
+        {
+            match repo::user::get_by_identifier(&state.pool, verified).await {
+                Ok((user, repo::user::identifier::EMAIL)) => {
+                    repo::oidc::link(
+                        &state.pool,
+                        &settings.name,
+                        &claims.sub,
+                        &user.id,
+                        Some(&email),
+                    )
+                    .await
+                    .map_err(internal_error)?;
+                    tracing::info!(user_id = %user.id, provider = settings.name, "Linked identity by email");
+                    return Ok(user);
+                }
+                Ok(_) | Err(sqlx::Error::RowNotFound) => {}
+                Err(err) => return Err(internal_error(err)),
+            }
+        }
+
+        let registration = &state.config.registration;
+        if !registration.enabled || registration.invite_only {
+            telemetry::registration(telemetry::FAILURE, "disabled");
+            return Err(reply(StatusCode::FORBIDDEN, "Registration is not open"));
+        }
+
+        if !email.is_empty() {
+            match repo::user::exists(&state.pool, "", &email).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    telemetry::registration(telemetry::FAILURE, "exists");
+                    return Err(reply(
+                        StatusCode::CONFLICT,
+                        "A user with this email already exists",
+                    ));
+                }
+                Err(err) => return Err(internal_error(err)),
+            }
+        }
+
+        let username = free_username(state, claims).await.map_err(internal_error)?;
+        let mut user = icarus_models::user::User {
+            username,
+            email: email.clone(),
+            firstname: claims
+                .given_name
+                .as_deref()
+                .and_then(|name| crate::validation::name(name).ok())
+                .unwrap_or_default(),
+            lastname: claims
+                .family_name
+                .as_deref()
+                .and_then(|name| crate::validation::name(name).ok())
+                .unwrap_or_default(),
+            status: String::from(if registration.moderated {
+                repo::user::status::PENDING
+            } else {
+                repo::user::status::ACTIVE
+            }),
+            email_verified: claims.verified_email().is_some(),
+            ..Default::default()
+        };
+
+        // Nobody knows the password, the user signs in through the provider or resets it
+        let salt_string = hashing::generate_salt()
+            .map_err(|err| reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string()))?;
+        user.password = match state.hasher.hash(oidc::random_string(), salt_string).await {
+            Ok(hashed) => hashed,
+            Err(hashing::workers::Error::Saturated) => {
+                return Err(reply(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Server is busy, try again later",
+                ));
+            }
+            Err(err) => return Err(reply(StatusCode::INTERNAL_SERVER_ERROR, &err.to_string())),
+        };
+
+        match create_user(state, settings, claims, &user).await {
+            Ok((id, date_created)) => {
+                user.id = id;
+                user.date_created = date_created;
+                telemetry::registration(telemetry::SUCCESS, "none");
+                tracing::info!(user_id = %user.id, provider = settings.name, "Created user for identity");
+                if user.status == repo::user::status::PENDING {
+                    state
+                        .notifier
+                        .registration(notify::Event::RegistrationPending, &user);
+                }
+                Ok(user)
+            }
+            Err(err) if repo::is_unique_violation(&err) => {
+                telemetry::registration(telemetry::FAILURE, "exists");
+                Err(reply(
+                    StatusCode::CONFLICT,
+                    "Username or email is not available",
+                ))
+            }
+            Err(err) => {
+                telemetry::registration(telemetry::FAILURE, "error");
+                Err(internal_error(err))
+            }
+        }
+    }
+
+    /// The username derived from the claims, or that with a random suffix when taken
+    async fn free_username(
+        state: &crate::state::AppState,
+        claims: &oidc::Claims,
+    ) -> Result<String, sqlx::Error> {
+        let base = super::username_from(claims);
+        let mut username = base.clone();
+
+        for _ in 0..super::USERNAME_ATTEMPTS {
+            if !repo::user::exists(&state.pool, &username, "").await? {
+                return Ok(username);
+            }
+            username = format!("{base}{:04}", rand::rng().random_range(0..10000));
+        }
+
+        // Let the unique index turn it into a conflict
+        Ok(username)
+    }
+
+    /// The user and its link to the identity, written together
+    async fn create_user(
+        state: &crate::state::AppState,
+        settings: &crate::config::OidcProvider,
+        claims: &oidc::Claims,
+        user: &icarus_models::user::User,
+    ) -> Result<(uuid::Uuid, Option<time::OffsetDateTime>), sqlx::Error> {
+        let mut tx = state.pool.begin().await?;
+        let (id, date_created) = repo::user::insert(&mut *tx, user).await?;
+        repo::oidc::link(
+            &mut *tx,
+            &settings.name,
+            &claims.sub,
+            &id,
+            Some(&user.email)
+                .filter(|email| !email.is_empty())
+                .map(|email| email.as_str()),
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok((id, date_created))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_username_from() {
+        let mut claims = crate::oidc::Claims {
+            preferred_username: Some(String::from("Bob Smith!")),
+            email: Some(String::from("robert@example.com")),
+            ..Default::default()
+        };
+        assert_eq!(username_from(&claims), "BobSmith");
+
+        claims.preferred_username = None;
+        assert_eq!(username_from(&claims), "robert");
+
+        claims.email = Some(String::from("_x@example.com"));
+        assert_eq!(username_from(&claims), "userx");
+
+        claims.email = None;
+        assert_eq!(username_from(&claims), "user");
+        assert!(crate::validation::username(&username_from(&claims)).is_ok());
+    }
+}
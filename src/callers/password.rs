@@ -0,0 +1,129 @@
+pub mod request {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Request {
+        pub current_password: String,
+        pub new_password: String,
+    }
+}
+
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Response {
+        pub message: String,
+        /// Id of the user whose password was changed
+        pub data: Vec<uuid::Uuid>,
+    }
+}
+
+/// Module for password endpoints
+pub mod endpoint {
+    use axum::{Json, extract::State, http::StatusCode};
+
+    use super::request;
+    use super::response;
+    use crate::callers::admin::AppUser;
+    use crate::hashing;
+    use crate::repo;
+
+    fn reply(status: StatusCode, message: String) -> (StatusCode, Json<response::Response>) {
+        (
+            status,
+            Json(response::Response {
+                message,
+                data: Vec::new(),
+            }),
+        )
+    }
+
+    /// Endpoint for a logged in user to change their password
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::CHANGE_PASSWORD,
+        request_body(
+            content = request::Request,
+            description = "Current and new password",
+            content_type = "application/json"
+        ),
+        responses(
+            (status = 200, description = "Password changed", body = response::Response),
+            (status = 400, description = "New password rejected by the policy", body = response::Response),
+            (status = 401, description = "Missing token or wrong current password", body = response::Response),
+            (status = 403, description = "Not a user token"),
+            (status = 503, description = "Too many concurrent password hashes", body = response::Response)
+        )
+    )]
+    pub async fn change_password(
+        app_user: AppUser,
+        State(state): State<crate::state::AppState>,
+        Json(payload): Json<request::Request>,
+    ) -> (StatusCode, Json<response::Response>) {
+        let user = match repo::user::get_by_id(&state.pool, &app_user.id).await {
+            Ok(user) => user,
+            Err(sqlx::Error::RowNotFound) => {
+                return reply(StatusCode::UNAUTHORIZED, String::from("Unauthorized"));
+            }
+            Err(err) => return reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        };
+
+        match state
+            .hasher
+            .verify(payload.current_password.clone(), user.password.clone())
+            .await
+        {
+            Ok(true) => {}
+            Ok(false) => {
+                return reply(
+                    StatusCode::UNAUTHORIZED,
+                    String::from("Current password is incorrect"),
+                );
+            }
+            Err(hashing::workers::Error::Saturated) => {
+                return reply(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    String::from("Server is busy, try again later"),
+                );
+            }
+            Err(err) => return reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        }
+
+        let problems = match state
+            .password_policy
+            .clone()
+            .check_blocking(payload.new_password.clone(), user.username.clone())
+            .await
+        {
+            Ok(problems) => problems,
+            Err(err) => return reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        };
+        if !problems.is_empty() {
+            return reply(StatusCode::BAD_REQUEST, problems.join("; "));
+        }
+
+        let salt = hashing::generate_salt().unwrap();
+        let hashed = match state.hasher.hash(payload.new_password.clone(), salt).await {
+            Ok(hashed) => hashed,
+            Err(hashing::workers::Error::Saturated) => {
+                return reply(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    String::from("Server is busy, try again later"),
+                );
+            }
+            Err(err) => return reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        };
+
+        match repo::user::update_password(&state.pool, &user.id, &hashed).await {
+            Ok(()) => (
+                StatusCode::OK,
+                Json(response::Response {
+                    message: String::from("Password changed"),
+                    data: vec![user.id],
+                }),
+            ),
+            Err(err) => reply(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        }
+    }
+}
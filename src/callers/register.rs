@@ -1,7 +1,10 @@
 use axum::{Json, http::StatusCode};
 
 use crate::hashing;
+use crate::notify;
 use crate::repo;
+use crate::telemetry;
+use crate::validation;
 
 pub mod request {
     use serde::{Deserialize, Serialize};
@@ -20,6 +23,9 @@ pub mod request {
         pub firstname: String,
         #[serde(skip_serializing_if = "String::is_empty")]
         pub lastname: String,
+        /// Invitation code, required when registration is invite-only
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        pub invitation: String,
     }
 }
 
@@ -30,9 +36,61 @@ pub mod response {
     pub struct Response {
         pub message: String,
         pub data: Vec<icarus_models::user::User>,
+        /// Fields that failed validation
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub errors: Vec<crate::validation::FieldError>,
     }
 }
 
+impl request::Request {
+    /// Copy of the request with every field normalized, or every field that is invalid.
+    /// The password is left untouched and checked by the password policy instead
+    pub fn validate(&self) -> Result<request::Request, Vec<validation::FieldError>> {
+        let mut errors = Vec::new();
+        let mut check = |field: &str, result: Result<String, String>| match result {
+            Ok(value) => value,
+            Err(message) => {
+                errors.push(validation::FieldError::new(field, message));
+                String::new()
+            }
+        };
+
+        let validated = request::Request {
+            username: check("username", validation::username(&self.username)),
+            password: self.password.clone(),
+            email: check("email", validation::email(&self.email)),
+            phone: check("phone", validation::phone(&self.phone)),
+            firstname: check("firstname", validation::name(&self.firstname)),
+            lastname: check("lastname", validation::name(&self.lastname)),
+            invitation: self.invitation.trim().to_string(),
+        };
+
+        if errors.is_empty() {
+            Ok(validated)
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Same message whether the username, the email or both are taken
+pub const UNAVAILABLE: &str = "Username or email is not available";
+
+fn reply(
+    status: StatusCode,
+    message: String,
+    data: Vec<icarus_models::user::User>,
+) -> (StatusCode, Json<response::Response>) {
+    (
+        status,
+        Json(response::Response {
+            message,
+            data,
+            errors: Vec::new(),
+        }),
+    )
+}
+
 /// Endpoint to register a user
 #[utoipa::path(
     post,
@@ -43,117 +101,247 @@ pub mod response {
         content_type = "application/json"
     ),
     responses(
-        (status = 201, description = "User created", body = response::Response),
-        (status = 404, description = "User already exists", body = response::Response),
-        (status = 400, description = "Issue creating user", body = response::Response)
+        (status = 201, description = "User created, pending approval when registration is moderated", body = response::Response),
+        (status = 400, description = "Issue creating user or password rejected by the policy", body = response::Response),
+        (status = 403, description = "Invitation missing, invalid, expired or for another email", body = response::Response),
+        (status = 406, description = "Registration is not enabled", body = response::Response),
+        (status = 409, description = "Username or email already taken", body = response::Response),
+        (status = 422, description = "Invalid fields, listed in errors", body = response::Response),
+        (status = 500, description = "Could not hash the password", body = response::Response),
+        (status = 503, description = "Too many concurrent password hashes", body = response::Response)
     )
 )]
 pub async fn register_user(
-    axum::Extension(pool): axum::Extension<sqlx::PgPool>,
+    axum::extract::State(state): axum::extract::State<crate::state::AppState>,
     Json(payload): Json<request::Request>,
 ) -> (StatusCode, Json<response::Response>) {
-    let registration_enabled = match is_registration_enabled().await {
-        Ok(value) => value,
-        Err(err) => {
-            eprintln!("Error: {err:?}");
+    let pool = &state.pool;
+
+    if !state.config.registration.enabled {
+        telemetry::registration(telemetry::FAILURE, "disabled");
+        return reply(
+            StatusCode::NOT_ACCEPTABLE,
+            String::from("Registration is not enabled"),
+            Vec::new(),
+        );
+    }
+
+    let payload = match payload.validate() {
+        Ok(payload) => payload,
+        Err(errors) => {
+            telemetry::registration(telemetry::FAILURE, "invalid");
             return (
-                axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+                StatusCode::UNPROCESSABLE_ENTITY,
                 Json(response::Response {
-                    message: String::from("Registration check failed"),
+                    message: String::from("Invalid registration"),
                     data: Vec::new(),
+                    errors,
                 }),
             );
         }
     };
 
-    if registration_enabled {
-        let mut user = icarus_models::user::User {
-            username: payload.username.clone(),
-            password: payload.password.clone(),
-            email: payload.email.clone(),
-            phone: payload.phone.clone(),
-            firstname: payload.firstname.clone(),
-            lastname: payload.lastname.clone(),
-            status: String::from("Active"),
-            email_verified: true,
-            ..Default::default()
+    let invitation = match check_invitation(&state, &payload).await {
+        Ok(invitation) => invitation,
+        Err(response) => {
+            telemetry::registration(telemetry::FAILURE, "invitation");
+            return response;
+        }
+    };
+
+    let problems = match state
+        .password_policy
+        .clone()
+        .check_blocking(payload.password.clone(), payload.username.clone())
+        .await
+    {
+        Ok(problems) => problems,
+        Err(err) => {
+            telemetry::registration(telemetry::FAILURE, "error");
+            return reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.to_string(),
+                Vec::new(),
+            );
+        }
+    };
+    if !problems.is_empty() {
+        telemetry::registration(telemetry::FAILURE, "weak_password");
+        return reply(StatusCode::BAD_REQUEST, problems.join("; "), Vec::new());
+    }
+
+    let mut user = icarus_models::user::User {
+        username: payload.username.clone(),
+        password: payload.password.clone(),
+        email: payload.email.clone(),
+        phone: payload.phone.clone(),
+        firstname: payload.firstname.clone(),
+        lastname: payload.lastname.clone(),
+        status: String::from(if state.config.registration.moderated {
+            repo::user::status::PENDING
+        } else {
+            repo::user::status::ACTIVE
+        }),
+        email_verified: true,
+        ..Default::default()
+    };
+
+    let salt_string = hashing::generate_salt().unwrap();
+    user.password = match state.hasher.hash(user.password.clone(), salt_string).await {
+        Ok(hashed) => hashed,
+        Err(hashing::workers::Error::Saturated) => {
+            telemetry::registration(telemetry::FAILURE, "busy");
+            return reply(
+                StatusCode::SERVICE_UNAVAILABLE,
+                String::from("Server is busy, try again later"),
+                Vec::new(),
+            );
+        }
+        Err(err) => {
+            telemetry::registration(telemetry::FAILURE, "error");
+            return reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.to_string(),
+                Vec::new(),
+            );
+        }
+    };
+
+    // Hashed before the duplicate check so a taken username or email answers as slowly
+    // as a registration that goes through
+    match repo::user::exists(pool, &user.username, &user.email).await {
+        Ok(false) => {}
+        Ok(true) => {
+            telemetry::registration(telemetry::FAILURE, "exists");
+            return reply(StatusCode::CONFLICT, String::from(UNAVAILABLE), Vec::new());
+        }
+        Err(err) => {
+            telemetry::registration(telemetry::FAILURE, "error");
+            return reply(StatusCode::BAD_REQUEST, err.to_string(), Vec::new());
+        }
+    }
+
+    match create_user(pool, &user, invitation.as_ref()).await {
+        Ok((id, date_created)) => {
+            user.id = id;
+            user.date_created = date_created;
+            telemetry::registration(telemetry::SUCCESS, "none");
+
+            let message = if user.status == repo::user::status::PENDING {
+                state
+                    .notifier
+                    .registration(notify::Event::RegistrationPending, &user);
+                "User created, pending approval"
+            } else {
+                "User created"
+            };
+            reply(StatusCode::CREATED, String::from(message), vec![user])
+        }
+        Err(CreateError::InvitationUsed) => {
+            telemetry::registration(telemetry::FAILURE, "invitation");
+            reply(
+                StatusCode::FORBIDDEN,
+                String::from("Invitation is invalid or expired"),
+                Vec::new(),
+            )
+        }
+        // Lost the race against a concurrent registration for the same username or email
+        Err(CreateError::Db(err)) if repo::is_unique_violation(&err) => {
+            telemetry::registration(telemetry::FAILURE, "exists");
+            reply(StatusCode::CONFLICT, String::from(UNAVAILABLE), Vec::new())
+        }
+        Err(CreateError::Db(err)) => {
+            telemetry::registration(telemetry::FAILURE, "error");
+            reply(StatusCode::BAD_REQUEST, err.to_string(), vec![user])
+        }
+    }
+}
+
+/// Invitation the registration uses, if any. Required in invite-only mode, and must be
+/// for the registering email when it names one
+async fn check_invitation(
+    state: &crate::state::AppState,
+    payload: &request::Request,
+) -> Result<Option<repo::invitation::Invitation>, (StatusCode, Json<response::Response>)> {
+    if payload.invitation.is_empty() {
+        return if state.config.registration.invite_only {
+            Err(reply(
+                StatusCode::FORBIDDEN,
+                String::from("An invitation is required to register"),
+                Vec::new(),
+            ))
+        } else {
+            Ok(None)
         };
+    }
 
-        match repo::user::exists(&pool, &user.username).await {
-            Ok(res) => {
-                if res {
-                    (
-                        StatusCode::BAD_REQUEST,
-                        Json(response::Response {
-                            message: String::from("Error"),
-                            data: Vec::new(),
-                        }),
-                    )
-                } else {
-                    let salt_string = hashing::generate_salt().unwrap();
-                    let mut salt = icarus_models::user::salt::Salt::default();
-                    let generated_salt = salt_string;
-                    salt.salt = generated_salt.to_string();
-                    salt.id = repo::salt::insert(&pool, &salt).await.unwrap();
-                    user.salt_id = salt.id;
-                    let hashed_password =
-                        hashing::hash_password(&user.password, &generated_salt).unwrap();
-                    user.password = hashed_password;
-
-                    match repo::user::insert(&pool, &user).await {
-                        Ok((id, date_created)) => {
-                            user.id = id;
-                            user.date_created = date_created;
-                            (
-                                StatusCode::CREATED,
-                                Json(response::Response {
-                                    message: String::from("User created"),
-                                    data: vec![user],
-                                }),
-                            )
-                        }
-                        Err(err) => (
-                            StatusCode::BAD_REQUEST,
-                            Json(response::Response {
-                                message: err.to_string(),
-                                data: vec![user],
-                            }),
-                        ),
-                    }
-                }
-            }
-            Err(err) => (
-                StatusCode::BAD_REQUEST,
-                Json(response::Response {
-                    message: err.to_string(),
-                    data: vec![user],
-                }),
-            ),
-        }
-    } else {
-        (
-            axum::http::StatusCode::NOT_ACCEPTABLE,
-            Json(response::Response {
-                message: String::from("Registration is not enabled"),
-                data: Vec::new(),
-            }),
-        )
+    let code_hash = super::invitation::hash_code(&payload.invitation);
+    let invitation = match repo::invitation::get_by_code_hash(&state.pool, &code_hash).await {
+        Ok(invitation) if invitation.is_usable(time::OffsetDateTime::now_utc()) => invitation,
+        Ok(_) | Err(sqlx::Error::RowNotFound) => {
+            return Err(reply(
+                StatusCode::FORBIDDEN,
+                String::from("Invitation is invalid or expired"),
+                Vec::new(),
+            ));
+        }
+        Err(err) => {
+            return Err(reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                err.to_string(),
+                Vec::new(),
+            ));
+        }
+    };
+
+    if let Some(email) = &invitation.email
+        && validation::fold(email) != validation::fold(&payload.email)
+    {
+        return Err(reply(
+            StatusCode::FORBIDDEN,
+            String::from("Invitation is for a different email"),
+            Vec::new(),
+        ));
     }
+
+    Ok(Some(invitation))
+}
+
+enum CreateError {
+    /// The invitation expired or was used up by a concurrent registration
+    InvitationUsed,
+    Db(sqlx::Error),
 }
 
-/// Checks to see if registration is enabled
-async fn is_registration_enabled() -> Result<bool, std::io::Error> {
-    let key = String::from("ENABLE_REGISTRATION");
-    let var = icarus_envy::environment::get_env(&key).await;
-    let parsed_value = var.value.to_uppercase();
-
-    if parsed_value == "TRUE" {
-        Ok(true)
-    } else if parsed_value == "FALSE" {
-        Ok(false)
-    } else {
-        Err(std::io::Error::other(
-            "Could not determine value of ENABLE_REGISTRATION",
-        ))
+impl From<sqlx::Error> for CreateError {
+    fn from(err: sqlx::Error) -> Self {
+        CreateError::Db(err)
     }
 }
+
+/// Writes everything a registration creates in one transaction: the user and the use
+/// of its invitation. The `exists` check above only catches obvious duplicates, the
+/// unique indexes are what stop two concurrent registrations from both succeeding
+async fn create_user(
+    pool: &sqlx::PgPool,
+    user: &icarus_models::user::User,
+    invitation: Option<&repo::invitation::Invitation>,
+) -> Result<(uuid::Uuid, Option<time::OffsetDateTime>), CreateError> {
+    let mut tx = pool.begin().await?;
+    let (id, date_created) = repo::user::insert(&mut *tx, user).await?;
+
+    if let Some(invitation) = invitation {
+        match repo::invitation::consume(&mut tx, &invitation.id, &id).await {
+            Ok(()) => {}
+            Err(sqlx::Error::RowNotFound) => return Err(CreateError::InvitationUsed),
+            Err(err) => return Err(err.into()),
+        }
+        if let Some(role) = &invitation.role {
+            repo::user::update_role(&mut tx, &id, role).await?;
+        }
+    }
+
+    tx.commit().await?;
+
+    Ok((id, date_created))
+}
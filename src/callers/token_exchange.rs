@@ -0,0 +1,326 @@
+/// `grant_type` of a token exchange, see RFC 8693 section 2.1
+pub const GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+/// Token types this service issues and accepts, see RFC 8693 section 3
+pub const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+pub const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+pub mod request {
+    use serde::{Deserialize, Serialize};
+
+    /// Sent form encoded, see RFC 8693 section 2.1
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Request {
+        #[schema(example = "urn:ietf:params:oauth:grant-type:token-exchange")]
+        pub grant_type: String,
+        /// App token of the user the service acts for
+        pub subject_token: String,
+        #[schema(example = "urn:ietf:params:oauth:token-type:access_token")]
+        pub subject_token_type: String,
+        /// Service token of the service asking
+        pub actor_token: String,
+        #[serde(default)]
+        pub actor_token_type: Option<String>,
+        /// Space separated scopes put in the delegated token for the services it is sent to
+        #[serde(default)]
+        pub scope: Option<String>,
+        /// Only this service's own audience can be asked for
+        #[serde(default)]
+        pub audience: Option<String>,
+        #[serde(default)]
+        pub requested_token_type: Option<String>,
+    }
+}
+
+pub mod response {
+    use serde::{Deserialize, Serialize};
+
+    /// See RFC 8693 section 2.2.1
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Response {
+        pub access_token: String,
+        #[schema(example = "urn:ietf:params:oauth:token-type:access_token")]
+        pub issued_token_type: String,
+        #[schema(example = "Bearer")]
+        pub token_type: String,
+        /// Seconds until the token expires
+        pub expires_in: i64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub scope: Option<String>,
+    }
+
+    /// Error as defined by RFC 6749 section 5.2 and RFC 8693 section 2.2.2
+    #[derive(Debug, Default, Deserialize, Serialize, utoipa::ToSchema)]
+    pub struct Error {
+        #[schema(example = "invalid_grant")]
+        pub error: String,
+        pub error_description: String,
+    }
+}
+
+/// Requested scopes normalized to single spaces, or the first one the service may not ask for
+fn allowed_scope<'a>(requested: &'a str, allowed: &[String]) -> Result<Option<String>, &'a str> {
+    let scopes: Vec<&str> = requested.split_whitespace().collect();
+    if let Some(scope) = scopes
+        .iter()
+        .find(|scope| !allowed.iter().any(|allowed| allowed == *scope))
+    {
+        return Err(scope);
+    }
+    Ok(Some(scopes.join(" ")).filter(|scope| !scope.is_empty()))
+}
+
+/// Whether a `*_token_type` names a token this service issues
+fn is_access_token_type(token_type: &str) -> bool {
+    token_type == ACCESS_TOKEN_TYPE || token_type == JWT_TOKEN_TYPE
+}
+
+/// Module for token exchange endpoints
+pub mod endpoint {
+    use axum::{
+        Form, Json,
+        extract::State,
+        http::StatusCode,
+        response::{IntoResponse, Response},
+    };
+
+    use super::request;
+    use super::response;
+    use crate::repo;
+    use crate::telemetry;
+    use crate::token_stuff;
+
+    /// Token responses must not be cached, see RFC 6749 section 5.1
+    const NO_STORE: [(axum::http::HeaderName, &str); 1] =
+        [(axum::http::header::CACHE_CONTROL, "no-store")];
+
+    fn error(status: StatusCode, reason: &'static str, description: &str) -> Response {
+        telemetry::token_exchange(telemetry::FAILURE, reason);
+        (
+            status,
+            NO_STORE,
+            Json(response::Error {
+                error: String::from(reason),
+                error_description: String::from(description),
+            }),
+        )
+            .into_response()
+    }
+
+    /// Claims of a valid token of the given type
+    fn claims_of(
+        state: &crate::state::AppState,
+        token: &String,
+        token_type: &str,
+    ) -> Option<token_stuff::Claims> {
+        token_stuff::decode(&state.keys.secret, token, &state.config.token)
+            .ok()
+            .filter(|claims| claims.token_type == token_type)
+    }
+
+    /// Endpoint for a service to get a short-lived token to act for a user (RFC 8693).
+    /// The delegated token names the service in its `act` claim, never outlives the
+    /// user's token, and is not accepted by endpoints that need a user's own token
+    #[utoipa::path(
+        post,
+        path = super::super::endpoints::TOKEN_EXCHANGE,
+        request_body(
+            content = request::Request,
+            description = "The user's token and the service's own token",
+            content_type = "application/x-www-form-urlencoded"
+        ),
+        responses(
+            (status = 200, description = "Delegated token", body = response::Response),
+            (status = 400, description = "`invalid_request`, `invalid_grant`, `invalid_scope`, `invalid_target` or `unsupported_grant_type`", body = response::Error),
+            (status = 401, description = "`invalid_client`, the actor token is not a valid service token", body = response::Error)
+        )
+    )]
+    pub async fn exchange(
+        State(state): State<crate::state::AppState>,
+        Form(payload): Form<request::Request>,
+    ) -> Response {
+        if payload.grant_type != super::GRANT_TYPE {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "unsupported_grant_type",
+                "Only token exchange is supported",
+            );
+        }
+        let token_types = [
+            Some(payload.subject_token_type.as_str()),
+            payload.actor_token_type.as_deref(),
+            payload.requested_token_type.as_deref(),
+        ];
+        if token_types
+            .into_iter()
+            .flatten()
+            .any(|token_type| !super::is_access_token_type(token_type))
+        {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                "Only access tokens can be exchanged and issued",
+            );
+        }
+        if payload
+            .audience
+            .as_deref()
+            .is_some_and(|audience| audience != token_stuff::AUDIENCE)
+        {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_target",
+                "Delegated tokens are only issued for this service's audience",
+            );
+        }
+
+        let key = &state.keys.secret;
+        let actor = match claims_of(
+            &state,
+            &payload.actor_token,
+            token_stuff::SERVICE_TOKEN_TYPE,
+        ) {
+            Some(claims) => claims.sub,
+            None => {
+                return error(
+                    StatusCode::UNAUTHORIZED,
+                    "invalid_client",
+                    "The actor token must be a valid service token",
+                );
+            }
+        };
+        let service = match repo::service::get_passphrase(&state.pool, &actor).await {
+            Ok((username, _, _)) => username,
+            Err(sqlx::Error::RowNotFound) => {
+                return error(
+                    StatusCode::UNAUTHORIZED,
+                    "invalid_client",
+                    "Unknown service",
+                );
+            }
+            Err(err) => {
+                return error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    &err.to_string(),
+                );
+            }
+        };
+
+        let allowed = state
+            .config
+            .token
+            .delegated_scopes
+            .get(&service)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let scope =
+            match super::allowed_scope(payload.scope.as_deref().unwrap_or_default(), allowed) {
+                Ok(scope) => scope,
+                Err(scope) => {
+                    return error(
+                        StatusCode::BAD_REQUEST,
+                        "invalid_scope",
+                        &format!("The service may not ask for the scope {scope}"),
+                    );
+                }
+            };
+
+        // Only a user's own token can be exchanged, so delegation does not chain
+        let subject = match claims_of(&state, &payload.subject_token, token_stuff::APP_TOKEN_TYPE) {
+            Some(claims) => claims,
+            None => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_grant",
+                    "The subject token must be a valid user token",
+                );
+            }
+        };
+        let user_id = subject.sub;
+        match repo::user::get_by_id(&state.pool, &user_id).await {
+            Ok(user) if user.status == repo::user::status::ACTIVE => {}
+            Ok(_) | Err(sqlx::Error::RowNotFound) => {
+                return error(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_grant",
+                    "The user cannot be acted for",
+                );
+            }
+            Err(err) => {
+                return error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    &err.to_string(),
+                );
+            }
+        }
+
+        // Tokens within the clock skew of expiring pass validation but have no time left
+        let current_time = time::OffsetDateTime::now_utc();
+        if subject.exp <= current_time {
+            return error(
+                StatusCode::BAD_REQUEST,
+                "invalid_grant",
+                "The subject token has expired",
+            );
+        }
+        let expiration = subject
+            .exp
+            .min(current_time + state.config.token.ttl(token_stuff::TokenKind::Delegated));
+
+        let (access_token, expires_at) = match token_stuff::create_delegated_token(
+            key,
+            &user_id,
+            &actor,
+            scope.as_deref(),
+            &subject.auth_time,
+            &expiration,
+        ) {
+            Ok(token) => token,
+            Err(err) => {
+                return error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "server_error",
+                    &err.to_string(),
+                );
+            }
+        };
+
+        telemetry::token_exchange(telemetry::SUCCESS, "none");
+        tracing::info!(user_id = %user_id, actor = %actor, "Issued delegated token");
+
+        (
+            StatusCode::OK,
+            NO_STORE,
+            Json(response::Response {
+                access_token,
+                issued_token_type: String::from(super::ACCESS_TOKEN_TYPE),
+                token_type: String::from("Bearer"),
+                expires_in: expires_at - current_time.unix_timestamp(),
+                scope,
+            }),
+        )
+            .into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_scope() {
+        let allowed = vec![String::from("songs:read"), String::from("playlists:read")];
+        assert_eq!(
+            allowed_scope(" songs:read  playlists:read ", &allowed),
+            Ok(Some(String::from("songs:read playlists:read")))
+        );
+        assert_eq!(allowed_scope("", &allowed), Ok(None));
+        assert_eq!(allowed_scope("", &[]), Ok(None));
+        assert_eq!(
+            allowed_scope("songs:read songs:write", &allowed),
+            Err("songs:write")
+        );
+        assert_eq!(allowed_scope("songs:read", &[]), Err("songs:read"));
+    }
+}
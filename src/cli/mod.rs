@@ -0,0 +1,499 @@
+//! Command-line interface. `serve` runs the API, the other subcommands are
+//! administrative tasks run against the same database and configuration.
+
+use std::io::BufRead;
+
+use crate::config;
+use crate::hashing;
+use crate::repo;
+use crate::validation;
+
+/// Length of generated service passphrases and signing keys
+pub const SECRET_LENGTH: usize = 64;
+
+#[derive(Debug, clap::Parser)]
+#[command(version, about = "Auth API for the Icarus project")]
+pub struct Cli {
+    #[command(flatten)]
+    pub args: config::Args,
+    /// Defaults to `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum Command {
+    /// Run the API server
+    Serve,
+    /// Apply pending database migrations
+    Migrate,
+    /// Manage user accounts
+    User {
+        #[command(subcommand)]
+        command: UserCommand,
+    },
+    /// Manage service accounts
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+    /// Manage token signing keys
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum UserCommand {
+    /// Create an active user. The password is read from standard input
+    Create {
+        #[arg(long)]
+        username: String,
+        #[arg(long)]
+        email: String,
+        #[arg(long, default_value = "")]
+        phone: String,
+        #[arg(long, default_value = "")]
+        firstname: String,
+        #[arg(long, default_value = "")]
+        lastname: String,
+    },
+    /// Prevent a user from logging in
+    Disable {
+        /// Username or email, matched the way logins match them
+        #[arg(long)]
+        username: String,
+    },
+    /// Set a new password. The password is read from standard input
+    ResetPassword {
+        /// Username or email, matched the way logins match them
+        #[arg(long)]
+        username: String,
+    },
+    /// Import users with existing Argon2, bcrypt, PBKDF2 or scrypt hashes from a JSON
+    /// file shaped like the body of the import endpoint
+    Import {
+        #[arg(long)]
+        file: std::path::PathBuf,
+    },
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ServiceCommand {
+    /// Create a service account and print its passphrase
+    Create {
+        #[arg(long)]
+        username: String,
+        /// Allow the account to use administrative endpoints
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Replace the passphrase of a service account and print the new one
+    Rotate {
+        #[arg(long)]
+        id: uuid::Uuid,
+    },
+    /// Allow a service account to use administrative endpoints
+    Admin {
+        #[arg(long)]
+        id: uuid::Uuid,
+        /// Take the permission away instead
+        #[arg(long)]
+        revoke: bool,
+    },
+    /// List service accounts
+    List,
+}
+
+#[derive(Debug, clap::Subcommand)]
+pub enum KeysCommand {
+    /// Generate a new signing key. Tokens signed with the old key stop verifying
+    /// once the service is restarted with the new one
+    Rotate {
+        /// Dotenv file whose `SECRET_KEY` entry is replaced. The key is printed when not set
+        #[arg(long)]
+        env_file: Option<std::path::PathBuf>,
+    },
+}
+
+impl Cli {
+    /// Parses the command line and environment
+    pub fn load() -> Cli {
+        // Make values from a `.env` file visible to the argument parser
+        dotenvy::dotenv().ok();
+        <Cli as clap::Parser>::parse()
+    }
+}
+
+/// Runs an administrative subcommand. `serve` is handled by the caller
+pub async fn run(command: Command, config: config::Config) -> Result<(), std::io::Error> {
+    match command {
+        Command::Serve => Err(std::io::Error::other("serve is not an admin command")),
+        Command::Migrate => migrate(&config).await,
+        Command::User { command } => user(command, &config).await,
+        Command::Service { command } => service(command, &config).await,
+        Command::Keys { command } => keys(command),
+    }
+}
+
+async fn connect(config: &config::Config) -> Result<sqlx::PgPool, std::io::Error> {
+    crate::db::init::create_pool(&config.database)
+        .await
+        .map_err(std::io::Error::other)
+}
+
+async fn migrate(config: &config::Config) -> Result<(), std::io::Error> {
+    let pool = connect(config).await?;
+    crate::db::init::MIGRATOR
+        .run(&pool)
+        .await
+        .map_err(std::io::Error::other)?;
+    crate::db::init::normalize_identities(&pool)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let version = crate::db::init::applied_migration_version(&pool)
+        .await
+        .map_err(std::io::Error::other)?;
+    match version {
+        Some(version) => println!("Database is at migration {version}"),
+        None => println!("No migrations applied"),
+    }
+    Ok(())
+}
+
+async fn user(command: UserCommand, config: &config::Config) -> Result<(), std::io::Error> {
+    let pool = connect(config).await?;
+
+    match command {
+        UserCommand::Create {
+            username,
+            email,
+            phone,
+            firstname,
+            lastname,
+        } => {
+            let invalid = |field: &str, message: String| {
+                std::io::Error::other(format!("Invalid {field}: {message}"))
+            };
+            let username =
+                validation::username(&username).map_err(|err| invalid("username", err))?;
+            let email = validation::email(&email).map_err(|err| invalid("email", err))?;
+            let phone = validation::phone(&phone).map_err(|err| invalid("phone", err))?;
+            let firstname =
+                validation::name(&firstname).map_err(|err| invalid("firstname", err))?;
+            let lastname = validation::name(&lastname).map_err(|err| invalid("lastname", err))?;
+
+            if repo::user::exists(&pool, &username, &email)
+                .await
+                .map_err(std::io::Error::other)?
+            {
+                return Err(std::io::Error::other(format!(
+                    "User {username} or email {email} already exists"
+                )));
+            }
+
+            let password = read_password()?;
+            check_password(config, &password, &username)?;
+            let hashed = hash(&password, &config.hashing)?;
+            let user = icarus_models::user::User {
+                username,
+                password: hashed,
+                email,
+                phone,
+                firstname,
+                lastname,
+                status: String::from(repo::user::status::ACTIVE),
+                email_verified: true,
+                ..Default::default()
+            };
+
+            let (id, _date_created) = repo::user::insert(&pool, &user).await.map_err(|err| {
+                if repo::is_unique_violation(&err) {
+                    std::io::Error::other(format!(
+                        "User {} or email {} already exists",
+                        user.username, user.email
+                    ))
+                } else {
+                    std::io::Error::other(err)
+                }
+            })?;
+            println!("Created user {} ({id})", user.username);
+        }
+        UserCommand::Disable { username } => {
+            let (user, _) = repo::user::get_by_identifier(&pool, &username)
+                .await
+                .map_err(|err| not_found(err, &username))?;
+            repo::user::update_status(&pool, &user.id, repo::user::status::DISABLED)
+                .await
+                .map_err(|err| not_found(err, &username))?;
+            println!("Disabled user {} ({})", user.username, user.id);
+        }
+        UserCommand::ResetPassword { username } => {
+            let (user, _) = repo::user::get_by_identifier(&pool, &username)
+                .await
+                .map_err(|err| not_found(err, &username))?;
+
+            let password = read_password()?;
+            check_password(config, &password, &user.username)?;
+            let hashed = hash(&password, &config.hashing)?;
+            repo::user::update_password(&pool, &user.id, &hashed)
+                .await
+                .map_err(std::io::Error::other)?;
+            println!("Reset password of user {} ({})", user.username, user.id);
+        }
+        UserCommand::Import { file } => {
+            let contents = std::fs::read_to_string(&file)?;
+            let request: crate::callers::import::request::Request = serde_json::from_str(&contents)
+                .map_err(|err| {
+                    std::io::Error::other(format!("Invalid import file {}: {err}", file.display()))
+                })?;
+
+            let outcomes = crate::callers::import::import_users(&pool, &request.users).await;
+            let mut imported = 0;
+            for outcome in &outcomes {
+                match (&outcome.id, &outcome.error) {
+                    (Some(id), _) => {
+                        imported += 1;
+                        println!("Imported {} ({id})", outcome.username);
+                    }
+                    (None, Some(err)) => println!("Skipped {}: {err}", outcome.username),
+                    (None, None) => println!("Skipped {}", outcome.username),
+                }
+            }
+            println!("Imported {imported} of {} users", outcomes.len());
+        }
+    }
+
+    Ok(())
+}
+
+async fn service(command: ServiceCommand, config: &config::Config) -> Result<(), std::io::Error> {
+    let pool = connect(config).await?;
+
+    match command {
+        ServiceCommand::Create { username, admin } => {
+            let passphrase = generate_secret();
+            let (id, _date_created) = repo::service::insert(&pool, &username, &passphrase, admin)
+                .await
+                .map_err(std::io::Error::other)?;
+            println!("Created service account {username} ({id})");
+            println!("{passphrase}");
+        }
+        ServiceCommand::Rotate { id } => {
+            let passphrase = generate_secret();
+            repo::service::update_passphrase(&pool, &id, &passphrase)
+                .await
+                .map_err(|err| not_found(err, &id.to_string()))?;
+            println!("Rotated passphrase of service account {id}");
+            println!("{passphrase}");
+        }
+        ServiceCommand::Admin { id, revoke } => {
+            repo::service::set_admin(&pool, &id, !revoke)
+                .await
+                .map_err(|err| not_found(err, &id.to_string()))?;
+            if revoke {
+                println!("Service account {id} is no longer an admin");
+            } else {
+                println!("Service account {id} is now an admin");
+            }
+        }
+        ServiceCommand::List => {
+            let accounts = repo::service::list(&pool)
+                .await
+                .map_err(std::io::Error::other)?;
+            for (id, username, is_admin, date_created) in accounts {
+                let role = if is_admin { "admin" } else { "service" };
+                println!("{id}\t{username}\t{role}\t{date_created}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn keys(command: KeysCommand) -> Result<(), std::io::Error> {
+    match command {
+        KeysCommand::Rotate { env_file } => {
+            let key = generate_secret();
+            match env_file {
+                Some(path) => {
+                    let contents = match std::fs::read_to_string(&path) {
+                        Ok(contents) => contents,
+                        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+                        Err(err) => return Err(err),
+                    };
+                    std::fs::write(&path, replace_env_entry(&contents, &key))?;
+                    println!(
+                        "Wrote a new {} to {}. Restart the service to use it",
+                        crate::token_stuff::KEY_ENV,
+                        path.display()
+                    );
+                }
+                None => println!("{}={key}", crate::token_stuff::KEY_ENV),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn not_found(err: sqlx::Error, what: &str) -> std::io::Error {
+    match err {
+        sqlx::Error::RowNotFound => std::io::Error::other(format!("{what} was not found")),
+        err => std::io::Error::other(err),
+    }
+}
+
+/// Reads a password from the first line of standard input
+fn read_password() -> Result<String, std::io::Error> {
+    eprint!("Password: ");
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+
+    let password = line.trim_end_matches(['\r', '\n']).to_string();
+    if password.is_empty() {
+        Err(std::io::Error::other("Password cannot be empty"))
+    } else {
+        Ok(password)
+    }
+}
+
+/// Applies the password policy, including the breach list when one is configured
+fn check_password(
+    config: &config::Config,
+    password: &str,
+    username: &str,
+) -> Result<(), std::io::Error> {
+    let policy = crate::password_policy::Policy::load(&config.password)?;
+    let problems = policy.check(password, username);
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other(problems.join("\n")))
+    }
+}
+
+/// Hashes a password with a fresh salt
+fn hash(password: &String, settings: &config::Hashing) -> Result<String, std::io::Error> {
+    let salt_string = hashing::generate_salt().map_err(std::io::Error::other)?;
+    hashing::hash_password(password, &salt_string, settings).map_err(std::io::Error::other)
+}
+
+/// Random alphanumeric string used for passphrases and signing keys
+fn generate_secret() -> String {
+    use rand::Rng;
+
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(SECRET_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Replaces the signing key entry of a dotenv file, appending it when missing
+fn replace_env_entry(contents: &str, key: &str) -> String {
+    let prefix = format!("{}=", crate::token_stuff::KEY_ENV);
+    let entry = format!("{prefix}{key}");
+    let mut replaced = false;
+
+    let mut lines: Vec<String> = contents
+        .lines()
+        .map(|line| {
+            if line.trim_start().starts_with(&prefix) {
+                replaced = true;
+                entry.clone()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !replaced {
+        lines.push(entry);
+    }
+
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cli_definition() {
+        <Cli as clap::CommandFactory>::command().debug_assert();
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        let cli = <Cli as clap::Parser>::try_parse_from(["icarus_auth"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli = <Cli as clap::Parser>::try_parse_from([
+            "icarus_auth",
+            "user",
+            "disable",
+            "--username",
+            "bob",
+            "--max-connections",
+            "1",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::User {
+                command: UserCommand::Disable { ref username }
+            }) if username == "bob"
+        ));
+        assert_eq!(cli.args.max_connections, Some(1));
+
+        let cli = <Cli as clap::Parser>::try_parse_from([
+            "icarus_auth",
+            "service",
+            "admin",
+            "--id",
+            "22f9c775-cce9-457a-a147-9dafbb801f61",
+            "--revoke",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Service {
+                command: ServiceCommand::Admin { revoke: true, .. }
+            })
+        ));
+
+        assert!(
+            <Cli as clap::Parser>::try_parse_from([
+                "icarus_auth",
+                "service",
+                "rotate",
+                "--id",
+                "x"
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_replace_env_entry() {
+        let contents = "APP_ENV=development\nSECRET_KEY=old\n";
+        assert_eq!(
+            replace_env_entry(contents, "new"),
+            "APP_ENV=development\nSECRET_KEY=new\n"
+        );
+        assert_eq!(
+            replace_env_entry("APP_ENV=development", "new"),
+            "APP_ENV=development\nSECRET_KEY=new\n"
+        );
+    }
+
+    #[test]
+    fn test_generate_secret() {
+        let secret = generate_secret();
+        assert_eq!(secret.len(), SECRET_LENGTH);
+        assert!(secret.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(secret, generate_secret());
+    }
+}
@@ -1,10 +1,1466 @@
-pub fn get_full() -> String {
-    get_address() + ":" + &get_port()
+//! Service configuration, resolved once at startup from defaults, an optional
+//! TOML file, environment variables and command-line flags (in that order).
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Environment variables recognized by the service
+pub mod keys {
+    pub const CONFIG_FILE: &str = "ICARUS_AUTH_CONFIG";
+    pub const APP_ENV: &str = "APP_ENV";
+    pub const ADDRESS: &str = "BACKEND_ADDRESS";
+    pub const PORT: &str = "BACKEND_PORT";
+    pub const REQUEST_TIMEOUT: &str = "REQUEST_TIMEOUT";
+    pub const DRAIN_TIMEOUT: &str = "DRAIN_TIMEOUT";
+    pub const PRE_STOP_DELAY: &str = "PRE_STOP_DELAY";
+    pub const MAX_CONNECTIONS: &str = "DB_MAX_CONNECTIONS";
+    pub const ACQUIRE_TIMEOUT: &str = "DB_ACQUIRE_TIMEOUT";
+    pub const APP_TOKEN_TTL: &str = "APP_TOKEN_TTL";
+    pub const SERVICE_TOKEN_TTL: &str = "SERVICE_TOKEN_TTL";
+    pub const SERVICE_REFRESH_TOKEN_TTL: &str = "SERVICE_REFRESH_TOKEN_TTL";
+    pub const SLIDING_EXPIRATION: &str = "SLIDING_EXPIRATION";
+    pub const MAX_SESSION_LIFETIME: &str = "MAX_SESSION_LIFETIME";
+    pub const ENABLE_REGISTRATION: &str = "ENABLE_REGISTRATION";
+    pub const INVITATION_TTL: &str = "INVITATION_TTL";
+    pub const REGISTRATION_WEBHOOK_URL: &str = "REGISTRATION_WEBHOOK_URL";
+    pub const ALLOWED_ORIGINS: &str = "ALLOWED_ORIGINS";
+    pub const LOG_FORMAT: &str = "LOG_FORMAT";
+    pub const OTLP_ENDPOINT: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+    pub const HASH_MEMORY_COST: &str = "HASH_MEMORY_COST";
+    pub const HASH_TIME_COST: &str = "HASH_TIME_COST";
+    pub const HASH_PARALLELISM: &str = "HASH_PARALLELISM";
+    pub const PASSWORD_PEPPER: &str = "PASSWORD_PEPPER";
+    pub const MAX_CONCURRENT_HASHES: &str = "MAX_CONCURRENT_HASHES";
+    pub const PASSWORD_MIN_LENGTH: &str = "PASSWORD_MIN_LENGTH";
+    pub const PASSWORD_MAX_LENGTH: &str = "PASSWORD_MAX_LENGTH";
+    pub const PASSWORD_MIN_SCORE: &str = "PASSWORD_MIN_SCORE";
+    pub const BREACHED_PASSWORDS_FILE: &str = "BREACHED_PASSWORDS_FILE";
+    pub const HASH_QUEUE_TIMEOUT: &str = "HASH_QUEUE_TIMEOUT";
+}
+
+pub const PRODUCTION: &str = "production";
+pub const DEVELOPMENT: &str = "development";
+pub const LOG_FORMAT_JSON: &str = "json";
+pub const LOG_FORMAT_TEXT: &str = "text";
+
+/// Command-line flags shared by every subcommand. Every flag can also be provided
+/// through the environment
+#[derive(Debug, Default, clap::Args)]
+pub struct Args {
+    /// Path to a TOML configuration file
+    #[arg(long, global = true, env = keys::CONFIG_FILE)]
+    pub config: Option<PathBuf>,
+    /// Environment the service runs in (`development` or `production`)
+    #[arg(long, global = true, env = keys::APP_ENV)]
+    pub environment: Option<String>,
+    /// IP address to bind to
+    #[arg(long, global = true, env = keys::ADDRESS)]
+    pub address: Option<String>,
+    /// Port to listen on
+    #[arg(long, global = true, env = keys::PORT)]
+    pub port: Option<u16>,
+    /// Seconds before an in-flight request is aborted
+    #[arg(long, global = true, env = keys::REQUEST_TIMEOUT)]
+    pub request_timeout: Option<u64>,
+    /// Seconds in-flight requests are given to finish on shutdown
+    #[arg(long, global = true, env = keys::DRAIN_TIMEOUT)]
+    pub drain_timeout: Option<u64>,
+    /// Seconds the service keeps serving while reporting not ready before it stops
+    /// accepting connections on shutdown
+    #[arg(long, global = true, env = keys::PRE_STOP_DELAY)]
+    pub pre_stop_delay: Option<u64>,
+    /// Maximum number of database connections in the pool
+    #[arg(long, global = true, env = keys::MAX_CONNECTIONS)]
+    pub max_connections: Option<u32>,
+    /// Seconds to wait for a database connection
+    #[arg(long, global = true, env = keys::ACQUIRE_TIMEOUT)]
+    pub acquire_timeout: Option<u64>,
+    /// Lifetime of user access tokens in seconds
+    #[arg(long, global = true, env = keys::APP_TOKEN_TTL)]
+    pub app_token_ttl: Option<i64>,
+    /// Lifetime of service access tokens in seconds
+    #[arg(long, global = true, env = keys::SERVICE_TOKEN_TTL)]
+    pub service_token_ttl: Option<i64>,
+    /// Lifetime of refreshed service tokens in seconds
+    #[arg(long, global = true, env = keys::SERVICE_REFRESH_TOKEN_TTL)]
+    pub service_refresh_token_ttl: Option<i64>,
+    /// Whether refreshing a token extends the session (`TRUE` or `FALSE`)
+    #[arg(long, global = true, env = keys::SLIDING_EXPIRATION, value_parser = parse_toggle)]
+    pub sliding_expiration: Option<bool>,
+    /// Seconds after login beyond which a session can no longer be refreshed
+    #[arg(long, global = true, env = keys::MAX_SESSION_LIFETIME)]
+    pub max_session_lifetime: Option<i64>,
+    /// Whether new users can register (`TRUE`, `FALSE`, `INVITE` to require an
+    /// invitation code or `MODERATED` to hold new users until an admin approves them)
+    #[arg(long, global = true, env = keys::ENABLE_REGISTRATION, value_parser = parse_registration)]
+    pub enable_registration: Option<RegistrationMode>,
+    /// Seconds invitation codes stay valid when no expiry is given
+    #[arg(long, global = true, env = keys::INVITATION_TTL)]
+    pub invitation_ttl: Option<i64>,
+    /// URL registration events are posted to as JSON
+    #[arg(long, global = true, env = keys::REGISTRATION_WEBHOOK_URL)]
+    pub registration_webhook_url: Option<String>,
+    /// Comma separated origins allowed by CORS in production
+    #[arg(long, global = true, env = keys::ALLOWED_ORIGINS, value_delimiter = ',')]
+    pub allowed_origins: Option<Vec<String>>,
+    /// Log output format (`json` or `text`)
+    #[arg(long, global = true, env = keys::LOG_FORMAT)]
+    pub log_format: Option<String>,
+    /// Base OTLP/HTTP endpoint, traces are exported to its `/v1/traces`. Export is
+    /// disabled when not set
+    #[arg(long, global = true, env = keys::OTLP_ENDPOINT)]
+    pub otlp_endpoint: Option<String>,
+    /// Argon2id memory cost of new password hashes in KiB
+    #[arg(long, global = true, env = keys::HASH_MEMORY_COST)]
+    pub hash_memory_cost: Option<u32>,
+    /// Argon2id iterations of new password hashes
+    #[arg(long, global = true, env = keys::HASH_TIME_COST)]
+    pub hash_time_cost: Option<u32>,
+    /// Argon2id lanes of new password hashes
+    #[arg(long, global = true, env = keys::HASH_PARALLELISM)]
+    pub hash_parallelism: Option<u32>,
+    /// Server-side secret mixed into new password hashes
+    #[arg(long, global = true, env = keys::PASSWORD_PEPPER, hide_env_values = true)]
+    pub password_pepper: Option<String>,
+    /// Password hashes allowed to run at once
+    #[arg(long, global = true, env = keys::MAX_CONCURRENT_HASHES)]
+    pub max_concurrent_hashes: Option<usize>,
+    /// Seconds a request waits for a hashing slot before getting a 503
+    #[arg(long, global = true, env = keys::HASH_QUEUE_TIMEOUT)]
+    pub hash_queue_timeout: Option<u64>,
+    /// Minimum number of characters in a new password
+    #[arg(long, global = true, env = keys::PASSWORD_MIN_LENGTH)]
+    pub password_min_length: Option<usize>,
+    /// Maximum number of characters in a new password
+    #[arg(long, global = true, env = keys::PASSWORD_MAX_LENGTH)]
+    pub password_max_length: Option<usize>,
+    /// Minimum strength score of a new password, from 0 to 4
+    #[arg(long, global = true, env = keys::PASSWORD_MIN_SCORE)]
+    pub password_min_score: Option<u8>,
+    /// Pwned Passwords `SHA1:COUNT` file new passwords are checked against
+    #[arg(long, global = true, env = keys::BREACHED_PASSWORDS_FILE)]
+    pub breached_passwords_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub environment: String,
+    pub server: Server,
+    pub database: Database,
+    pub token: Token,
+    pub registration: Registration,
+    pub hashing: Hashing,
+    pub password: Password,
+    pub cors: Cors,
+    pub logging: Logging,
+    pub oidc: Oidc,
+    pub authentication: Authentication,
+    pub ldap: Ldap,
+    pub magic_link: MagicLink,
+    pub mail: Mail,
+    pub device: Device,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub address: String,
+    pub port: u16,
+    /// Seconds
+    pub request_timeout: u64,
+    /// Seconds in-flight requests are given to finish on shutdown
+    pub drain_timeout: u64,
+    /// Seconds between reporting not ready and no longer accepting connections on
+    /// shutdown, so load balancers stop sending traffic first
+    pub pre_stop_delay: u64,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+    pub max_connections: u32,
+    /// Seconds
+    pub acquire_timeout: u64,
+}
+
+/// Token lifetime policy. Durations are in seconds
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Token {
+    pub app_ttl: i64,
+    pub service_ttl: i64,
+    pub service_refresh_ttl: i64,
+    /// Tokens a service gets by exchanging a user's token, capped by that token's expiry
+    pub delegated_ttl: i64,
+    /// Scopes each service, by username, may ask for in a token exchange. Services not
+    /// listed can only get delegated tokens without a scope
+    pub delegated_scopes: HashMap<String, Vec<String>>,
+    /// Refreshing grants a full lifetime from now instead of from the start of the session
+    pub sliding_expiration: bool,
+    /// Absolute lifetime of a session, unlimited when not set
+    pub max_session: Option<i64>,
+    /// Seconds the clocks of this service and its clients may differ by when checking
+    /// when a token expires or becomes valid
+    pub clock_skew: i64,
+    /// Accept tokens issued before typed claims, which name their type in `sub`. Turn it
+    /// off once the last of them has expired
+    pub accept_legacy: bool,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Registration {
+    pub enabled: bool,
+    /// Registering requires an invitation code
+    pub invite_only: bool,
+    /// Seconds invitation codes stay valid when no expiry is given
+    pub invitation_ttl: i64,
+    /// Longest expiry, in seconds, admins can give an invitation code. Users are held to
+    /// `invitation_ttl`
+    pub max_invitation_ttl: i64,
+    /// New users are `Pending` until an admin approves them
+    pub moderated: bool,
+    /// URL registration events are posted to as JSON
+    pub webhook_url: Option<String>,
+}
+
+/// Value of `ENABLE_REGISTRATION`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistrationMode {
+    Open,
+    Invite,
+    Moderated,
+    Closed,
+}
+
+/// Argon2id parameters of new password hashes. Hashes stored with other parameters
+/// are replaced on the next successful login
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hashing {
+    /// KiB
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+    /// Secret mixed into new hashes. Hashes computed with it stop verifying if it changes
+    pub pepper: Option<String>,
+    /// Hashes allowed to run at once on the blocking thread pool
+    pub max_concurrent: usize,
+    /// Seconds a request waits for a hashing slot before being turned away
+    pub queue_timeout: u64,
+}
+
+/// Rules new passwords are checked against at registration, change and reset
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Password {
+    pub min_length: usize,
+    /// Bounds the input given to Argon2
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    /// Reject passwords containing the username
+    pub disallow_username: bool,
+    /// From 0 (too guessable) to 4 (very unguessable)
+    pub min_score: u8,
+    /// Pwned Passwords `SHA1:COUNT` file. No breach check when not set
+    pub breached_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Cors {
+    /// Origins allowed in production. Development always allows the local frontend
+    pub allowed_origins: Vec<String>,
+    /// Seconds the preflight response is cached for
+    pub max_age: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            environment: String::from(DEVELOPMENT),
+            server: Server::default(),
+            database: Database::default(),
+            token: Token::default(),
+            registration: Registration::default(),
+            hashing: Hashing::default(),
+            password: Password::default(),
+            cors: Cors::default(),
+            logging: Logging::default(),
+            oidc: Oidc::default(),
+            authentication: Authentication::default(),
+            ldap: Ldap::default(),
+            magic_link: MagicLink::default(),
+            mail: Mail::default(),
+            device: Device::default(),
+        }
+    }
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Server {
+            address: String::from("0.0.0.0"),
+            port: 8001,
+            request_timeout: 30,
+            drain_timeout: 30,
+            pre_stop_delay: 5,
+        }
+    }
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database {
+            max_connections: 5,
+            acquire_timeout: 30,
+        }
+    }
+}
+
+impl Default for Token {
+    fn default() -> Self {
+        Token {
+            app_ttl: time::Duration::hours(4).whole_seconds(),
+            service_ttl: time::Duration::hours(1).whole_seconds(),
+            service_refresh_ttl: time::Duration::hours(4).whole_seconds(),
+            delegated_ttl: time::Duration::minutes(5).whole_seconds(),
+            delegated_scopes: HashMap::new(),
+            sliding_expiration: true,
+            max_session: None,
+            clock_skew: 60,
+            accept_legacy: true,
+        }
+    }
+}
+
+impl Token {
+    pub fn ttl(&self, kind: crate::token_stuff::TokenKind) -> time::Duration {
+        let seconds = match kind {
+            crate::token_stuff::TokenKind::App => self.app_ttl,
+            crate::token_stuff::TokenKind::Service => self.service_ttl,
+            crate::token_stuff::TokenKind::ServiceRefresh => self.service_refresh_ttl,
+            crate::token_stuff::TokenKind::Delegated => self.delegated_ttl,
+        };
+        time::Duration::seconds(seconds)
+    }
+
+    pub fn max_session(&self) -> Option<time::Duration> {
+        self.max_session.map(time::Duration::seconds)
+    }
+}
+
+impl Default for Hashing {
+    fn default() -> Self {
+        Hashing {
+            memory_cost: argon2::Params::DEFAULT_M_COST,
+            time_cost: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+            pepper: None,
+            max_concurrent: std::thread::available_parallelism()
+                .map(|cores| cores.get())
+                .unwrap_or(1),
+            queue_timeout: 5,
+        }
+    }
 }
-fn get_address() -> String {
-    String::from("0.0.0.0")
+
+/// External OpenID Connect identity providers users can sign in with. Only set in the
+/// configuration file
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Oidc {
+    pub providers: Vec<OidcProvider>,
+    /// Seconds a sign-in started at a provider has to come back
+    pub state_ttl: i64,
+}
+
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OidcProvider {
+    /// Used in the endpoint paths, e.g. `/api/v2/oidc/{name}/authorize`
+    pub name: String,
+    /// Issuer URL. Endpoints are discovered from its `/.well-known/openid-configuration`
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// This service's callback URL registered at the provider
+    pub redirect_url: String,
+    pub scopes: Vec<String>,
+    /// Algorithms the provider signs ID tokens with, `RS256` when not set. Tokens signed
+    /// with any other algorithm are rejected
+    pub algorithms: Vec<String>,
+    /// Sign in to an existing user with the same email when the provider says it is
+    /// verified, instead of creating a new user
+    pub link_by_email: bool,
+    /// Override discovery when the provider does not support it
+    pub authorization_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+    pub jwks_uri: Option<String>,
+}
+
+impl std::fmt::Debug for OidcProvider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OidcProvider")
+            .field("name", &self.name)
+            .field("issuer", &self.issuer)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .field("redirect_url", &self.redirect_url)
+            .field("scopes", &self.scopes)
+            .field("algorithms", &self.algorithms)
+            .field("link_by_email", &self.link_by_email)
+            .field("authorization_endpoint", &self.authorization_endpoint)
+            .field("token_endpoint", &self.token_endpoint)
+            .field("jwks_uri", &self.jwks_uri)
+            .finish()
+    }
+}
+
+impl Default for Oidc {
+    fn default() -> Self {
+        Oidc {
+            providers: Vec::new(),
+            state_ttl: 600,
+        }
+    }
+}
+
+impl Oidc {
+    pub fn provider(&self, name: &str) -> Option<&OidcProvider> {
+        self.providers.iter().find(|provider| provider.name == name)
+    }
+}
+
+impl OidcProvider {
+    /// Algorithms ID tokens from the provider may be signed with
+    pub fn algorithms(&self) -> impl Iterator<Item = &str> {
+        let configured = self.algorithms.iter().map(String::as_str);
+        let default = crate::oidc::DEFAULT_ALGORITHMS.into_iter();
+        configured.chain(default.filter(|_| self.algorithms.is_empty()))
+    }
+}
+
+/// Where login credentials are checked. Only set in the configuration file
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Authentication {
+    /// Tried in order until one accepts the credentials
+    pub backends: Vec<Backend>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Users and password hashes in the database
+    Local,
+    /// Bind as the user in an LDAP or Active Directory server
+    Ldap,
+}
+
+impl Default for Authentication {
+    fn default() -> Self {
+        Authentication {
+            backends: vec![Backend::Local],
+        }
+    }
+}
+
+/// Directory used by the `ldap` backend. Only set in the configuration file
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ldap {
+    /// `ldap://`, `ldaps://` or `ldapi://` URL of the server
+    pub url: String,
+    /// Upgrade an `ldap://` connection with StartTLS
+    pub starttls: bool,
+    /// Seconds to wait for the server to connect or answer
+    pub timeout: u64,
+    /// Account users are searched with. The search is anonymous when empty
+    pub bind_dn: String,
+    pub bind_password: String,
+    /// Where users are searched below
+    pub base_dn: String,
+    /// `{identifier}` is replaced with the escaped username or email being logged in with
+    pub user_filter: String,
+    pub attributes: LdapAttributes,
+}
+
+/// Directory attributes read into the local user
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LdapAttributes {
+    /// Stable id of the entry. The DN is used when the entry does not have it
+    pub subject: String,
+    pub username: String,
+    pub email: String,
+    pub firstname: String,
+    pub lastname: String,
+    pub phone: String,
+}
+
+impl Default for Ldap {
+    fn default() -> Self {
+        Ldap {
+            url: String::new(),
+            starttls: false,
+            timeout: 5,
+            bind_dn: String::new(),
+            bind_password: String::new(),
+            base_dn: String::new(),
+            user_filter: String::from("(|(uid={identifier})(mail={identifier}))"),
+            attributes: LdapAttributes::default(),
+        }
+    }
+}
+
+impl Default for LdapAttributes {
+    fn default() -> Self {
+        LdapAttributes {
+            subject: String::from("entryUUID"),
+            username: String::from("uid"),
+            email: String::from("mail"),
+            firstname: String::from("givenName"),
+            lastname: String::from("sn"),
+            phone: String::from("telephoneNumber"),
+        }
+    }
+}
+
+impl std::fmt::Debug for Ldap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Ldap")
+            .field("url", &self.url)
+            .field("starttls", &self.starttls)
+            .field("timeout", &self.timeout)
+            .field("bind_dn", &self.bind_dn)
+            .field("bind_password", &"<redacted>")
+            .field("base_dn", &self.base_dn)
+            .field("user_filter", &self.user_filter)
+            .field("attributes", &self.attributes)
+            .finish()
+    }
+}
+
+/// Passwordless login through a link sent by email. Only set in the configuration file
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MagicLink {
+    pub enabled: bool,
+    /// Seconds a link stays valid
+    pub ttl: i64,
+    /// Page the link opens, with `{token}` replaced by the login token. The page posts
+    /// the token to `/api/v2/login/magic/consume`
+    pub link_url: String,
+    /// Links that can be asked for per email address within `limit_window`
+    pub per_email_limit: u32,
+    /// Links that can be asked for per client IP address within `limit_window`
+    pub per_ip_limit: u32,
+    /// Seconds
+    pub limit_window: u64,
+}
+
+impl Default for MagicLink {
+    fn default() -> Self {
+        MagicLink {
+            enabled: false,
+            ttl: 900,
+            link_url: String::from("http://localhost:4200/login/magic?token={token}"),
+            per_email_limit: 3,
+            per_ip_limit: 20,
+            limit_window: 3600,
+        }
+    }
+}
+
+/// Device authorization grant (RFC 8628) for clients that cannot take a password, such
+/// as TVs and terminals. Only set in the configuration file
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Device {
+    /// Seconds a device code and its user code stay valid
+    pub ttl: i64,
+    /// Seconds a device waits between polls, raised by 5 each time it polls too fast
+    pub interval: i32,
+    /// Page where a logged in user enters the user code
+    pub verification_url: String,
+}
+
+impl Default for Device {
+    fn default() -> Self {
+        Device {
+            ttl: 600,
+            interval: 5,
+            verification_url: String::from("http://localhost:4200/device"),
+        }
+    }
 }
 
-fn get_port() -> String {
-    String::from("8001")
+/// How email is sent. Only set in the configuration file
+#[derive(Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mail {
+    pub transport: MailTransport,
+    /// Sender, e.g. `Icarus <no-reply@soaricarus.com>`
+    pub from: String,
+    pub smtp_host: String,
+    pub smtp_port: u16,
+    pub smtp_tls: SmtpTls,
+    /// No authentication when empty
+    pub smtp_username: String,
+    pub smtp_password: String,
+    /// Seconds to wait for the SMTP server
+    pub smtp_timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MailTransport {
+    /// Messages are only written to the log, for development
+    Log,
+    Smtp,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Upgrade a plain connection, usually on port 587
+    Starttls,
+    /// TLS from the start, usually on port 465
+    Tls,
+    /// No encryption, only for a relay on the same host
+    Plain,
+}
+
+impl Default for Mail {
+    fn default() -> Self {
+        Mail {
+            transport: MailTransport::Log,
+            from: String::from("Icarus <no-reply@localhost>"),
+            smtp_host: String::new(),
+            smtp_port: 587,
+            smtp_tls: SmtpTls::Starttls,
+            smtp_username: String::new(),
+            smtp_password: String::new(),
+            smtp_timeout: 10,
+        }
+    }
+}
+
+impl std::fmt::Debug for Mail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mail")
+            .field("transport", &self.transport)
+            .field("from", &self.from)
+            .field("smtp_host", &self.smtp_host)
+            .field("smtp_port", &self.smtp_port)
+            .field("smtp_tls", &self.smtp_tls)
+            .field("smtp_username", &self.smtp_username)
+            .field("smtp_password", &"<redacted>")
+            .field("smtp_timeout", &self.smtp_timeout)
+            .finish()
+    }
+}
+
+// The pepper is kept out of debug output
+impl std::fmt::Debug for Hashing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hashing")
+            .field("memory_cost", &self.memory_cost)
+            .field("time_cost", &self.time_cost)
+            .field("parallelism", &self.parallelism)
+            .field("pepper", &self.pepper.as_ref().map(|_| "<redacted>"))
+            .field("max_concurrent", &self.max_concurrent)
+            .field("queue_timeout", &self.queue_timeout)
+            .finish()
+    }
+}
+
+impl Default for Password {
+    fn default() -> Self {
+        Password {
+            min_length: 8,
+            max_length: 128,
+            require_lowercase: false,
+            require_uppercase: false,
+            require_digit: false,
+            require_symbol: false,
+            disallow_username: true,
+            min_score: 2,
+            breached_file: None,
+        }
+    }
+}
+
+impl Default for Registration {
+    fn default() -> Self {
+        Registration {
+            // Closed unless turned on, so an upgrade never opens sign-ups by surprise
+            enabled: false,
+            invite_only: false,
+            // 7 days
+            invitation_ttl: 604800,
+            // 90 days
+            max_invitation_ttl: 7776000,
+            moderated: false,
+            webhook_url: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Logging {
+    /// `json` or `text`. The level is controlled with `RUST_LOG`
+    pub format: String,
+    /// Base OTLP/HTTP endpoint such as `http://localhost:4318`, without `/v1/traces`
+    pub otlp_endpoint: Option<String>,
+    /// Service name reported with exported traces
+    pub service_name: String,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            format: String::from(LOG_FORMAT_JSON),
+            otlp_endpoint: None,
+            service_name: String::from("icarus_auth"),
+        }
+    }
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Cors {
+            allowed_origins: Vec::new(),
+            max_age: 3600,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from already parsed arguments
+    pub fn from_args(args: Args) -> Result<Config, std::io::Error> {
+        let mut config = match &args.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply(args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, std::io::Error> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            std::io::Error::other(format!(
+                "Could not read config file {}: {err}",
+                path.display()
+            ))
+        })?;
+        Config::from_toml(&contents).map_err(|err| {
+            std::io::Error::other(format!("Invalid config file {}: {err}", path.display()))
+        })
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Config, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    fn apply(&mut self, args: Args) {
+        if let Some(environment) = args.environment {
+            self.environment = environment;
+        }
+        if let Some(address) = args.address {
+            self.server.address = address;
+        }
+        if let Some(port) = args.port {
+            self.server.port = port;
+        }
+        if let Some(timeout) = args.request_timeout {
+            self.server.request_timeout = timeout;
+        }
+        if let Some(timeout) = args.drain_timeout {
+            self.server.drain_timeout = timeout;
+        }
+        if let Some(delay) = args.pre_stop_delay {
+            self.server.pre_stop_delay = delay;
+        }
+        if let Some(max) = args.max_connections {
+            self.database.max_connections = max;
+        }
+        if let Some(timeout) = args.acquire_timeout {
+            self.database.acquire_timeout = timeout;
+        }
+        if let Some(ttl) = args.app_token_ttl {
+            self.token.app_ttl = ttl;
+        }
+        if let Some(ttl) = args.service_token_ttl {
+            self.token.service_ttl = ttl;
+        }
+        if let Some(ttl) = args.service_refresh_token_ttl {
+            self.token.service_refresh_ttl = ttl;
+        }
+        if let Some(sliding) = args.sliding_expiration {
+            self.token.sliding_expiration = sliding;
+        }
+        if let Some(lifetime) = args.max_session_lifetime {
+            self.token.max_session = Some(lifetime);
+        }
+        if let Some(mode) = args.enable_registration {
+            self.registration.enabled = mode != RegistrationMode::Closed;
+            self.registration.invite_only = mode == RegistrationMode::Invite;
+            self.registration.moderated = mode == RegistrationMode::Moderated;
+        }
+        if let Some(url) = args.registration_webhook_url {
+            self.registration.webhook_url = Some(url);
+        }
+        self.registration.webhook_url = self
+            .registration
+            .webhook_url
+            .take()
+            .filter(|url| !url.is_empty());
+        if let Some(ttl) = args.invitation_ttl {
+            self.registration.invitation_ttl = ttl;
+        }
+        if let Some(cost) = args.hash_memory_cost {
+            self.hashing.memory_cost = cost;
+        }
+        if let Some(cost) = args.hash_time_cost {
+            self.hashing.time_cost = cost;
+        }
+        if let Some(parallelism) = args.hash_parallelism {
+            self.hashing.parallelism = parallelism;
+        }
+        if let Some(pepper) = args.password_pepper {
+            self.hashing.pepper = Some(pepper);
+        }
+        self.hashing.pepper = self
+            .hashing
+            .pepper
+            .take()
+            .filter(|pepper| !pepper.is_empty());
+        if let Some(format) = args.log_format {
+            self.logging.format = format;
+        }
+        if let Some(endpoint) = args.otlp_endpoint {
+            self.logging.otlp_endpoint = Some(endpoint).filter(|endpoint| !endpoint.is_empty());
+        }
+        if let Some(origins) = args.allowed_origins {
+            self.cors.allowed_origins = origins
+                .into_iter()
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+    }
+
+    /// Checks the values so misconfiguration is reported before the server starts
+    pub fn validate(&self) -> Result<(), std::io::Error> {
+        let mut problems: Vec<String> = Vec::new();
+
+        if self.environment != PRODUCTION && self.environment != DEVELOPMENT {
+            problems.push(format!(
+                "{}: expected `{PRODUCTION}` or `{DEVELOPMENT}`, got `{}`",
+                keys::APP_ENV,
+                self.environment
+            ));
+        }
+        if self.server.address.parse::<std::net::IpAddr>().is_err() {
+            problems.push(format!(
+                "{}: `{}` is not an IP address",
+                keys::ADDRESS,
+                self.server.address
+            ));
+        }
+        if self.server.request_timeout == 0 {
+            problems.push(format!("{}: must be greater than 0", keys::REQUEST_TIMEOUT));
+        }
+        if self.database.max_connections == 0 {
+            problems.push(format!("{}: must be greater than 0", keys::MAX_CONNECTIONS));
+        }
+        if self.database.acquire_timeout == 0 {
+            problems.push(format!("{}: must be greater than 0", keys::ACQUIRE_TIMEOUT));
+        }
+        for (key, ttl) in [
+            (keys::APP_TOKEN_TTL, self.token.app_ttl),
+            (keys::SERVICE_TOKEN_TTL, self.token.service_ttl),
+            (
+                keys::SERVICE_REFRESH_TOKEN_TTL,
+                self.token.service_refresh_ttl,
+            ),
+            ("token.delegated_ttl", self.token.delegated_ttl),
+            (keys::INVITATION_TTL, self.registration.invitation_ttl),
+            (
+                "registration.max_invitation_ttl",
+                self.registration.max_invitation_ttl,
+            ),
+        ] {
+            if ttl <= 0 {
+                problems.push(format!("{key}: must be greater than 0"));
+            }
+        }
+        if self.registration.max_invitation_ttl < self.registration.invitation_ttl {
+            problems.push(format!(
+                "registration.max_invitation_ttl: must be at least {}",
+                keys::INVITATION_TTL
+            ));
+        }
+        if self.token.clock_skew < 0 {
+            problems.push(String::from("token.clock_skew: must not be negative"));
+        }
+        for (service, scopes) in &self.token.delegated_scopes {
+            if scopes
+                .iter()
+                .any(|scope| scope.is_empty() || scope.contains(char::is_whitespace))
+            {
+                problems.push(format!(
+                    "token.delegated_scopes.{service}: scopes must be single words"
+                ));
+            }
+        }
+        if self.token.max_session.is_some_and(|lifetime| lifetime <= 0) {
+            problems.push(format!(
+                "{}: must be greater than 0",
+                keys::MAX_SESSION_LIFETIME
+            ));
+        }
+        if let Err(err) = argon2::Params::new(
+            self.hashing.memory_cost,
+            self.hashing.time_cost,
+            self.hashing.parallelism,
+            None,
+        ) {
+            problems.push(format!(
+                "{}, {}, {}: {err}",
+                keys::HASH_MEMORY_COST,
+                keys::HASH_TIME_COST,
+                keys::HASH_PARALLELISM
+            ));
+        }
+        if self.hashing.max_concurrent == 0 {
+            problems.push(format!(
+                "{}: must be greater than 0",
+                keys::MAX_CONCURRENT_HASHES
+            ));
+        }
+        if self.password.max_length == 0 {
+            problems.push(format!(
+                "{}: must be greater than 0",
+                keys::PASSWORD_MAX_LENGTH
+            ));
+        }
+        if self.password.min_length > self.password.max_length {
+            problems.push(format!(
+                "{}: must not be greater than {}",
+                keys::PASSWORD_MIN_LENGTH,
+                keys::PASSWORD_MAX_LENGTH
+            ));
+        }
+        if self.password.min_score > 4 {
+            problems.push(format!(
+                "{}: must be between 0 and 4",
+                keys::PASSWORD_MIN_SCORE
+            ));
+        }
+        if let Some(url) = &self.registration.webhook_url
+            && reqwest::Url::parse(url).is_err()
+        {
+            problems.push(format!(
+                "{}: `{url}` is not a valid URL",
+                keys::REGISTRATION_WEBHOOK_URL
+            ));
+        }
+        if self.oidc.state_ttl <= 0 {
+            problems.push(String::from("oidc.state_ttl: must be greater than 0"));
+        }
+        for (index, provider) in self.oidc.providers.iter().enumerate() {
+            let name = &provider.name;
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                problems.push(format!(
+                    "oidc.providers[{index}].name: `{name}` must be letters, digits, '-' or '_'"
+                ));
+            }
+            if self.oidc.providers[..index]
+                .iter()
+                .any(|other| other.name == *name)
+            {
+                problems.push(format!(
+                    "oidc.providers[{index}].name: `{name}` is repeated"
+                ));
+            }
+            if provider.client_id.is_empty() {
+                problems.push(format!("oidc.providers[{index}].client_id: is required"));
+            }
+            for algorithm in &provider.algorithms {
+                if !crate::oidc::ALGORITHMS.contains(&algorithm.as_str()) {
+                    problems.push(format!(
+                        "oidc.providers[{index}].algorithms: `{algorithm}` is not supported, expected one of {}",
+                        crate::oidc::ALGORITHMS.join(", ")
+                    ));
+                } else if algorithm.starts_with("HS") && provider.client_secret.is_empty() {
+                    problems.push(format!(
+                        "oidc.providers[{index}].algorithms: `{algorithm}` needs a client_secret"
+                    ));
+                }
+            }
+            for (field, url) in [
+                ("issuer", Some(&provider.issuer)),
+                ("redirect_url", Some(&provider.redirect_url)),
+                (
+                    "authorization_endpoint",
+                    provider.authorization_endpoint.as_ref(),
+                ),
+                ("token_endpoint", provider.token_endpoint.as_ref()),
+                ("jwks_uri", provider.jwks_uri.as_ref()),
+            ] {
+                if let Some(url) = url
+                    && reqwest::Url::parse(url).is_err()
+                {
+                    problems.push(format!(
+                        "oidc.providers[{index}].{field}: `{url}` is not a valid URL"
+                    ));
+                }
+            }
+        }
+        let backends = &self.authentication.backends;
+        if backends.is_empty() {
+            problems.push(String::from(
+                "authentication.backends: at least one backend is required",
+            ));
+        }
+        for (index, backend) in backends.iter().enumerate() {
+            if backends[..index].contains(backend) {
+                problems.push(format!(
+                    "authentication.backends[{index}]: `{backend:?}` is repeated"
+                ));
+            }
+        }
+        if backends.contains(&Backend::Ldap) {
+            match reqwest::Url::parse(&self.ldap.url) {
+                Ok(url) if matches!(url.scheme(), "ldap" | "ldaps" | "ldapi") => {}
+                _ => problems.push(format!(
+                    "ldap.url: `{}` is not an ldap://, ldaps:// or ldapi:// URL",
+                    self.ldap.url
+                )),
+            }
+            if self.ldap.base_dn.is_empty() {
+                problems.push(String::from("ldap.base_dn: is required"));
+            }
+            if !self.ldap.user_filter.contains("{identifier}") {
+                problems.push(String::from(
+                    "ldap.user_filter: must contain `{identifier}`",
+                ));
+            }
+            if self.ldap.timeout == 0 {
+                problems.push(String::from("ldap.timeout: must be greater than 0"));
+            }
+            if self.ldap.attributes.username.is_empty() {
+                problems.push(String::from("ldap.attributes.username: is required"));
+            }
+        }
+        if self.magic_link.ttl <= 0 {
+            problems.push(String::from("magic_link.ttl: must be greater than 0"));
+        }
+        if self.magic_link.limit_window == 0 {
+            problems.push(String::from(
+                "magic_link.limit_window: must be greater than 0",
+            ));
+        }
+        if self.magic_link.enabled
+            && self.is_production()
+            && self.mail.transport == MailTransport::Log
+        {
+            problems.push(String::from(
+                "mail.transport: `log` writes login links to the log, use `smtp` in production",
+            ));
+        }
+        if self.magic_link.enabled {
+            let link_url = &self.magic_link.link_url;
+            if !link_url.contains("{token}")
+                || reqwest::Url::parse(&link_url.replace("{token}", "token")).is_err()
+            {
+                problems.push(format!(
+                    "magic_link.link_url: `{link_url}` must be a URL containing `{{token}}`"
+                ));
+            }
+        }
+        if self.device.ttl <= 0 {
+            problems.push(String::from("device.ttl: must be greater than 0"));
+        }
+        if self.device.interval <= 0 {
+            problems.push(String::from("device.interval: must be greater than 0"));
+        }
+        if reqwest::Url::parse(&self.device.verification_url).is_err() {
+            problems.push(format!(
+                "device.verification_url: `{}` is not a URL",
+                self.device.verification_url
+            ));
+        }
+        if self.mail.from.parse::<lettre::message::Mailbox>().is_err() {
+            problems.push(format!(
+                "mail.from: `{}` is not a valid sender",
+                self.mail.from
+            ));
+        }
+        if self.mail.transport == MailTransport::Smtp && self.mail.smtp_host.is_empty() {
+            problems.push(String::from("mail.smtp_host: is required for SMTP"));
+        }
+        if self.logging.format != LOG_FORMAT_JSON && self.logging.format != LOG_FORMAT_TEXT {
+            problems.push(format!(
+                "{}: expected `{LOG_FORMAT_JSON}` or `{LOG_FORMAT_TEXT}`, got `{}`",
+                keys::LOG_FORMAT,
+                self.logging.format
+            ));
+        }
+        if self.is_production() && self.cors.allowed_origins.is_empty() {
+            problems.push(format!(
+                "{}: at least one origin is required in production",
+                keys::ALLOWED_ORIGINS
+            ));
+        }
+        for origin in &self.cors.allowed_origins {
+            if origin.parse::<axum::http::HeaderValue>().is_err() {
+                problems.push(format!(
+                    "{}: `{origin}` is not a valid origin",
+                    keys::ALLOWED_ORIGINS
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(std::io::Error::other(format!(
+                "Invalid configuration:\n  {}",
+                problems.join("\n  ")
+            )))
+        }
+    }
+
+    pub fn is_production(&self) -> bool {
+        self.environment == PRODUCTION
+    }
+
+    /// Address the listener binds to
+    pub fn get_full(&self) -> String {
+        format!("{}:{}", self.server.address, self.server.port)
+    }
+}
+
+/// Parses `ENABLE_REGISTRATION`: `TRUE`, `FALSE`, `INVITE` or `MODERATED`
+fn parse_registration(value: &str) -> Result<RegistrationMode, String> {
+    match value.to_uppercase().as_str() {
+        "TRUE" => Ok(RegistrationMode::Open),
+        "FALSE" => Ok(RegistrationMode::Closed),
+        "INVITE" => Ok(RegistrationMode::Invite),
+        "MODERATED" => Ok(RegistrationMode::Moderated),
+        _ => Err(format!(
+            "expected TRUE, FALSE, INVITE or MODERATED, got `{value}`"
+        )),
+    }
+}
+
+/// Parses the `TRUE`/`FALSE` toggles used in the env files
+fn parse_toggle(value: &str) -> Result<bool, String> {
+    match value.to_uppercase().as_str() {
+        "TRUE" => Ok(true),
+        "FALSE" => Ok(false),
+        _ => Err(format!("expected TRUE or FALSE, got `{value}`")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_is_valid() {
+        let config = Config::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.get_full(), "0.0.0.0:8001");
+        assert!(!config.registration.enabled);
+    }
+
+    #[test]
+    fn test_toml_file() {
+        let contents = r#"
+            environment = "production"
+
+            [server]
+            port = 9000
+
+            [token]
+            app_ttl = 600
+            sliding_expiration = false
+            max_session = 86400
+
+            [cors]
+            allowed_origins = ["https://soaricarus.com"]
+        "#;
+
+        let config = Config::from_toml(contents).unwrap();
+        assert!(config.validate().is_ok());
+        assert!(config.is_production());
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.server.address, "0.0.0.0");
+        assert_eq!(config.token.app_ttl, 600);
+        assert_eq!(config.token.service_ttl, 3600);
+        assert!(!config.token.sliding_expiration);
+        assert_eq!(config.token.max_session, Some(86400));
+    }
+
+    #[test]
+    fn test_oidc_providers() {
+        let contents = r#"
+            [[oidc.providers]]
+            name = "keycloak"
+            issuer = "https://keycloak.example.com/realms/icarus"
+            client_id = "icarus"
+            client_secret = "s3cret"
+            redirect_url = "https://auth.example.com/api/v2/oidc/keycloak/callback"
+            scopes = ["openid", "email", "profile"]
+        "#;
+
+        let config = Config::from_toml(contents).unwrap();
+        assert!(config.validate().is_ok());
+        let provider = config.oidc.provider("keycloak").unwrap();
+        assert_eq!(provider.client_id, "icarus");
+        assert!(!format!("{provider:?}").contains("s3cret"));
+        assert_eq!(provider.algorithms().collect::<Vec<_>>(), vec!["RS256"]);
+
+        let mut config = config.clone();
+        config.oidc.providers.push(config.oidc.providers[0].clone());
+        config.oidc.providers[1].issuer = String::from("not a url");
+        config.oidc.providers[1].client_secret = String::new();
+        config.oidc.providers[1].algorithms = vec![
+            String::from("ES256"),
+            String::from("HS256"),
+            String::from("none"),
+        ];
+        assert_eq!(
+            config.oidc.providers[1].algorithms().collect::<Vec<_>>(),
+            vec!["ES256", "HS256", "none"]
+        );
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("is repeated"), "{message}");
+        assert!(message.contains("oidc.providers[1].issuer"), "{message}");
+        assert!(
+            message.contains("`HS256` needs a client_secret"),
+            "{message}"
+        );
+        assert!(message.contains("`none` is not supported"), "{message}");
+        assert!(!message.contains("`ES256`"), "{message}");
+    }
+
+    #[test]
+    fn test_ldap_backend() {
+        let contents = r#"
+            [authentication]
+            backends = ["ldap", "local"]
+
+            [ldap]
+            url = "ldaps://ldap.example.com"
+            bind_dn = "cn=icarus,ou=services,dc=example,dc=com"
+            bind_password = "s3cret"
+            base_dn = "ou=people,dc=example,dc=com"
+
+            [ldap.attributes]
+            username = "sAMAccountName"
+        "#;
+
+        let config = Config::from_toml(contents).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.authentication.backends,
+            vec![Backend::Ldap, Backend::Local]
+        );
+        assert_eq!(config.ldap.attributes.username, "sAMAccountName");
+        assert_eq!(config.ldap.attributes.email, "mail");
+        assert!(!format!("{:?}", config.ldap).contains("s3cret"));
+
+        let mut config = config.clone();
+        config.authentication.backends.push(Backend::Ldap);
+        config.ldap.url = String::from("https://ldap.example.com");
+        config.ldap.user_filter = String::from("(uid=bob)");
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("is repeated"), "{message}");
+        assert!(message.contains("ldap.url"), "{message}");
+        assert!(message.contains("ldap.user_filter"), "{message}");
+
+        config.authentication.backends.clear();
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("authentication.backends"), "{message}");
+    }
+
+    #[test]
+    fn test_magic_link() {
+        let contents = r#"
+            [magic_link]
+            enabled = true
+            link_url = "https://soaricarus.com/login/magic?token={token}"
+
+            [mail]
+            transport = "smtp"
+            from = "Icarus <no-reply@soaricarus.com>"
+            smtp_host = "smtp.example.com"
+            smtp_tls = "tls"
+            smtp_port = 465
+            smtp_username = "icarus"
+            smtp_password = "s3cret"
+        "#;
+
+        let config = Config::from_toml(contents).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.magic_link.ttl, 900);
+        assert_eq!(config.mail.smtp_tls, SmtpTls::Tls);
+        assert!(!format!("{:?}", config.mail).contains("s3cret"));
+
+        let mut config = config.clone();
+        config.magic_link.link_url = String::from("https://soaricarus.com/login/magic");
+        config.mail.from = String::from("not an address");
+        config.mail.smtp_host = String::new();
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("magic_link.link_url"), "{message}");
+        assert!(message.contains("mail.from"), "{message}");
+        assert!(message.contains("mail.smtp_host"), "{message}");
+
+        // Login links are only logged in development
+        let mut config = Config::default();
+        config.magic_link.enabled = true;
+        assert_eq!(config.mail.transport, MailTransport::Log);
+        assert!(config.validate().is_ok());
+        config.environment = String::from(PRODUCTION);
+        config.cors.allowed_origins = vec![String::from("https://soaricarus.com")];
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("mail.transport"), "{message}");
+        config.magic_link.enabled = false;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_delegated_scopes() {
+        let contents = r#"
+            [token.delegated_scopes]
+            player = ["songs:read", "playlists:read"]
+        "#;
+
+        let config = Config::from_toml(contents).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            config.token.delegated_scopes["player"],
+            vec!["songs:read", "playlists:read"]
+        );
+        assert!(Config::default().token.delegated_scopes.is_empty());
+
+        let mut config = config.clone();
+        config.token.delegated_scopes.insert(
+            String::from("uploader"),
+            vec![String::from("songs:read songs:write")],
+        );
+        let message = config.validate().unwrap_err().to_string();
+        assert!(
+            message.contains("token.delegated_scopes.uploader"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn test_device() {
+        let contents = r#"
+            [device]
+            interval = 10
+            verification_url = "https://soaricarus.com/device"
+        "#;
+
+        let config = Config::from_toml(contents).unwrap();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.device.ttl, 600);
+        assert_eq!(config.device.interval, 10);
+
+        let mut config = config.clone();
+        config.device.interval = 0;
+        config.device.verification_url = String::from("/device");
+        let message = config.validate().unwrap_err().to_string();
+        assert!(message.contains("device.interval"), "{message}");
+        assert!(message.contains("device.verification_url"), "{message}");
+    }
+
+    #[test]
+    fn test_unknown_field_rejected() {
+        let contents = r#"
+            [server]
+            prot = 9000
+        "#;
+        assert!(Config::from_toml(contents).is_err());
+    }
+
+    #[test]
+    fn test_args_override_defaults() {
+        let args = Args {
+            port: Some(9100),
+            pre_stop_delay: Some(0),
+            enable_registration: Some(RegistrationMode::Open),
+            allowed_origins: Some(vec![String::from(" https://a.com "), String::new()]),
+            ..Default::default()
+        };
+
+        let config = Config::from_args(args).unwrap();
+        assert_eq!(config.server.port, 9100);
+        assert_eq!(config.server.pre_stop_delay, 0);
+        assert!(config.registration.enabled);
+        assert_eq!(config.cors.allowed_origins, vec!["https://a.com"]);
+    }
+
+    #[test]
+    fn test_invalid_values_reported() {
+        let args = Args {
+            environment: Some(String::from(PRODUCTION)),
+            address: Some(String::from("not-an-ip")),
+            max_connections: Some(0),
+            app_token_ttl: Some(-1),
+            hash_parallelism: Some(0),
+            ..Default::default()
+        };
+
+        let message = Config::from_args(args).unwrap_err().to_string();
+        assert!(message.contains(keys::ADDRESS), "{message}");
+        assert!(message.contains(keys::MAX_CONNECTIONS), "{message}");
+        assert!(message.contains(keys::APP_TOKEN_TTL), "{message}");
+        assert!(message.contains(keys::ALLOWED_ORIGINS), "{message}");
+        assert!(message.contains(keys::HASH_PARALLELISM), "{message}");
+    }
+
+    #[test]
+    fn test_parse_toggle() {
+        assert_eq!(parse_toggle("TRUE"), Ok(true));
+        assert_eq!(parse_toggle("false"), Ok(false));
+        assert!(parse_toggle("maybe").is_err());
+    }
+
+    #[test]
+    fn test_registration_mode() {
+        let args = Args {
+            enable_registration: Some(parse_registration("invite").unwrap()),
+            ..Default::default()
+        };
+
+        let config = Config::from_args(args).unwrap();
+        assert!(config.registration.enabled);
+        assert!(config.registration.invite_only);
+        assert!(!config.registration.moderated);
+        assert_eq!(parse_registration("TRUE"), Ok(RegistrationMode::Open));
+        assert_eq!(
+            parse_registration("moderated"),
+            Ok(RegistrationMode::Moderated)
+        );
+        assert!(parse_registration("maybe").is_err());
+    }
 }
@@ -1,20 +1,178 @@
 use sqlx::postgres::PgPoolOptions;
 
-pub async fn create_pool() -> Result<sqlx::PgPool, sqlx::Error> {
+/// Migrations embedded from the ./migrations folder relative to Cargo.toml
+pub static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+
+pub async fn create_pool(settings: &crate::config::Database) -> Result<sqlx::PgPool, sqlx::Error> {
     let database_url = icarus_envy::environment::get_db_url().await.value;
-    println!("Database url: {database_url}");
+    tracing::info!(
+        database_url = %crate::telemetry::logging::redact_url(&database_url),
+        "Connecting to database"
+    );
 
     PgPoolOptions::new()
-        .max_connections(super::connection_settings::MAXCONN)
+        .max_connections(settings.max_connections)
+        .acquire_timeout(std::time::Duration::from_secs(settings.acquire_timeout))
         .connect(&database_url)
         .await
 }
 
 pub async fn migrations(pool: &sqlx::PgPool) {
-    // Run migrations using the sqlx::migrate! macro
-    // Assumes your migrations are in a ./migrations folder relative to Cargo.toml
-    sqlx::migrate!("./migrations")
-        .run(pool)
-        .await
-        .expect("Failed to run migrations");
+    MIGRATOR.run(pool).await.expect("Failed to run migrations");
+    if let Err(err) = normalize_identities(pool).await {
+        panic!("Failed to normalize usernames and emails: {err}");
+    }
+}
+
+/// Unique indexes on the folded username and email, created once the rows are folded
+const IDENTITY_INDEXES: [(&str, &str, &str); 2] = [
+    (
+        "user_username_normalized_key",
+        "username_normalized",
+        r#"CREATE UNIQUE INDEX IF NOT EXISTS user_username_normalized_key ON "user" (username_normalized)"#,
+    ),
+    (
+        "user_email_normalized_key",
+        "email_normalized",
+        r#"CREATE UNIQUE INDEX IF NOT EXISTS user_email_normalized_key ON "user" (email_normalized) WHERE email_normalized <> ''"#,
+    ),
+];
+
+#[derive(Debug)]
+pub enum IdentityError {
+    Db(sqlx::Error),
+    /// Groups of users that share a folded value of the column
+    Collisions {
+        column: &'static str,
+        groups: usize,
+    },
+}
+
+impl std::fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IdentityError::Db(err) => write!(f, "{err}"),
+            IdentityError::Collisions { column, groups } => write!(
+                f,
+                "{groups} groups of users share a {column}, so its unique index cannot be \
+                created. Their ids are logged; change or remove all but one user of each group \
+                and start again"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IdentityError {}
+
+impl From<sqlx::Error> for IdentityError {
+    fn from(err: sqlx::Error) -> Self {
+        IdentityError::Db(err)
+    }
+}
+
+/// Folds usernames and emails with [`crate::validation::fold`], which the SQL backfill
+/// can only approximate, then adds the unique indexes on them. Registration relies on
+/// those indexes, so users that collide are logged by id and fail startup until they
+/// are resolved
+pub async fn normalize_identities(pool: &sqlx::PgPool) -> Result<(), IdentityError> {
+    let existing = existing_identity_indexes(pool).await?;
+    if existing.len() == IDENTITY_INDEXES.len() {
+        return Ok(());
+    }
+
+    let rows: Vec<(uuid::Uuid, String, String, String, String)> = sqlx::query_as(
+        r#"
+        SELECT id, username, email, username_normalized, email_normalized FROM "user"
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut tx = pool.begin().await?;
+    for (id, username, email, username_normalized, email_normalized) in rows {
+        let username_folded = crate::validation::fold(&username);
+        let email_folded = crate::validation::fold(&email);
+        if username_folded == username_normalized && email_folded == email_normalized {
+            continue;
+        }
+        sqlx::query(
+            r#"
+            UPDATE "user" SET username_normalized = $2, email_normalized = $3 WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .bind(username_folded)
+        .bind(email_folded)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+
+    let mut failure = None;
+    for (name, column, create) in IDENTITY_INDEXES {
+        if existing.iter().any(|index| index == name) {
+            continue;
+        }
+
+        let collisions: Vec<Vec<uuid::Uuid>> = sqlx::query_scalar(&format!(
+            r#"
+            SELECT array_agg(id ORDER BY id) FROM "user"
+            WHERE {column} <> '' GROUP BY {column} HAVING COUNT(*) > 1
+            "#
+        ))
+        .fetch_all(pool)
+        .await?;
+        if collisions.is_empty() {
+            sqlx::query(create).execute(pool).await?;
+            tracing::info!(index = name, "Created unique index");
+            continue;
+        }
+
+        // Only ids are logged, the colliding usernames and emails stay out of the logs
+        for user_ids in &collisions {
+            tracing::error!(column, user_ids = ?user_ids, "Users collide once folded");
+        }
+        failure.get_or_insert(IdentityError::Collisions {
+            column,
+            groups: collisions.len(),
+        });
+    }
+
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Whether both unique indexes on the folded username and email exist. Without them
+/// concurrent registrations of the same user could both succeed
+pub async fn identity_indexes_exist(pool: &sqlx::PgPool) -> Result<bool, sqlx::Error> {
+    Ok(existing_identity_indexes(pool).await?.len() == IDENTITY_INDEXES.len())
+}
+
+async fn existing_identity_indexes(pool: &sqlx::PgPool) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT indexname::TEXT FROM pg_indexes WHERE tablename = 'user' AND indexname = ANY($1)
+        "#,
+    )
+    .bind(IDENTITY_INDEXES.map(|(name, _, _)| name).to_vec())
+    .fetch_all(pool)
+    .await
+}
+
+/// Version of the newest migration shipped with the binary
+pub fn latest_migration_version() -> Option<i64> {
+    MIGRATOR.iter().map(|migration| migration.version).max()
+}
+
+/// Version of the newest migration successfully applied to the database
+pub async fn applied_migration_version(pool: &sqlx::PgPool) -> Result<Option<i64>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT MAX(version) FROM _sqlx_migrations WHERE success
+        "#,
+    )
+    .fetch_one(pool)
+    .await
 }
@@ -1,5 +1 @@
 pub mod init;
-
-mod connection_settings {
-    pub const MAXCONN: u32 = 5;
-}
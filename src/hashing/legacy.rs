@@ -0,0 +1,36 @@
+//! Verification of hashes imported from older deployments. These are never
+//! produced here; a successful login replaces them with an Argon2id hash.
+
+use argon2::password_hash::{Error, PasswordHash, PasswordVerifier};
+
+/// Prefixes of the modular crypt format used by bcrypt, which predates PHC strings
+const BCRYPT_PREFIXES: [&str; 4] = ["$2a$", "$2b$", "$2x$", "$2y$"];
+
+pub fn is_bcrypt(stored_hash: &str) -> bool {
+    BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| stored_hash.starts_with(prefix))
+}
+
+pub fn verify_bcrypt(password: &[u8], stored_hash: &str) -> Result<bool, Error> {
+    bcrypt::verify(password, stored_hash).map_err(|err| {
+        tracing::warn!(error = %err, "Invalid bcrypt hash");
+        Error::PhcStringField
+    })
+}
+
+pub fn verify_pbkdf2(password: &[u8], parsed_hash: &PasswordHash) -> Result<bool, Error> {
+    matches(pbkdf2::Pbkdf2.verify_password(password, parsed_hash))
+}
+
+pub fn verify_scrypt(password: &[u8], parsed_hash: &PasswordHash) -> Result<bool, Error> {
+    matches(scrypt::Scrypt.verify_password(password, parsed_hash))
+}
+
+fn matches(result: Result<(), Error>) -> Result<bool, Error> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(Error::Password) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
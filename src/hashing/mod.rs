@@ -1,6 +1,15 @@
+pub mod legacy;
+pub mod workers;
+
 use argon2::{
+    ARGON2ID_IDENT,
+    Algorithm,
     Argon2, // The Argon2 algorithm struct
+    KeyId,
+    Params,
+    ParamsBuilder,
     PasswordVerifier,
+    Version,
     password_hash::{
         PasswordHasher,
         SaltString,
@@ -18,51 +27,173 @@ pub fn get_salt(s: &str) -> Result<SaltString, argon2::password_hash::Error> {
     SaltString::from_b64(s)
 }
 
+/// Families of stored password hashes that can be verified
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheme {
+    Argon2,
+    Bcrypt,
+    Pbkdf2,
+    Scrypt,
+}
+
+/// Identifies the scheme of a stored hash from its PHC identifier, or its `$2?$`
+/// prefix for bcrypt. `None` when the hash cannot be verified
+pub fn scheme(stored_hash: &str) -> Option<Scheme> {
+    if legacy::is_bcrypt(stored_hash) {
+        return Some(Scheme::Bcrypt);
+    }
+
+    let parsed_hash = argon2::PasswordHash::new(stored_hash).ok()?;
+    match parsed_hash.algorithm.as_str() {
+        "argon2id" | "argon2i" | "argon2d" => Some(Scheme::Argon2),
+        "pbkdf2" | "pbkdf2-sha256" | "pbkdf2-sha512" => Some(Scheme::Pbkdf2),
+        "scrypt" => Some(Scheme::Scrypt),
+        _ => None,
+    }
+}
+
+/// Key id recorded in hashes computed with the pepper, so hashes created before a
+/// pepper was configured keep verifying
+pub const PEPPER_KEY_ID: &[u8] = b"pepper";
+
+/// Argon2id parameters configured for new hashes
+fn params(settings: &crate::config::Hashing) -> Result<Params, argon2::Error> {
+    let mut builder = ParamsBuilder::new();
+    builder
+        .m_cost(settings.memory_cost)
+        .t_cost(settings.time_cost)
+        .p_cost(settings.parallelism);
+    if settings.pepper.is_some() {
+        builder.keyid(KeyId::new(PEPPER_KEY_ID)?);
+    }
+    builder.build()
+}
+
+fn hasher<'a>(
+    pepper: Option<&'a String>,
+    params: Params,
+) -> Result<Argon2<'a>, argon2::password_hash::Error> {
+    match pepper {
+        Some(pepper) => Ok(Argon2::new_with_secret(
+            pepper.as_bytes(),
+            Algorithm::Argon2id,
+            Version::V0x13,
+            params,
+        )?),
+        None => Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)),
+    }
+}
+
 pub fn hash_password(
     password: &String,
     salt: &SaltString,
+    settings: &crate::config::Hashing,
 ) -> Result<String, argon2::password_hash::Error> {
     let password_bytes = password.as_bytes();
-
-    // Create an Argon2 instance with default parameters (recommended)
-    // You could customize parameters here if needed, but defaults are strong
-    let argon2 = Argon2::default();
+    let argon2 = hasher(settings.pepper.as_ref(), params(settings)?)?;
 
     // Hash the password with the salt
     // The output is a PasswordHash string format that includes algorithm, version,
     // parameters, salt, and the hash itself.
-    Ok(argon2.hash_password(password_bytes, salt)?.to_string())
+    let start = std::time::Instant::now();
+    let hashed = argon2.hash_password(password_bytes, salt)?.to_string();
+    crate::telemetry::hash_duration("hash", start.elapsed());
+
+    Ok(hashed)
 }
 
 pub fn verify_password(
     password_attempt: &String,
     stored_hash: String,
+    settings: &crate::config::Hashing,
 ) -> Result<bool, argon2::password_hash::Error> {
     let password_bytes = password_attempt.as_bytes();
+    let scheme = scheme(&stored_hash).ok_or(argon2::password_hash::Error::Algorithm)?;
+    if scheme != Scheme::Argon2 {
+        let start = std::time::Instant::now();
+        let result = verify_legacy(scheme, password_bytes, &stored_hash);
+        crate::telemetry::hash_duration("verify", start.elapsed());
+        return result;
+    }
 
     // Parse the stored hash string
     // This extracts the salt, parameters, and hash digest
     let parsed_hash = argon2::PasswordHash::new(stored_hash.as_str())?;
 
-    // Create an Argon2 instance (it will use the parameters from the parsed hash)
+    // Only hashes tagged with the pepper key id were computed with the pepper
+    let pepper = if is_peppered(&parsed_hash) {
+        match &settings.pepper {
+            Some(pepper) => Some(pepper),
+            None => return Err(argon2::password_hash::Error::Crypto),
+        }
+    } else {
+        None
+    };
+
     // Verify the password against the parsed hash
     // This automatically uses the correct salt and parameters embedded in `parsed_hash`
-    match Argon2::default().verify_password(password_bytes, &parsed_hash) {
+    let start = std::time::Instant::now();
+    let result = hasher(pepper, Params::default())?.verify_password(password_bytes, &parsed_hash);
+    crate::telemetry::hash_duration("verify", start.elapsed());
+
+    match result {
         Ok(()) => Ok(true),                                       // Passwords match
         Err(argon2::password_hash::Error::Password) => Ok(false), // Passwords don't match
         Err(e) => Err(e), // Some other error occurred (e.g., invalid hash format)
     }
 }
 
+fn verify_legacy(
+    scheme: Scheme,
+    password: &[u8],
+    stored_hash: &str,
+) -> Result<bool, argon2::password_hash::Error> {
+    match scheme {
+        Scheme::Bcrypt => legacy::verify_bcrypt(password, stored_hash),
+        Scheme::Pbkdf2 => legacy::verify_pbkdf2(password, &argon2::PasswordHash::new(stored_hash)?),
+        Scheme::Scrypt => legacy::verify_scrypt(password, &argon2::PasswordHash::new(stored_hash)?),
+        Scheme::Argon2 => Err(argon2::password_hash::Error::Algorithm),
+    }
+}
+
+/// Whether a stored hash was computed with different parameters than the configured
+/// ones and should be replaced the next time the plain password is known
+pub fn needs_rehash(
+    stored_hash: &str,
+    settings: &crate::config::Hashing,
+) -> Result<bool, argon2::password_hash::Error> {
+    match scheme(stored_hash) {
+        Some(Scheme::Argon2) => {}
+        Some(_) => return Ok(true),
+        None => return Err(argon2::password_hash::Error::Algorithm),
+    }
+
+    let parsed_hash = argon2::PasswordHash::new(stored_hash)?;
+    let stored = Params::try_from(&parsed_hash)?;
+    let current = params(settings)?;
+
+    Ok(parsed_hash.algorithm != ARGON2ID_IDENT
+        || parsed_hash.version != Some(Version::V0x13.into())
+        || stored.m_cost() != current.m_cost()
+        || stored.t_cost() != current.t_cost()
+        || stored.p_cost() != current.p_cost()
+        || is_peppered(&parsed_hash) != settings.pepper.is_some())
+}
+
+fn is_peppered(parsed_hash: &argon2::PasswordHash) -> bool {
+    parsed_hash.params.get("keyid").is_some()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_hash_password() {
+        let settings = crate::config::Hashing::default();
         let some_password = String::from("somethingrandom");
-        match hash_password(&some_password, &generate_salt().unwrap()) {
-            Ok(p) => match verify_password(&some_password, p.clone()) {
+        match hash_password(&some_password, &generate_salt().unwrap(), &settings) {
+            Ok(p) => match verify_password(&some_password, p.clone(), &settings) {
                 Ok(res) => {
                     assert_eq!(res, true);
                 }
@@ -78,10 +209,11 @@ mod tests {
 
     #[test]
     fn test_wrong_password() {
+        let settings = crate::config::Hashing::default();
         let some_password = String::from("somethingrandom");
-        match hash_password(&some_password, &generate_salt().unwrap()) {
+        match hash_password(&some_password, &generate_salt().unwrap(), &settings) {
             Ok(p) => {
-                match verify_password(&some_password, p.clone()) {
+                match verify_password(&some_password, p.clone(), &settings) {
                     Ok(res) => {
                         assert_eq!(res, true, "Passwords are not verified");
                     }
@@ -90,7 +222,7 @@ mod tests {
                     }
                 }
                 let wrong_password = String::from("Differentanotherlevel");
-                let result = verify_password(&wrong_password, p.clone()).unwrap();
+                let result = verify_password(&wrong_password, p.clone(), &settings).unwrap();
                 assert_eq!(false, result, "Passwords should not match");
             }
             Err(err) => {
@@ -98,4 +230,119 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_needs_rehash() {
+        let password = String::from("somethingrandom");
+        let settings = crate::config::Hashing::default();
+        let hashed = hash_password(&password, &generate_salt().unwrap(), &settings).unwrap();
+        assert!(!needs_rehash(&hashed, &settings).unwrap());
+
+        let stronger = crate::config::Hashing {
+            time_cost: settings.time_cost + 1,
+            ..Default::default()
+        };
+        assert!(needs_rehash(&hashed, &stronger).unwrap());
+
+        // Hashes made with the old parameters keep verifying
+        assert!(verify_password(&password, hashed.clone(), &stronger).unwrap());
+        let rehashed = hash_password(&password, &generate_salt().unwrap(), &stronger).unwrap();
+        assert!(!needs_rehash(&rehashed, &stronger).unwrap());
+    }
+
+    #[test]
+    fn test_pepper() {
+        let password = String::from("somethingrandom");
+        let plain = crate::config::Hashing::default();
+        let peppered = crate::config::Hashing {
+            pepper: Some(String::from("server-side secret")),
+            ..Default::default()
+        };
+
+        let hashed = hash_password(&password, &generate_salt().unwrap(), &peppered).unwrap();
+        assert!(verify_password(&password, hashed.clone(), &peppered).unwrap());
+        assert!(verify_password(&password, hashed.clone(), &plain).is_err());
+
+        let other_pepper = crate::config::Hashing {
+            pepper: Some(String::from("another secret")),
+            ..Default::default()
+        };
+        assert!(!verify_password(&password, hashed.clone(), &other_pepper).unwrap());
+
+        // Existing hashes without the pepper still verify and are flagged for rehashing
+        let unpeppered = hash_password(&password, &generate_salt().unwrap(), &plain).unwrap();
+        assert!(verify_password(&password, unpeppered.clone(), &peppered).unwrap());
+        assert!(needs_rehash(&unpeppered, &peppered).unwrap());
+        assert!(needs_rehash(&hashed, &plain).unwrap());
+    }
+
+    #[test]
+    fn test_legacy_hashes() {
+        use argon2::password_hash::PasswordHasher;
+
+        let password = String::from("somethingrandom");
+        let wrong_password = String::from("Differentanotherlevel");
+        let settings = crate::config::Hashing::default();
+        let salt = generate_salt().unwrap();
+
+        let bcrypt_hash = bcrypt::hash(&password, 4).unwrap();
+        let pbkdf2_hash = pbkdf2::Pbkdf2
+            .hash_password_customized(
+                password.as_bytes(),
+                Some(pbkdf2::Algorithm::Pbkdf2Sha256.ident()),
+                None,
+                pbkdf2::Params {
+                    rounds: 1000,
+                    output_length: 32,
+                },
+                &salt,
+            )
+            .unwrap()
+            .to_string();
+        let pbkdf2_sha1_hash = pbkdf2::Pbkdf2
+            .hash_password_customized(
+                password.as_bytes(),
+                Some(pbkdf2::Algorithm::Pbkdf2Sha1.ident()),
+                None,
+                pbkdf2::Params {
+                    rounds: 1000,
+                    output_length: 20,
+                },
+                &salt,
+            )
+            .unwrap()
+            .to_string();
+        assert!(pbkdf2_sha1_hash.starts_with("$pbkdf2$"));
+        let scrypt_hash = scrypt::Scrypt
+            .hash_password_customized(
+                password.as_bytes(),
+                None,
+                None,
+                scrypt::Params::new(4, 8, 1, 32).unwrap(),
+                &salt,
+            )
+            .unwrap()
+            .to_string();
+
+        for (hash, expected) in [
+            (&bcrypt_hash, Scheme::Bcrypt),
+            (&pbkdf2_hash, Scheme::Pbkdf2),
+            (&pbkdf2_sha1_hash, Scheme::Pbkdf2),
+            (&scrypt_hash, Scheme::Scrypt),
+        ] {
+            assert_eq!(scheme(hash), Some(expected), "{hash}");
+            assert!(verify_password(&password, hash.clone(), &settings).unwrap());
+            assert!(!verify_password(&wrong_password, hash.clone(), &settings).unwrap());
+            assert!(needs_rehash(hash, &settings).unwrap(), "{hash}");
+        }
+    }
+
+    #[test]
+    fn test_unknown_scheme() {
+        let settings = crate::config::Hashing::default();
+        assert_eq!(scheme("$md5$abc"), None);
+        assert_eq!(scheme("plaintext"), None);
+        assert!(verify_password(&String::from("x"), String::from("plaintext"), &settings).is_err());
+        assert!(needs_rehash("plaintext", &settings).is_err());
+    }
 }
@@ -0,0 +1,220 @@
+//! Runs Argon2 on the blocking thread pool so hashing never stalls the async
+//! executor. A semaphore bounds how many hashes run at once; callers that cannot
+//! get a slot within the queue timeout are turned away instead of piling up.
+
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Every slot stayed busy for the whole queue timeout
+    Saturated,
+    Hash(argon2::password_hash::Error),
+    Task(tokio::task::JoinError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Saturated => write!(f, "Too many concurrent password hashes"),
+            Error::Hash(err) => write!(f, "{err}"),
+            Error::Task(err) => write!(f, "Hashing task failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub struct Hasher {
+    settings: Arc<crate::config::Hashing>,
+    slots: Arc<tokio::sync::Semaphore>,
+    queue_timeout: std::time::Duration,
+    /// Hash of a random password with the current settings, verified against when
+    /// there is no real hash so the caller takes as long either way
+    dummy: Arc<String>,
+    /// Password verifications run so far, real and dummy
+    verifications: Arc<std::sync::atomic::AtomicU64>,
+}
+
+impl Hasher {
+    /// Computes the dummy hash up front, so the first unknown user costs no more
+    /// than the ones after it
+    pub fn new(settings: &crate::config::Hashing) -> Self {
+        let salt = super::generate_salt().expect("Could not generate a salt");
+        let dummy = super::hash_password(&salt.to_string(), &salt, settings)
+            .expect("Hashing settings are validated with the config");
+
+        Hasher {
+            settings: Arc::new(settings.clone()),
+            slots: Arc::new(tokio::sync::Semaphore::new(settings.max_concurrent)),
+            queue_timeout: std::time::Duration::from_secs(settings.queue_timeout),
+            dummy: Arc::new(dummy),
+            verifications: Arc::new(std::sync::atomic::AtomicU64::new(0)),
+        }
+    }
+
+    pub async fn hash(
+        &self,
+        password: String,
+        salt: argon2::password_hash::SaltString,
+    ) -> Result<String, Error> {
+        let settings = self.settings.clone();
+        self.run(move || super::hash_password(&password, &salt, &settings))
+            .await
+    }
+
+    pub async fn verify(&self, password: String, stored_hash: String) -> Result<bool, Error> {
+        let settings = self.settings.clone();
+        let verifications = self.verifications.clone();
+        self.run(move || {
+            verifications.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            super::verify_password(&password, stored_hash, &settings)
+        })
+        .await
+    }
+
+    /// Verifies the password against a dummy hash. Always `false`, but costs the same
+    /// as a real verification
+    pub async fn verify_dummy(&self, password: String) -> Result<bool, Error> {
+        let settings = self.settings.clone();
+        let dummy = self.dummy.clone();
+        let verifications = self.verifications.clone();
+        self.run(move || {
+            verifications.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            super::verify_password(&password, String::from(dummy.as_str()), &settings)?;
+            Ok(false)
+        })
+        .await
+    }
+
+    /// Number of password verifications run, dummy ones included
+    pub fn verifications(&self) -> u64 {
+        self.verifications
+            .load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Number of hashes that can start right now
+    pub fn available(&self) -> usize {
+        self.slots.available_permits()
+    }
+
+    async fn run<T, F>(&self, work: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, argon2::password_hash::Error> + Send + 'static,
+    {
+        let permit = match tokio::time::timeout(
+            self.queue_timeout,
+            self.slots.clone().acquire_owned(),
+        )
+        .await
+        {
+            Ok(Ok(permit)) => permit,
+            // The semaphore is never closed, so only the timeout lands here
+            _ => {
+                crate::telemetry::hash_rejected();
+                return Err(Error::Saturated);
+            }
+        };
+
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            work()
+        })
+        .await
+        .map_err(Error::Task)?
+        .map_err(Error::Hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(max_concurrent: usize, queue_timeout: u64) -> crate::config::Hashing {
+        crate::config::Hashing {
+            max_concurrent,
+            queue_timeout,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_and_verify() {
+        let hasher = Hasher::new(&settings(2, 5));
+        let password = String::from("somethingrandom");
+
+        let hashed = hasher
+            .hash(password.clone(), super::super::generate_salt().unwrap())
+            .await
+            .unwrap();
+        assert!(hasher.verify(password, hashed.clone()).await.unwrap());
+        assert!(
+            !hasher
+                .verify(String::from("Differentanotherlevel"), hashed)
+                .await
+                .unwrap()
+        );
+        assert_eq!(hasher.available(), 2);
+        assert!(!hasher.verify_dummy(String::from("anything")).await.unwrap());
+        assert_eq!(hasher.verifications(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_saturated() {
+        let hasher = Arc::new(Hasher::new(&settings(1, 0)));
+        let password = String::from("somethingrandom");
+        let hashed = hasher
+            .hash(password.clone(), super::super::generate_salt().unwrap())
+            .await
+            .unwrap();
+
+        // Hold the only slot so the next caller cannot get one
+        let held = hasher.slots.clone().acquire_owned().await.unwrap();
+        match hasher.verify(password.clone(), hashed.clone()).await {
+            Err(Error::Saturated) => {}
+            other => panic!("Expected saturation, got {other:?}"),
+        }
+
+        drop(held);
+        assert!(hasher.verify(password, hashed).await.unwrap());
+    }
+
+    /// Throughput of concurrent logins. Run with
+    /// `cargo test --release bench_concurrent_verify -- --ignored --nocapture`. The
+    /// README records a measurement and how it sets the `max_concurrent` default
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn bench_concurrent_verify() {
+        const LOGINS: usize = 64;
+
+        let password = String::from("somethingrandom");
+        let defaults = crate::config::Hashing::default();
+        let hashed = super::super::hash_password(
+            &password,
+            &super::super::generate_salt().unwrap(),
+            &defaults,
+        )
+        .unwrap();
+
+        let hasher = Arc::new(Hasher::new(&settings(defaults.max_concurrent, 60)));
+        let start = std::time::Instant::now();
+        let tasks: Vec<_> = (0..LOGINS)
+            .map(|_| {
+                let hasher = hasher.clone();
+                let password = password.clone();
+                let hashed = hashed.clone();
+                tokio::spawn(async move { hasher.verify(password, hashed).await })
+            })
+            .collect();
+        for task in tasks {
+            assert!(task.await.unwrap().unwrap());
+        }
+        let elapsed = start.elapsed();
+
+        println!(
+            "{LOGINS} concurrent logins with {} slots in {elapsed:?} ({:.1} logins/s)",
+            defaults.max_concurrent,
+            LOGINS as f64 / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Outgoing email. The transport is picked in the configuration; `log` only writes
+//! messages to the log so links can be followed in development without a mail server.
+
+/// Future returned by [`Mailer::send`]
+pub type BoxFuture<'a, T> = std::pin::Pin<Box<dyn std::future::Future<Output = T> + Send + 'a>>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub to: String,
+    pub subject: String,
+    /// Plain text
+    pub body: String,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The sender or recipient is not a valid address
+    Address(String),
+    Transport(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Address(err) => write!(f, "Invalid address: {err}"),
+            Error::Transport(err) => write!(f, "Could not send email: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub trait Mailer: Send + Sync {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+/// Mailer for the configured transport. A transport that cannot be set up is logged
+/// and every message sent through it fails
+pub fn from_config(settings: &crate::config::Mail) -> std::sync::Arc<dyn Mailer> {
+    match settings.transport {
+        crate::config::MailTransport::Log => std::sync::Arc::new(Log),
+        crate::config::MailTransport::Smtp => match Smtp::new(settings) {
+            Ok(smtp) => std::sync::Arc::new(smtp),
+            Err(err) => {
+                tracing::error!(error = %err, "Could not set up SMTP, email is disabled");
+                std::sync::Arc::new(Unavailable(err.to_string()))
+            }
+        },
+    }
+}
+
+/// Writes messages to the log instead of sending them
+pub struct Log;
+
+impl Mailer for Log {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            tracing::info!(
+                to = message.to,
+                subject = message.subject,
+                body = message.body,
+                "Email not sent, mail transport is `log`"
+            );
+            Ok(())
+        })
+    }
+}
+
+struct Unavailable(String);
+
+impl Mailer for Unavailable {
+    fn send<'a>(&'a self, _message: &'a Message) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move { Err(Error::Transport(self.0.clone())) })
+    }
+}
+
+pub struct Smtp {
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+    from: lettre::message::Mailbox,
+}
+
+impl Smtp {
+    pub fn new(settings: &crate::config::Mail) -> Result<Self, Error> {
+        use lettre::transport::smtp::client::{Tls, TlsParameters};
+
+        let from = settings
+            .from
+            .parse()
+            .map_err(|err: lettre::address::AddressError| Error::Address(err.to_string()))?;
+        let tls = match settings.smtp_tls {
+            crate::config::SmtpTls::Plain => Tls::None,
+            crate::config::SmtpTls::Starttls | crate::config::SmtpTls::Tls => {
+                let parameters = TlsParameters::new(settings.smtp_host.clone())
+                    .map_err(|err| Error::Transport(err.to_string()))?;
+                if settings.smtp_tls == crate::config::SmtpTls::Tls {
+                    Tls::Wrapper(parameters)
+                } else {
+                    Tls::Required(parameters)
+                }
+            }
+        };
+
+        let mut builder = lettre::AsyncSmtpTransport::<lettre::Tokio1Executor>::builder_dangerous(
+            settings.smtp_host.clone(),
+        )
+        .port(settings.smtp_port)
+        .tls(tls)
+        .timeout(Some(std::time::Duration::from_secs(settings.smtp_timeout)));
+        if !settings.smtp_username.is_empty() {
+            builder =
+                builder.credentials(lettre::transport::smtp::authentication::Credentials::new(
+                    settings.smtp_username.clone(),
+                    settings.smtp_password.clone(),
+                ));
+        }
+
+        Ok(Smtp {
+            transport: builder.build(),
+            from,
+        })
+    }
+}
+
+impl Mailer for Smtp {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<(), Error>> {
+        use lettre::AsyncTransport;
+
+        Box::pin(async move {
+            let to = message
+                .to
+                .parse()
+                .map_err(|err: lettre::address::AddressError| Error::Address(err.to_string()))?;
+            let email = lettre::Message::builder()
+                .from(self.from.clone())
+                .to(to)
+                .subject(&message.subject)
+                .header(lettre::message::header::ContentType::TEXT_PLAIN)
+                .body(message.body.clone())
+                .map_err(|err| Error::Transport(err.to_string()))?;
+
+            self.transport
+                .send(email)
+                .await
+                .map_err(|err| Error::Transport(err.to_string()))?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Keeps sent messages for tests to read
+#[cfg(test)]
+pub struct Outbox(tokio::sync::mpsc::UnboundedSender<Message>);
+
+#[cfg(test)]
+impl Outbox {
+    pub fn new() -> (Self, tokio::sync::mpsc::UnboundedReceiver<Message>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        (Outbox(sender), receiver)
+    }
+}
+
+#[cfg(test)]
+impl Mailer for Outbox {
+    fn send<'a>(&'a self, message: &'a Message) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let _ = self.0.send(message.clone());
+            Ok(())
+        })
+    }
+}
@@ -1,34 +1,154 @@
+pub mod authentication;
 pub mod callers;
+pub mod cli;
 pub mod config;
 pub mod db;
 pub mod hashing;
+pub mod mail;
+pub mod notify;
+pub mod oidc;
+pub mod password_policy;
 pub mod repo;
+pub mod state;
+pub mod telemetry;
+pub mod throttle;
 pub mod token_stuff;
+pub mod validation;
 
 #[tokio::main]
 async fn main() {
+    let cli = cli::Cli::load();
+    let config = match config::Config::from_args(cli.args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    match cli.command.unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve => serve(config).await,
+        command => {
+            if let Err(err) = cli::run(command, config).await {
+                eprintln!("{err}");
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+async fn serve(config: config::Config) {
     // initialize tracing
-    tracing_subscriber::fmt::init();
+    let tracer_provider = match telemetry::logging::init(&config.logging) {
+        Ok(provider) => provider,
+        Err(err) => {
+            eprintln!("Could not initialize tracing: {err}");
+            std::process::exit(1);
+        }
+    };
+
+    let url = config.get_full();
+    let state = init::state(config).await;
+    let app = init::app(state.clone()).await;
+
+    // run our app with hyper, listening on the configured address
+    let listener = tokio::net::TcpListener::bind(&url).await.unwrap();
+    tracing::info!(address = %url, "Listening");
+
+    let draining = std::sync::Arc::new(tokio::sync::Notify::new());
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown::signal(state.clone(), draining.clone()));
+    let drain_timeout = std::time::Duration::from_secs(state.config.server.drain_timeout);
+
+    tokio::select! {
+        result = server => {
+            if let Err(err) = result {
+                tracing::error!(error = %err, "Server error");
+            }
+        }
+        _ = async {
+            draining.notified().await;
+            tokio::time::sleep(drain_timeout).await;
+        } => {
+            tracing::warn!("Drain timeout elapsed, dropping remaining connections");
+        }
+    }
+
+    state.pool.close().await;
+    tracing::info!("Shutdown complete");
 
-    let app = init::app().await;
+    if let Some(provider) = tracer_provider
+        && let Err(err) = provider.shutdown()
+    {
+        eprintln!("Could not flush traces: {err}");
+    }
+}
+
+mod shutdown {
+    /// Resolves on SIGINT or SIGTERM once the service has reported not ready for the
+    /// pre-stop delay, so load balancers stop routing to it before it stops accepting
+    pub async fn signal(
+        state: crate::state::AppState,
+        draining: std::sync::Arc<tokio::sync::Notify>,
+    ) {
+        let ctrl_c = async {
+            tokio::signal::ctrl_c()
+                .await
+                .expect("Failed to listen for SIGINT");
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("Failed to listen for SIGTERM")
+                .recv()
+                .await;
+        };
+
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate => {},
+        }
+
+        let delay = std::time::Duration::from_secs(state.config.server.pre_stop_delay);
+        tracing::info!(
+            pre_stop_delay = delay.as_secs(),
+            "Shutdown signal received, reporting not ready"
+        );
+        state.start_draining();
+        tokio::time::sleep(delay).await;
 
-    // run our app with hyper, listening globally on port 8001
-    let url = config::get_full();
-    let listener = tokio::net::TcpListener::bind(url).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+        tracing::info!("Draining connections");
+        draining.notify_one();
+    }
 }
 
 mod init {
     use axum::{
         Router,
-        routing::{get, post},
+        routing::{delete, get, post},
     };
     use utoipa::OpenApi;
 
     use super::callers;
+    use callers::approval as approval_callers;
     use callers::common as common_callers;
+    use callers::device as device_callers;
+    use callers::health as health_callers;
+    use callers::import as import_callers;
+    use callers::invitation as invitation_callers;
     use callers::login as login_caller;
+    use callers::magic_link as magic_link_callers;
+    use callers::oidc as oidc_callers;
+    use callers::password as password_callers;
     use callers::register as register_caller;
+    use callers::token_exchange as token_exchange_callers;
     use login_caller::endpoint as login_endpoints;
     use login_caller::response as login_responses;
     use register_caller::response as register_responses;
@@ -36,13 +156,31 @@ mod init {
     #[derive(utoipa::OpenApi)]
     #[openapi(
         paths(
-            common_callers::endpoint::db_ping, common_callers::endpoint::root,
+            common_callers::endpoint::db_ping, common_callers::endpoint::root, common_callers::endpoint::metrics,
             register_caller::register_user,
-            login_endpoints::login, login_endpoints::service_login, login_endpoints::refresh_token
+            login_endpoints::login, login_endpoints::service_login, login_endpoints::refresh_token,
+            token_exchange_callers::endpoint::exchange,
+            magic_link_callers::endpoint::request_link, magic_link_callers::endpoint::consume,
+            oidc_callers::endpoint::authorize, oidc_callers::endpoint::callback,
+            device_callers::endpoint::code, device_callers::endpoint::approve, device_callers::endpoint::token,
+            health_callers::endpoint::healthz, health_callers::endpoint::readyz, health_callers::endpoint::report,
+            import_callers::endpoint::import,
+            password_callers::endpoint::change_password,
+            invitation_callers::endpoint::create, invitation_callers::endpoint::list, invitation_callers::endpoint::revoke,
+            approval_callers::endpoint::list, approval_callers::endpoint::approve, approval_callers::endpoint::reject
             ),
         components(schemas(common_callers::response::TestResult,
-                register_responses::Response,
-            login_responses::Response, login_responses::service_login::Response, login_responses::refresh_token::Response)),
+                register_responses::Response, crate::validation::FieldError,
+            login_responses::Response, login_responses::service_login::Response, login_responses::refresh_token::Response,
+            magic_link_callers::request::Request, magic_link_callers::request::consume::Request, magic_link_callers::response::Response,
+            device_callers::request::Request, device_callers::request::token::Request, device_callers::request::approve::Request,
+            device_callers::response::Response, device_callers::response::Error, device_callers::response::token::Response, device_callers::response::approve::Response,
+            token_exchange_callers::request::Request, token_exchange_callers::response::Response, token_exchange_callers::response::Error,
+            health_callers::response::Check, health_callers::response::readiness::Response, health_callers::response::report::Response,
+            import_callers::request::Request, import_callers::response::Response,
+            password_callers::request::Request, password_callers::response::Response,
+            invitation_callers::request::Request, invitation_callers::response::Response, invitation_callers::response::list::Response,
+            crate::repo::invitation::Invitation, approval_callers::response::Response)),
         tags(
             (name = "Icarus Auth API", description = "Auth API for Icarus API")
             )
@@ -50,7 +188,7 @@ mod init {
     struct ApiDoc;
 
     mod cors {
-        pub async fn configure_cors() -> tower_http::cors::CorsLayer {
+        pub fn configure_cors(config: &crate::config::Config) -> tower_http::cors::CorsLayer {
             // Start building the CORS layer with common settings
             let cors = tower_http::cors::CorsLayer::new()
                 .allow_methods([
@@ -64,40 +202,35 @@ mod init {
                     axum::http::header::AUTHORIZATION,
                 ]) // Specify allowed headers:cite[2]
                 .allow_credentials(true) // If you need to send cookies or authentication headers:cite[2]
-                .max_age(std::time::Duration::from_secs(3600)); // Cache the preflight response for 1 hour:cite[2]
+                .max_age(std::time::Duration::from_secs(config.cors.max_age));
 
             // Dynamically set the allowed origin based on the environment
-            match std::env::var(icarus_envy::keys::APP_ENV).as_deref() {
-                Ok("production") => {
-                    let allowed_origins_env = icarus_envy::environment::get_allowed_origins().await;
-                    match icarus_envy::utility::delimitize(&allowed_origins_env) {
-                        Ok(alwd) => {
-                            let allowed_origins: Vec<axum::http::HeaderValue> = alwd
-                                .into_iter()
-                                .map(|s| s.parse::<axum::http::HeaderValue>().unwrap())
-                                .collect();
-                            cors.allow_origin(allowed_origins)
-                        }
-                        Err(err) => {
-                            eprintln!(
-                                "Could not parse out allowed origins from env: Error: {err:?}"
-                            );
-                            std::process::exit(-1);
-                        }
-                    }
-                }
-                _ => {
-                    // Development (default): Allow localhost origins
-                    cors.allow_origin(vec![
-                        "http://localhost:4200".parse().unwrap(),
-                        "http://127.0.0.1:4200".parse().unwrap(),
-                    ])
-                }
+            if config.is_production() {
+                // Origins are validated when the configuration is loaded
+                let allowed_origins: Vec<axum::http::HeaderValue> = config
+                    .cors
+                    .allowed_origins
+                    .iter()
+                    .map(|s| s.parse::<axum::http::HeaderValue>().unwrap())
+                    .collect();
+                cors.allow_origin(allowed_origins)
+            } else {
+                // Development (default): Allow localhost origins
+                cors.allow_origin(vec![
+                    "http://localhost:4200".parse().unwrap(),
+                    "http://127.0.0.1:4200".parse().unwrap(),
+                ])
             }
         }
     }
 
-    pub async fn routes() -> Router {
+    pub async fn routes(state: crate::state::AppState) -> Router {
+        let cors = cors::configure_cors(&state.config);
+        let timeout = std::time::Duration::from_secs(state.config.server.request_timeout);
+
+        // Install the recorder before any metric is emitted
+        crate::telemetry::handle();
+
         // build our application with a route
         Router::new()
             .route(
@@ -124,23 +257,122 @@ mod init {
                 callers::endpoints::REFRESH_TOKEN,
                 post(callers::login::endpoint::refresh_token),
             )
-            .layer(cors::configure_cors().await)
+            .route(
+                callers::endpoints::TOKEN_EXCHANGE,
+                post(callers::token_exchange::endpoint::exchange),
+            )
+            .route(
+                callers::endpoints::MAGIC_LINK,
+                post(callers::magic_link::endpoint::request_link),
+            )
+            .route(
+                callers::endpoints::MAGIC_LINK_CONSUME,
+                post(callers::magic_link::endpoint::consume),
+            )
+            .route(
+                callers::endpoints::OIDC_AUTHORIZE,
+                get(callers::oidc::endpoint::authorize),
+            )
+            .route(
+                callers::endpoints::OIDC_CALLBACK,
+                get(callers::oidc::endpoint::callback),
+            )
+            .route(
+                callers::endpoints::DEVICE_CODE,
+                post(callers::device::endpoint::code),
+            )
+            .route(
+                callers::endpoints::DEVICE_TOKEN,
+                post(callers::device::endpoint::token),
+            )
+            .route(
+                callers::endpoints::DEVICE_APPROVE,
+                post(callers::device::endpoint::approve),
+            )
+            .route(
+                callers::endpoints::METRICS,
+                get(callers::common::endpoint::metrics),
+            )
+            .route(
+                callers::endpoints::HEALTHZ,
+                get(callers::health::endpoint::healthz),
+            )
+            .route(
+                callers::endpoints::READYZ,
+                get(callers::health::endpoint::readyz),
+            )
+            .route(
+                callers::endpoints::HEALTH_REPORT,
+                get(callers::health::endpoint::report),
+            )
+            .route(
+                callers::endpoints::CHANGE_PASSWORD,
+                post(callers::password::endpoint::change_password),
+            )
+            .route(
+                callers::endpoints::INVITATIONS,
+                post(callers::invitation::endpoint::create)
+                    .get(callers::invitation::endpoint::list),
+            )
+            .route(
+                callers::endpoints::INVITATION,
+                delete(callers::invitation::endpoint::revoke),
+            )
+            .route(
+                callers::endpoints::PENDING_REGISTRATIONS,
+                get(callers::approval::endpoint::list),
+            )
+            .route(
+                callers::endpoints::APPROVE_REGISTRATION,
+                post(callers::approval::endpoint::approve),
+            )
+            .route(
+                callers::endpoints::REJECT_REGISTRATION,
+                post(callers::approval::endpoint::reject),
+            )
+            .route(
+                callers::endpoints::IMPORT_USERS,
+                post(callers::import::endpoint::import),
+            )
+            .layer(tower_http::timeout::TimeoutLayer::new(timeout))
+            .route_layer(axum::middleware::from_fn(crate::telemetry::track_latency))
+            .layer(
+                tower::ServiceBuilder::new()
+                    .layer(tower_http::request_id::SetRequestIdLayer::x_request_id(
+                        tower_http::request_id::MakeRequestUuid,
+                    ))
+                    .layer(
+                        tower_http::trace::TraceLayer::new_for_http()
+                            .make_span_with(crate::telemetry::logging::make_span),
+                    )
+                    .layer(tower_http::request_id::PropagateRequestIdLayer::x_request_id()),
+            )
+            .layer(cors)
+            .with_state(state)
     }
 
-    pub async fn app() -> Router {
-        let pool = super::db::init::create_pool()
+    pub async fn state(config: crate::config::Config) -> crate::state::AppState {
+        let pool = super::db::init::create_pool(&config.database)
             .await
             .expect("Failed to create pool");
 
+        let keys = crate::state::Keys::load()
+            .await
+            .expect("Failed to load signing keys");
+
+        let password_policy = crate::password_policy::Policy::load(&config.password)
+            .expect("Failed to load password policy");
+
         super::db::init::migrations(&pool).await;
 
-        routes()
-            .await
-            .merge(
-                utoipa_swagger_ui::SwaggerUi::new("/swagger-ui")
-                    .url("/api-docs/openapi.json", ApiDoc::openapi()),
-            )
-            .layer(axum::Extension(pool))
+        crate::state::AppState::new(pool, keys, config).with_password_policy(password_policy)
+    }
+
+    pub async fn app(state: crate::state::AppState) -> Router {
+        routes(state).await.merge(
+            utoipa_swagger_ui::SwaggerUi::new("/swagger-ui")
+                .url("/api-docs/openapi.json", ApiDoc::openapi()),
+        )
     }
 }
 
@@ -220,14 +452,31 @@ mod tests {
         }
     }
 
+    const TEST_SECRET_KEY: &str = "refero34o8rfhfjn983thf39fhc943rf923n3h";
+
+    fn get_test_state(pool: sqlx::PgPool) -> state::AppState {
+        let keys = state::Keys {
+            secret: String::from(TEST_SECRET_KEY),
+        };
+        state::AppState::new(pool, keys, get_test_config())
+    }
+
+    /// Default config with registration open, which most tests start by using
+    fn get_test_config() -> config::Config {
+        let mut config = config::Config::default();
+        config.registration.enabled = true;
+        config
+    }
+
     fn get_test_register_request() -> callers::register::request::Request {
         callers::register::request::Request {
             username: String::from("somethingsss"),
             password: String::from("Raindown!"),
             email: String::from("dev@null.com"),
-            phone: String::from("1234567890"),
+            phone: String::from("+12025550123"),
             firstname: String::from("Bob"),
             lastname: String::from("Smith"),
+            invitation: String::new(),
         }
     }
 
@@ -239,6 +488,7 @@ mod tests {
             "phone": &usr.phone,
             "firstname": &usr.firstname,
             "lastname": &usr.lastname,
+            "invitation": &usr.invitation,
         })
     }
 
@@ -259,11 +509,30 @@ mod tests {
 
             app.clone().oneshot(req).await
         }
+
+        pub async fn login(
+            app: &axum::Router,
+            username: &str,
+            password: &str,
+        ) -> Result<axum::response::Response, std::convert::Infallible> {
+            let payload = serde_json::json!({
+                "username": username,
+                "password": password,
+            });
+            let req = axum::http::Request::builder()
+                .method(axum::http::Method::POST)
+                .uri(crate::callers::endpoints::LOGIN)
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .body(axum::body::Body::from(payload.to_string()))
+                .unwrap();
+
+            app.clone().oneshot(req).await
+        }
     }
 
     #[tokio::test]
     async fn test_hello_world() {
-        let app = init::app().await;
+        let app = init::app(init::state(config::Config::default()).await).await;
 
         // `Router` implements `tower::Service<Request<Body>>` so we can
         // call it like any tower service, no need to run an HTTP server.
@@ -302,7 +571,7 @@ mod tests {
 
         db::init::migrations(&pool).await;
 
-        let app = init::routes().await.layer(axum::Extension(pool));
+        let app = init::routes(get_test_state(pool)).await;
 
         let usr = get_test_register_request();
 
@@ -359,7 +628,7 @@ mod tests {
 
         db::init::migrations(&pool).await;
 
-        let app = init::routes().await.layer(axum::Extension(pool));
+        let app = init::routes(get_test_state(pool)).await;
 
         let usr = get_test_register_request();
 
@@ -447,7 +716,7 @@ mod tests {
 
         db::init::migrations(&pool).await;
 
-        let app = init::routes().await.layer(axum::Extension(pool));
+        let app = init::routes(get_test_state(pool)).await;
         let passphrase =
             String::from("iUOo1fxshf3y1tUGn1yU8l9raPApHCdinW0VdCHdRFEjqhR3Bf02aZzsKbLtaDFH");
         let payload = serde_json::json!({
@@ -501,11 +770,13 @@ mod tests {
 
         db::init::migrations(&pool).await;
 
-        let app = init::routes().await.layer(axum::Extension(pool));
+        let app = init::routes(get_test_state(pool)).await;
         let id = uuid::Uuid::parse_str("22f9c775-cce9-457a-a147-9dafbb801f61").unwrap();
-        let key = icarus_envy::environment::get_secret_key().await.value;
+        let key = String::from(TEST_SECRET_KEY);
+
+        let policy = config::Token::default();
 
-        match token_stuff::create_service_token(&key, &id) {
+        match token_stuff::create_service_token(&key, &id, &policy) {
             Ok((token, _expire)) => {
                 let payload = serde_json::json!({
                     "access_token": token
@@ -548,4 +819,1789 @@ mod tests {
 
         let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
     }
+
+    #[tokio::test]
+    async fn test_login_rehashes_outdated_password() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let usr = get_test_register_request();
+        let app = init::routes(get_test_state(pool.clone())).await;
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let original = repo::user::get(&pool, &usr.username)
+            .await
+            .unwrap()
+            .password;
+
+        let mut config = get_test_config();
+        config.hashing.time_cost += 1;
+        let hashing = config.hashing.clone();
+        let keys = state::Keys {
+            secret: String::from(TEST_SECRET_KEY),
+        };
+        let app = init::routes(state::AppState::new(pool.clone(), keys, config)).await;
+
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let stored = repo::user::get(&pool, &usr.username)
+            .await
+            .unwrap()
+            .password;
+        assert_ne!(original, stored, "Password was not rehashed");
+        assert!(!hashing::needs_rehash(&stored, &hashing).unwrap());
+
+        // The rehashed password keeps working
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_normalize_identities() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        for (username, email) in [("Straße", "straße@null.com"), ("bob", "bob@null.com")] {
+            let usr = icarus_models::user::User {
+                username: String::from(username),
+                password: String::from("hash"),
+                email: String::from(email),
+                status: String::from(repo::user::status::ACTIVE),
+                ..Default::default()
+            };
+            repo::user::insert(&pool, &usr).await.unwrap();
+        }
+
+        // As left by the SQL backfill, where bob's email only collides once fully folded
+        let index_names = || async {
+            let names: Vec<String> = sqlx::query_scalar(
+                r#"SELECT indexname::TEXT FROM pg_indexes WHERE tablename = 'user' AND indexname LIKE '%_normalized_key' ORDER BY indexname"#,
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            names
+        };
+        for statement in [
+            r#"DROP INDEX user_username_normalized_key"#,
+            r#"DROP INDEX user_email_normalized_key"#,
+            r#"UPDATE "user" SET email = 'STRASSE@null.com' WHERE username = 'bob'"#,
+            r#"UPDATE "user" SET username_normalized = lower(username), email_normalized = lower(email)"#,
+        ] {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+
+        // bob's email now collides, so startup is refused until it is resolved
+        match db::init::normalize_identities(&pool).await {
+            Err(db::init::IdentityError::Collisions { column, groups }) => {
+                assert_eq!((column, groups), ("email_normalized", 1));
+            }
+            other => panic!("Expected a collision, got {other:?}"),
+        }
+        let normalized: (String, String) = sqlx::query_as(
+            r#"SELECT username_normalized, email_normalized FROM "user" WHERE username = 'Straße'"#,
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            normalized,
+            (String::from("strasse"), String::from("strasse@null.com"))
+        );
+        assert_eq!(index_names().await, vec!["user_username_normalized_key"]);
+        assert!(!db::init::identity_indexes_exist(&pool).await.unwrap());
+
+        // Once the collision is resolved, the next run adds the email index
+        sqlx::query(r#"UPDATE "user" SET email = 'bob@null.com' WHERE username = 'bob'"#)
+            .execute(&pool)
+            .await
+            .unwrap();
+        db::init::normalize_identities(&pool).await.unwrap();
+        assert_eq!(
+            index_names().await,
+            vec!["user_email_normalized_key", "user_username_normalized_key"]
+        );
+        assert!(db::init::identity_indexes_exist(&pool).await.unwrap());
+        let login = repo::user::get_by_identifier(&pool, "STRASSE")
+            .await
+            .unwrap();
+        assert_eq!(login.0.username, "Straße");
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_legacy_salt_id_still_readable() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let usr = get_test_register_request();
+        let app = init::routes(get_test_state(pool.clone())).await;
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        // New users still get a salt row, with the salt embedded in their hash, so the
+        // previous release can read them during a rolling deploy or after a rollback
+        let created = repo::user::get(&pool, &usr.username).await.unwrap();
+        assert!(!created.salt_id.is_nil(), "New users should get a salt");
+        let salt: String = sqlx::query_scalar(r#"SELECT salt FROM "salt" WHERE id = $1"#)
+            .bind(created.salt_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        let hash = argon2::PasswordHash::new(&created.password).unwrap();
+        assert_eq!(Some(salt.as_str()), hash.salt.map(|salt| salt.as_str()));
+
+        // Rows without a salt_id, as written once the table is dropped, still read
+        sqlx::query(r#"UPDATE "user" SET salt_id = NULL WHERE id = $1"#)
+            .bind(created.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let unsalted = repo::user::get(&pool, &usr.username).await.unwrap();
+        assert!(unsalted.salt_id.is_nil());
+
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_import_legacy_user_upgrades_on_login() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+        let id = uuid::Uuid::parse_str("22f9c775-cce9-457a-a147-9dafbb801f61").unwrap();
+        repo::service::set_admin(&pool, &id, true).await.unwrap();
+        let (service_token, _) = token_stuff::create_service_token(
+            &String::from(TEST_SECRET_KEY),
+            &id,
+            &config::Token::default(),
+        )
+        .unwrap();
+
+        let password = "Raindown!";
+        let payload = callers::import::request::Request {
+            users: vec![
+                callers::import::request::User {
+                    username: String::from("legacy"),
+                    password_hash: bcrypt::hash(password, 4).unwrap(),
+                    email: String::from("legacy@null.com"),
+                    ..Default::default()
+                },
+                callers::import::request::User {
+                    username: String::from("unsupported"),
+                    password_hash: String::from("plaintext"),
+                    email: String::from("unsupported@null.com"),
+                    ..Default::default()
+                },
+            ],
+        };
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(axum::http::Method::POST)
+                    .uri(callers::endpoints::IMPORT_USERS)
+                    .header(axum::http::header::CONTENT_TYPE, "application/json")
+                    .header(
+                        axum::http::header::AUTHORIZATION,
+                        format!("Bearer {service_token}"),
+                    )
+                    .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, response.status());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed_body: callers::import::response::Response =
+            serde_json::from_slice(&body).unwrap();
+        assert!(parsed_body.data[0].id.is_some());
+        assert!(parsed_body.data[1].id.is_none());
+
+        let resp = requests::login(&app, "legacy", password).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let stored = repo::user::get(&pool, &String::from("legacy"))
+            .await
+            .unwrap()
+            .password;
+        assert_eq!(hashing::scheme(&stored), Some(hashing::Scheme::Argon2));
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_password_policy_on_register_and_change() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+
+        let mut usr = get_test_register_request();
+        usr.password = String::from("password");
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed_body: callers::login::response::Response =
+            serde_json::from_slice(&body).unwrap();
+        let token = parsed_body.data[0].token.clone();
+
+        let change = |current: &str, new: &str| {
+            let payload = callers::password::request::Request {
+                current_password: String::from(current),
+                new_password: String::from(new),
+            };
+            Request::builder()
+                .method(axum::http::Method::POST)
+                .uri(callers::endpoints::CHANGE_PASSWORD)
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::from(serde_json::to_string(&payload).unwrap()))
+                .unwrap()
+        };
+
+        let resp = app
+            .clone()
+            .oneshot(change(&usr.password, "12345678"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let resp = app
+            .clone()
+            .oneshot(change("Wrong-password-1", "Sunny-Afternoon-77"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = app
+            .clone()
+            .oneshot(change(&usr.password, "Sunny-Afternoon-77"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let resp = requests::login(&app, &usr.username, "Sunny-Afternoon-77")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Tokens of a user disabled after logging in stop working
+        let (disabled, _) = repo::user::get_by_identifier(&pool, &usr.username)
+            .await
+            .unwrap();
+        repo::user::update_status(&pool, &disabled.id, repo::user::status::DISABLED)
+            .await
+            .unwrap();
+        let resp = app
+            .clone()
+            .oneshot(change("Sunny-Afternoon-77", "Rainy-Morning-88"))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(callers::endpoints::INVITATIONS)
+                    .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_register_validation_and_normalization() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+
+        let mut usr = get_test_register_request();
+        usr.username = String::from("a b");
+        usr.email = String::from("not-an-email");
+        usr.phone = String::from("555-0123");
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed_body: callers::register::response::Response =
+            serde_json::from_slice(&body).unwrap();
+        let fields: Vec<&str> = parsed_body
+            .errors
+            .iter()
+            .map(|err| err.field.as_str())
+            .collect();
+        assert_eq!(fields, vec!["username", "email", "phone"]);
+
+        let mut usr = get_test_register_request();
+        usr.username = String::from(" ｓｏｍｅｔｈｉｎｇｓｓｓ ");
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(resp.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed_body: callers::register::response::Response =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(parsed_body.data[0].username, "somethingsss");
+
+        // Same username or email in another case is taken
+        let mut usr = get_test_register_request();
+        usr.username = String::from("SomethingSSS");
+        usr.email = String::from("other@null.com");
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let mut usr = get_test_register_request();
+        usr.username = String::from("someoneelse");
+        usr.email = String::from("DEV@Null.com");
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // The unique index holds even when the check is bypassed
+        let duplicate = icarus_models::user::User {
+            username: String::from("SOMETHINGSSS"),
+            password: String::from("hash"),
+            email: String::from("third@null.com"),
+            status: String::from(repo::user::status::ACTIVE),
+            ..Default::default()
+        };
+        let err = repo::user::insert(&pool, &duplicate).await.unwrap_err();
+        assert!(repo::is_unique_violation(&err));
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_registrations() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+
+        let mut registrations = tokio::task::JoinSet::new();
+        for _ in 0..8 {
+            let app = app.clone();
+            registrations.spawn(async move {
+                let usr = get_test_register_request();
+                requests::register(&app, &usr).await.unwrap().status()
+            });
+        }
+        let statuses = registrations.join_all().await;
+
+        let created = statuses
+            .iter()
+            .filter(|status| **status == StatusCode::CREATED)
+            .count();
+        assert_eq!(created, 1, "Statuses: {statuses:?}");
+        assert!(
+            statuses
+                .iter()
+                .all(|status| *status == StatusCode::CREATED || *status == StatusCode::CONFLICT),
+            "Statuses: {statuses:?}"
+        );
+
+        let count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM "user""#)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_invite_only_registration() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let mut config = get_test_config();
+        config.registration.invite_only = true;
+        let keys = state::Keys {
+            secret: String::from(TEST_SECRET_KEY),
+        };
+        let app = init::routes(state::AppState::new(pool.clone(), keys, config)).await;
+
+        let id = uuid::Uuid::parse_str("22f9c775-cce9-457a-a147-9dafbb801f61").unwrap();
+        repo::service::set_admin(&pool, &id, true).await.unwrap();
+        let (service_token, _) = token_stuff::create_service_token(
+            &String::from(TEST_SECRET_KEY),
+            &id,
+            &config::Token::default(),
+        )
+        .unwrap();
+
+        let invite = |token: &str, payload: serde_json::Value| {
+            Request::builder()
+                .method(axum::http::Method::POST)
+                .uri(callers::endpoints::INVITATIONS)
+                .header(axum::http::header::CONTENT_TYPE, "application/json")
+                .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+                .body(Body::from(payload.to_string()))
+                .unwrap()
+        };
+        let code_of = |body: &[u8]| {
+            let parsed_body: callers::invitation::response::Response =
+                serde_json::from_slice(body).unwrap();
+            parsed_body.data[0].code.clone()
+        };
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let resp = app
+            .clone()
+            .oneshot(invite(
+                &service_token,
+                json!({"max_uses": 2, "role": "member"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let code = code_of(&body);
+
+        for (username, email, status) in [
+            ("firstuser", "first@null.com", StatusCode::CREATED),
+            ("seconduser", "second@null.com", StatusCode::CREATED),
+            ("thirduser", "third@null.com", StatusCode::FORBIDDEN),
+        ] {
+            let mut usr = get_test_register_request();
+            usr.username = String::from(username);
+            usr.email = String::from(email);
+            usr.invitation = code.clone();
+            let resp = requests::register(&app, &usr).await.unwrap();
+            assert_eq!(resp.status(), status, "{username}");
+        }
+
+        let role: Option<String> =
+            sqlx::query_scalar(r#"SELECT role FROM "user" WHERE username = 'firstuser'"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(role.as_deref(), Some("member"));
+
+        let resp = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(callers::endpoints::INVITATIONS)
+                    .header(
+                        axum::http::header::AUTHORIZATION,
+                        format!("Bearer {service_token}"),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let parsed_body: callers::invitation::response::list::Response =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(parsed_body.data[0].uses, 2);
+        assert_eq!(parsed_body.data[0].used_by.len(), 2);
+
+        // Users can invite, but only once per code and without a role
+        let resp = requests::login(&app, "firstuser", &usr.password)
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let parsed_body: callers::login::response::Response =
+            serde_json::from_slice(&body).unwrap();
+        let user_token = parsed_body.data[0].token.clone();
+
+        let resp = app
+            .clone()
+            .oneshot(invite(&user_token, json!({"role": "admin"})))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Expiries past the allowed maximum are rejected instead of overflowing
+        let ttl = config::Registration::default().invitation_ttl;
+        let max_ttl = config::Registration::default().max_invitation_ttl;
+        for (token, expires_in) in [
+            (&user_token, ttl + 1),
+            (&user_token, i64::MAX),
+            (&service_token, max_ttl + 1),
+            (&service_token, i64::MAX),
+        ] {
+            let resp = app
+                .clone()
+                .oneshot(invite(token, json!({"expires_in": expires_in})))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{expires_in}");
+        }
+        let resp = app
+            .clone()
+            .oneshot(invite(&service_token, json!({"expires_in": max_ttl})))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let resp = app
+            .clone()
+            .oneshot(invite(&user_token, json!({"email": "friend@null.com"})))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let code = code_of(&body);
+
+        let mut usr = get_test_register_request();
+        usr.username = String::from("friend");
+        usr.email = String::from("stranger@null.com");
+        usr.invitation = code;
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        usr.email = String::from("Friend@null.com");
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_login_by_username_or_email() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        for identifier in [
+            "somethingsss",
+            "SomethingSSS",
+            "dev@null.com",
+            " DEV@Null.com ",
+        ] {
+            let resp = requests::login(&app, identifier, &usr.password)
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK, "{identifier}");
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let parsed_body: callers::login::response::Response =
+                serde_json::from_slice(&body).unwrap();
+            assert_eq!(parsed_body.data[0].username, usr.username);
+        }
+
+        let resp = requests::login(&app, "dev@null.com", "Wrong-password-1")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // The email can also be sent under its own name
+        let payload = json!({"email": "dev@null.com", "password": &usr.password});
+        let resp = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(axum::http::Method::POST)
+                    .uri(callers::endpoints::LOGIN)
+                    .header(axum::http::header::CONTENT_TYPE, "application/json")
+                    .body(Body::from(payload.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let metrics = telemetry::handle().render();
+        assert!(metrics.contains(r#"identifier="email""#), "{metrics}");
+
+        // Users without an email are not found by an empty identifier
+        let no_email = icarus_models::user::User {
+            username: String::from("noemail"),
+            password: String::from("hash"),
+            status: String::from(repo::user::status::ACTIVE),
+            ..Default::default()
+        };
+        repo::user::insert(&pool, &no_email).await.unwrap();
+        for identifier in ["", " "] {
+            let err = repo::user::get_by_identifier(&pool, identifier)
+                .await
+                .unwrap_err();
+            assert!(matches!(err, sqlx::Error::RowNotFound), "{identifier:?}");
+        }
+        let (found, _) = repo::user::get_by_identifier(&pool, "noemail")
+            .await
+            .unwrap();
+        assert_eq!(found.username, "noemail");
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_login_does_not_reveal_users() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let state = get_test_state(pool.clone());
+        let hasher = state.hasher.clone();
+        let app = init::routes(state).await;
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // An unknown user still costs exactly one password verification, against the
+        // dummy hash, so it takes as long as a known user with a wrong password
+        let mut responses = Vec::new();
+        for username in [usr.username.as_str(), "nobodyhere", "nobody@null.com"] {
+            let before = hasher.verifications();
+            let resp = requests::login(&app, username, "Wrong-password-1")
+                .await
+                .unwrap();
+            assert_eq!(hasher.verifications() - before, 1, "{username}");
+            let status = resp.status();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            responses.push((status, body));
+        }
+        assert_eq!(responses[0].0, StatusCode::NOT_FOUND);
+        assert!(
+            responses.iter().all(|response| *response == responses[0]),
+            "{responses:?}"
+        );
+
+        // A database that cannot be reached is a server error, not an unknown user
+        pool.close().await;
+        let before = hasher.verifications();
+        let resp = requests::login(&app, "nobodyhere", "Wrong-password-1")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(hasher.verifications(), before);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_register_does_not_reveal_which_field_is_taken() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let mut same_username = get_test_register_request();
+        same_username.email = String::from("other@null.com");
+        let mut same_email = get_test_register_request();
+        same_email.username = String::from("someoneelse");
+
+        let mut responses = Vec::new();
+        for usr in [&same_username, &same_email, &usr] {
+            let resp = requests::register(&app, usr).await.unwrap();
+            let status = resp.status();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            responses.push((status, body));
+        }
+        assert_eq!(responses[0].0, StatusCode::CONFLICT);
+        assert!(
+            responses.iter().all(|response| *response == responses[0]),
+            "{responses:?}"
+        );
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    /// Local server recording what is posted to it, standing in for a webhook
+    async fn spawn_webhook() -> (
+        String,
+        tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+    ) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let app = axum::Router::new().route(
+            "/hook",
+            axum::routing::post(move |axum::Json(body): axum::Json<serde_json::Value>| {
+                let sender = sender.clone();
+                async move {
+                    let _ = sender.send(body);
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (format!("http://{address}/hook"), receiver)
+    }
+
+    #[tokio::test]
+    async fn test_moderated_registration() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let (webhook_url, mut notifications) = spawn_webhook().await;
+        let mut config = get_test_config();
+        config.registration.moderated = true;
+        config.registration.webhook_url = Some(webhook_url);
+        let keys = state::Keys {
+            secret: String::from(TEST_SECRET_KEY),
+        };
+        let app = init::routes(state::AppState::new(pool.clone(), keys, config)).await;
+
+        let id = uuid::Uuid::parse_str("22f9c775-cce9-457a-a147-9dafbb801f61").unwrap();
+        repo::service::set_admin(&pool, &id, true).await.unwrap();
+        let (service_token, _) = token_stuff::create_service_token(
+            &String::from(TEST_SECRET_KEY),
+            &id,
+            &config::Token::default(),
+        )
+        .unwrap();
+        let admin = |method: axum::http::Method, uri: String| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header(
+                    axum::http::header::AUTHORIZATION,
+                    format!("Bearer {service_token}"),
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+        let pending = || async {
+            let resp = app
+                .clone()
+                .oneshot(admin(
+                    axum::http::Method::GET,
+                    String::from(callers::endpoints::PENDING_REGISTRATIONS),
+                ))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let parsed_body: callers::approval::response::Response =
+                serde_json::from_slice(&body).unwrap();
+            parsed_body.data
+        };
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification["event"], "registration_pending");
+        assert_eq!(notification["username"], usr.username.as_str());
+
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let parsed_body: callers::login::response::Response =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(parsed_body.message, "Registration is pending approval");
+
+        let users = pending().await;
+        assert_eq!(users.len(), 1);
+        assert!(users[0].password.is_empty());
+        let user_id = users[0].id;
+
+        let resp = app
+            .clone()
+            .oneshot(admin(
+                axum::http::Method::POST,
+                callers::endpoints::APPROVE_REGISTRATION.replace("{id}", &user_id.to_string()),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification["event"], "registration_approved");
+
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut usr = get_test_register_request();
+        usr.username = String::from("rejected");
+        usr.email = String::from("rejected@null.com");
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let rejected_id = pending().await[0].id;
+
+        let reject_uri =
+            callers::endpoints::REJECT_REGISTRATION.replace("{id}", &rejected_id.to_string());
+        let resp = app
+            .clone()
+            .oneshot(admin(axum::http::Method::POST, reject_uri.clone()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = app
+            .clone()
+            .oneshot(admin(axum::http::Method::POST, reject_uri))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert!(pending().await.is_empty());
+
+        // A rejected user can register again
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    /// Goes through the authorization code flow against the mock provider as the
+    /// identity. Returns the callback's status and body, and the state it used
+    async fn oidc_sign_in(
+        app: &axum::Router,
+        provider: &oidc::mock::Provider,
+        mut identity: oidc::mock::Identity,
+    ) -> (StatusCode, callers::login::response::Response, String) {
+        let req = axum::http::Request::builder()
+            .uri(callers::endpoints::OIDC_AUTHORIZE.replace("{provider}", "mock"))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+
+        let location = resp.headers()[axum::http::header::LOCATION]
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert!(location.starts_with(&format!("{}/authorize?", provider.issuer)));
+        assert_eq!(
+            oidc::mock::query_param(&location, "code_challenge_method").unwrap(),
+            "S256"
+        );
+        let login_state = oidc::mock::query_param(&location, "state").unwrap();
+        identity.nonce = oidc::mock::query_param(&location, "nonce").unwrap();
+        provider.sign_in_as(identity);
+
+        let (status, body) = oidc_callback(app, &login_state).await;
+        (status, body, login_state)
+    }
+
+    async fn oidc_callback(
+        app: &axum::Router,
+        login_state: &str,
+    ) -> (StatusCode, callers::login::response::Response) {
+        let uri = format!(
+            "{}?code=mock-code&state={login_state}",
+            callers::endpoints::OIDC_CALLBACK.replace("{provider}", "mock")
+        );
+        let req = axum::http::Request::builder()
+            .uri(uri)
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_oidc_login() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let provider = oidc::mock::Provider::spawn().await;
+        let mut config = get_test_config();
+        config.oidc.providers = vec![config::OidcProvider {
+            name: String::from("mock"),
+            issuer: provider.issuer.clone(),
+            client_id: String::from("icarus"),
+            client_secret: String::from("not-used-for-rs256"),
+            redirect_url: String::from("http://localhost:4200/oidc/callback"),
+            link_by_email: true,
+            ..Default::default()
+        }];
+        let keys = state::Keys {
+            secret: String::from(TEST_SECRET_KEY),
+        };
+        let app = init::routes(state::AppState::new(pool.clone(), keys, config)).await;
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let registered =
+            serde_json::from_slice::<callers::register::response::Response>(&body).unwrap();
+
+        let alice = oidc::mock::Identity {
+            subject: String::from("alice-1"),
+            email: Some(String::from("alice@example.com")),
+            email_verified: true,
+            preferred_username: Some(String::from("alice")),
+            ..Default::default()
+        };
+
+        // First sign-in creates the user
+        let (status, body, login_state) = oidc_sign_in(&app, &provider, alice.clone()).await;
+        assert_eq!(status, StatusCode::OK, "{}", body.message);
+        let created = &body.data[0];
+        assert_eq!(created.username, "alice");
+        assert!(token_stuff::verify_token(
+            &String::from(TEST_SECRET_KEY),
+            &created.token,
+            &config::Token::default()
+        ));
+
+        // The next one signs in to the same user
+        let (status, body, _) = oidc_sign_in(&app, &provider, alice.clone()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.data[0].id, created.id);
+
+        // A state can only be used once
+        let (status, _) = oidc_callback(&app, &login_state).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // A verified email is linked to the existing user
+        let linked = oidc::mock::Identity {
+            subject: String::from("bob-2"),
+            email: Some(usr.email.to_uppercase()),
+            email_verified: true,
+            ..Default::default()
+        };
+        let (status, body, _) = oidc_sign_in(&app, &provider, linked).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body.data[0].id, registered.data[0].id);
+
+        // An unverified one is not
+        let unverified = oidc::mock::Identity {
+            subject: String::from("mallory-3"),
+            email: Some(usr.email.clone()),
+            email_verified: false,
+            ..Default::default()
+        };
+        let (status, _, _) = oidc_sign_in(&app, &provider, unverified).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        // Tokens for another client are rejected
+        let mut other_audience = alice.clone();
+        other_audience.audience = Some(String::from("someone-else"));
+        let (status, _, _) = oidc_sign_in(&app, &provider, other_audience).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let req = axum::http::Request::builder()
+            .uri(callers::endpoints::OIDC_AUTHORIZE.replace("{provider}", "unknown"))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    /// Stand-in for a directory server: knows its users and their passwords, and gives
+    /// them shadow users the way the LDAP backend does
+    struct Directory {
+        pool: sqlx::PgPool,
+        hasher: std::sync::Arc<hashing::workers::Hasher>,
+        users: std::sync::Arc<std::sync::Mutex<Vec<(String, authentication::DirectoryUser)>>>,
+    }
+
+    impl authentication::Backend for Directory {
+        fn name(&self) -> &'static str {
+            "directory"
+        }
+
+        fn authenticate<'a>(
+            &'a self,
+            identifier: &'a str,
+            password: &'a str,
+        ) -> authentication::BoxFuture<'a, Result<authentication::Outcome, authentication::Error>>
+        {
+            Box::pin(async move {
+                let found = self
+                    .users
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .find(|(_, user)| user.username == identifier)
+                    .cloned();
+                let Some((expected, directory_user)) = found else {
+                    return Ok(authentication::Outcome::UnknownUser);
+                };
+                let identifier = repo::user::identifier::USERNAME;
+                if expected != password {
+                    return Ok(authentication::Outcome::InvalidPassword { identifier });
+                }
+
+                let user =
+                    authentication::shadow(&self.pool, &self.hasher, self.name(), &directory_user)
+                        .await?;
+                Ok(authentication::Outcome::Authenticated {
+                    user: Box::new(user),
+                    identifier,
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_directory_login() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let carol = authentication::DirectoryUser {
+            subject: String::from("5f0c4a2e-9a43-4f5e-a0b4-3c6f7ad1e1c2"),
+            username: String::from("carol"),
+            email: String::from("carol@example.com"),
+            firstname: String::from("Carol"),
+            lastname: String::from("Danvers"),
+            phone: String::new(),
+        };
+        let state = get_test_state(pool.clone());
+        let directory_users = std::sync::Arc::new(std::sync::Mutex::new(vec![(
+            String::from("Directory-Pass-1"),
+            carol,
+        )]));
+        let chain = authentication::Chain::new(vec![
+            Box::new(authentication::local::Local::new(
+                pool.clone(),
+                state.hasher.clone(),
+            )),
+            Box::new(Directory {
+                pool: pool.clone(),
+                hasher: state.hasher.clone(),
+                users: directory_users.clone(),
+            }),
+        ]);
+        let app = init::routes(state.with_authentication(chain)).await;
+
+        // Local users still log in
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // The first directory login creates the shadow user
+        let resp = requests::login(&app, "carol", "Directory-Pass-1")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let first = serde_json::from_slice::<callers::login::response::Response>(&body).unwrap();
+        let shadow_id = first.data[0].id;
+        assert_eq!(first.data[0].username, "carol");
+
+        let resp = requests::login(&app, "carol", "Wrong-Pass-1")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Later logins reuse it and pick up directory changes
+        directory_users.lock().unwrap()[0].1.email = String::from("carol.danvers@example.com");
+        let resp = requests::login(&app, "carol", "Directory-Pass-1")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let second = serde_json::from_slice::<callers::login::response::Response>(&body).unwrap();
+        assert_eq!(second.data[0].id, shadow_id);
+        let stored = repo::user::get_by_id(&pool, &shadow_id).await.unwrap();
+        assert_eq!(stored.email, "carol.danvers@example.com");
+        assert_eq!(stored.lastname, "Danvers");
+
+        // A directory user cannot take over a local one
+        directory_users.lock().unwrap().push((
+            String::from("Directory-Pass-2"),
+            authentication::DirectoryUser {
+                subject: String::from("uid=somethingsss,ou=people,dc=example,dc=com"),
+                username: usr.username.clone(),
+                email: String::from("impostor@example.com"),
+                ..Default::default()
+            },
+        ));
+        let resp = requests::login(&app, &usr.username, "Directory-Pass-2")
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    async fn magic_link_request(app: &axum::Router, email: &str) -> (StatusCode, String) {
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri(callers::endpoints::MAGIC_LINK)
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "email": email }).to_string()))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    async fn magic_link_consume(
+        app: &axum::Router,
+        token: &str,
+    ) -> (StatusCode, callers::login::response::Response) {
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri(callers::endpoints::MAGIC_LINK_CONSUME)
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .body(Body::from(json!({ "token": token }).to_string()))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_magic_link_login() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let mut config = get_test_config();
+        config.magic_link.enabled = true;
+        config.magic_link.link_url =
+            String::from("https://soaricarus.com/login/magic?token={token}");
+        let keys = state::Keys {
+            secret: String::from(TEST_SECRET_KEY),
+        };
+        let (outbox, mut sent) = mail::Outbox::new();
+        let state = state::AppState::new(pool.clone(), keys, config)
+            .with_mailer(std::sync::Arc::new(outbox));
+        let app = init::routes(state).await;
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let registered =
+            serde_json::from_slice::<callers::register::response::Response>(&body).unwrap();
+
+        let next_token = |message: mail::Message| {
+            message
+                .body
+                .split("token=")
+                .nth(1)
+                .unwrap()
+                .split_whitespace()
+                .next()
+                .unwrap()
+                .to_string()
+        };
+        let wait = std::time::Duration::from_secs(5);
+
+        // Known and unknown emails get the same answer, only the known one gets mail
+        let (known_status, known_body) = magic_link_request(&app, &usr.email.to_uppercase()).await;
+        let (unknown_status, unknown_body) = magic_link_request(&app, "nobody@example.com").await;
+        assert_eq!(known_status, StatusCode::ACCEPTED);
+        assert_eq!(unknown_status, known_status);
+        assert_eq!(unknown_body, known_body);
+
+        let message = tokio::time::timeout(wait, sent.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(message.to, usr.email);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(500), sent.recv())
+                .await
+                .is_err()
+        );
+
+        // A link logs in once
+        let token = next_token(message);
+        let (status, body) = magic_link_consume(&app, &token).await;
+        assert_eq!(status, StatusCode::OK, "{}", body.message);
+        assert_eq!(body.data[0].id, registered.data[0].id);
+        assert!(token_stuff::verify_token(
+            &String::from(TEST_SECRET_KEY),
+            &body.data[0].token,
+            &config::Token::default()
+        ));
+        let (status, _) = magic_link_consume(&app, &token).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Asking again keeps the earlier link working, and using one ends the others
+        magic_link_request(&app, &usr.email).await;
+        let older = next_token(
+            tokio::time::timeout(wait, sent.recv())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        magic_link_request(&app, &usr.email).await;
+        let newer = next_token(
+            tokio::time::timeout(wait, sent.recv())
+                .await
+                .unwrap()
+                .unwrap(),
+        );
+        let (status, _) = magic_link_consume(&app, &older).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = magic_link_consume(&app, &newer).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // Three links per email and window, in any case
+        let (status, body) = magic_link_request(&app, &usr.email.to_uppercase()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(
+            body.contains(callers::magic_link::TOO_MANY_REQUESTS),
+            "{body}"
+        );
+        let (status, _) = magic_link_request(&app, "nobody@example.com").await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // Expired links do not work
+        let expired = callers::magic_link::generate_token();
+        repo::magic_link::insert(
+            &pool,
+            &callers::invitation::hash_code(&expired),
+            &registered.data[0].id,
+            &(time::OffsetDateTime::now_utc() - time::Duration::seconds(1)),
+        )
+        .await
+        .unwrap();
+        let (status, body) = magic_link_consume(&app, &expired).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body.message, "Login link is invalid or expired");
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    async fn form_post(
+        app: &axum::Router,
+        uri: &str,
+        form: &[(&str, &str)],
+    ) -> (StatusCode, serde_json::Value) {
+        let body = form
+            .iter()
+            .map(|(name, value)| format!("{name}={}", value.replace(':', "%3A")))
+            .collect::<Vec<_>>()
+            .join("&");
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri(uri)
+            .header(
+                axum::http::header::CONTENT_TYPE,
+                "application/x-www-form-urlencoded",
+            )
+            .body(Body::from(body))
+            .unwrap();
+        let resp = app.clone().oneshot(req).await.unwrap();
+        let status = resp.status();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    async fn device_approve(
+        app: &axum::Router,
+        token: &str,
+        user_code: &str,
+        approve: bool,
+    ) -> StatusCode {
+        let req = Request::builder()
+            .method(axum::http::Method::POST)
+            .uri(callers::endpoints::DEVICE_APPROVE)
+            .header(axum::http::header::CONTENT_TYPE, "application/json")
+            .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::from(
+                json!({ "user_code": user_code, "approve": approve }).to_string(),
+            ))
+            .unwrap();
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn test_device_authorization() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let login = serde_json::from_slice::<callers::login::response::Response>(&body).unwrap();
+        let user_token = &login.data[0].token;
+
+        let start = |client_id: &'static str| {
+            let app = app.clone();
+            async move {
+                let (status, body) = form_post(
+                    &app,
+                    callers::endpoints::DEVICE_CODE,
+                    &[("client_id", client_id)],
+                )
+                .await;
+                assert_eq!(status, StatusCode::OK);
+                serde_json::from_value::<callers::device::response::Response>(body).unwrap()
+            }
+        };
+        let poll = |device_code: String, client_id: &'static str| {
+            let app = app.clone();
+            async move {
+                form_post(
+                    &app,
+                    callers::endpoints::DEVICE_TOKEN,
+                    &[
+                        ("grant_type", callers::device::GRANT_TYPE),
+                        ("device_code", &device_code),
+                        ("client_id", client_id),
+                    ],
+                )
+                .await
+            }
+        };
+
+        let codes = start("icarus-tv").await;
+        assert_eq!(codes.interval, 5);
+        assert!(codes.verification_uri_complete.contains(&codes.user_code));
+
+        // Waiting for the user, and told to slow down when polling too fast
+        let (status, body) = poll(codes.device_code.clone(), "icarus-tv").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "authorization_pending");
+        let (_, body) = poll(codes.device_code.clone(), "icarus-tv").await;
+        assert_eq!(body["error"], "slow_down");
+
+        // Another client cannot use the device code
+        let (_, body) = poll(codes.device_code.clone(), "someone-else").await;
+        assert_eq!(body["error"], "invalid_grant");
+
+        // The user approves with the code as typed
+        let typed = codes.user_code.to_lowercase().replace('-', " ");
+        assert_eq!(
+            device_approve(&app, user_token, &typed, true).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            device_approve(&app, user_token, &codes.user_code, true).await,
+            StatusCode::NOT_FOUND
+        );
+
+        let (status, body) = poll(codes.device_code.clone(), "icarus-tv").await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let issued =
+            serde_json::from_value::<callers::device::response::token::Response>(body).unwrap();
+        assert_eq!(issued.token_type, "Bearer");
+        assert_eq!(
+            token_stuff::extract_id_from_token(
+                &String::from(TEST_SECRET_KEY),
+                &issued.access_token,
+                &config::Token::default()
+            )
+            .unwrap(),
+            login.data[0].id
+        );
+
+        // The token is handed out once
+        let (_, body) = poll(codes.device_code.clone(), "icarus-tv").await;
+        assert_eq!(body["error"], "invalid_grant");
+
+        // Denied devices are told so
+        let codes = start("icarus-cli").await;
+        assert_eq!(
+            device_approve(&app, user_token, &codes.user_code, false).await,
+            StatusCode::OK
+        );
+        let (_, body) = poll(codes.device_code.clone(), "icarus-cli").await;
+        assert_eq!(body["error"], "access_denied");
+
+        // Expired codes cannot be approved or polled
+        let device_code = callers::device::generate_device_code();
+        repo::device::insert(
+            &pool,
+            &callers::invitation::hash_code(&device_code),
+            "BCDFGHJK",
+            "icarus-tv",
+            5,
+            &(time::OffsetDateTime::now_utc() - time::Duration::seconds(1)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            device_approve(&app, user_token, "BCDF-GHJK", true).await,
+            StatusCode::NOT_FOUND
+        );
+        let (_, body) = poll(device_code, "icarus-tv").await;
+        assert_eq!(body["error"], "expired_token");
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_token_exchange() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+        let db_name = db_mgr::generate_db_name().await;
+        db_mgr::create_database(&tm_pool, &db_name).await.unwrap();
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+        db::init::migrations(&pool).await;
+
+        let mut config = get_test_config();
+        config
+            .token
+            .delegated_scopes
+            .insert(String::from("service"), vec![String::from("songs:read")]);
+        let keys = state::Keys {
+            secret: String::from(TEST_SECRET_KEY),
+        };
+        let app = init::routes(state::AppState::new(pool.clone(), keys, config)).await;
+        let key = String::from(TEST_SECRET_KEY);
+
+        let usr = get_test_register_request();
+        let resp = requests::register(&app, &usr).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = requests::login(&app, &usr.username, &usr.password)
+            .await
+            .unwrap();
+        let body = resp.into_body().collect().await.unwrap().to_bytes();
+        let login = serde_json::from_slice::<callers::login::response::Response>(&body).unwrap();
+        let user_token = login.data[0].token.clone();
+
+        // Seeded by the passphrase migration
+        let service_id = uuid::Uuid::parse_str("22f9c775-cce9-457a-a147-9dafbb801f61").unwrap();
+        let (service_token, _) =
+            token_stuff::create_service_token(&key, &service_id, &config::Token::default())
+                .unwrap();
+
+        let exchange_scope = |subject_token: String,
+                              actor_token: String,
+                              scope: &'static str,
+                              audience: &'static str| {
+            let app = app.clone();
+            async move {
+                form_post(
+                    &app,
+                    callers::endpoints::TOKEN_EXCHANGE,
+                    &[
+                        ("grant_type", callers::token_exchange::GRANT_TYPE),
+                        ("subject_token", &subject_token),
+                        (
+                            "subject_token_type",
+                            callers::token_exchange::ACCESS_TOKEN_TYPE,
+                        ),
+                        ("actor_token", &actor_token),
+                        ("scope", scope),
+                        ("audience", audience),
+                    ],
+                )
+                .await
+            }
+        };
+        let exchange = |subject_token: String, actor_token: String, audience: &'static str| {
+            exchange_scope(subject_token, actor_token, "songs:read", audience)
+        };
+
+        let (status, body) = exchange(
+            user_token.clone(),
+            service_token.clone(),
+            token_stuff::AUDIENCE,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        let delegated =
+            serde_json::from_value::<callers::token_exchange::response::Response>(body).unwrap();
+        assert_eq!(delegated.scope.as_deref(), Some("songs:read"));
+        assert!(delegated.expires_in > 0 && delegated.expires_in <= 300);
+        let token = delegated.access_token;
+        let claims = token_stuff::decode(&key, &token, &config::Token::default()).unwrap();
+        assert_eq!(claims.token_type, token_stuff::DELEGATED_TOKEN_TYPE);
+        assert_eq!(claims.sub, login.data[0].id);
+        assert_eq!(claims.act, Some(service_id));
+
+        // A delegated token cannot act as the user's own token
+        let resp = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(axum::http::Method::POST)
+                    .uri(callers::endpoints::CHANGE_PASSWORD)
+                    .header(axum::http::header::CONTENT_TYPE, "application/json")
+                    .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+                    .body(Body::from(
+                        json!({ "current_password": usr.password, "new_password": "Raindown-2!" })
+                            .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // Nor be exchanged again
+        let (_, body) = exchange(token, service_token.clone(), token_stuff::AUDIENCE).await;
+        assert_eq!(body["error"], "invalid_grant");
+
+        // Only services can act for users
+        let (status, body) = exchange(
+            user_token.clone(),
+            user_token.clone(),
+            token_stuff::AUDIENCE,
+        )
+        .await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "invalid_client");
+        let (unknown_service, _) = token_stuff::create_service_token(
+            &key,
+            &uuid::Uuid::new_v4(),
+            &config::Token::default(),
+        )
+        .unwrap();
+        let (_, body) = exchange(user_token.clone(), unknown_service, token_stuff::AUDIENCE).await;
+        assert_eq!(body["error"], "invalid_client");
+
+        let (_, body) =
+            exchange(user_token.clone(), service_token.clone(), "another-service").await;
+        assert_eq!(body["error"], "invalid_target");
+
+        // Scopes outside the service's allow-list are refused
+        let (status, body) = exchange_scope(
+            user_token,
+            service_token,
+            "songs:read songs:write",
+            token_stuff::AUDIENCE,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "invalid_scope");
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_readyz() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+
+        let db_name = db_mgr::generate_db_name().await;
+
+        match db_mgr::create_database(&tm_pool, &db_name).await {
+            Ok(_) => {
+                println!("Success");
+            }
+            Err(e) => {
+                panic!("Error: {:?}", e.to_string());
+            }
+        }
+
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+
+        db::init::migrations(&pool).await;
+
+        let state = get_test_state(pool);
+        let app = init::routes(state.clone()).await;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(callers::endpoints::READYZ)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(StatusCode::OK, response.status(), "Status is not right");
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed_body: callers::health::response::readiness::Response =
+            serde_json::from_slice(&body).unwrap();
+        assert!(
+            parsed_body.data.iter().all(|check| check.healthy),
+            "Not every check passed {:?}",
+            parsed_body.data
+        );
+
+        state.start_draining();
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri(callers::endpoints::READYZ)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            response.status(),
+            "Draining service should not be ready"
+        );
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
+
+    #[tokio::test]
+    async fn test_health_report_requires_admin_service_account() {
+        let tm_pool = db_mgr::get_pool().await.unwrap();
+
+        let db_name = db_mgr::generate_db_name().await;
+
+        match db_mgr::create_database(&tm_pool, &db_name).await {
+            Ok(_) => {
+                println!("Success");
+            }
+            Err(e) => {
+                panic!("Error: {:?}", e.to_string());
+            }
+        }
+
+        let pool = db_mgr::connect_to_db(&db_name).await.unwrap();
+
+        db::init::migrations(&pool).await;
+
+        let app = init::routes(get_test_state(pool.clone())).await;
+        let id = uuid::Uuid::parse_str("22f9c775-cce9-457a-a147-9dafbb801f61").unwrap();
+        let key = String::from(TEST_SECRET_KEY);
+        let policy = config::Token::default();
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(callers::endpoints::HEALTH_REPORT)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+
+        let (user_token, _) = token_stuff::create_token(&key, &id, &policy).unwrap();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri(callers::endpoints::HEALTH_REPORT)
+                    .header(
+                        axum::http::header::AUTHORIZATION,
+                        format!("Bearer {user_token}"),
+                    )
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::FORBIDDEN, response.status());
+
+        // Only service accounts flagged as admin
+        let (service_token, _) = token_stuff::create_service_token(&key, &id, &policy).unwrap();
+        let report = |token: String| {
+            let app = app.clone();
+            async move {
+                app.oneshot(
+                    Request::builder()
+                        .uri(callers::endpoints::HEALTH_REPORT)
+                        .header(axum::http::header::AUTHORIZATION, format!("Bearer {token}"))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap()
+            }
+        };
+        assert_eq!(
+            report(service_token.clone()).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        repo::service::set_admin(&pool, &id, true).await.unwrap();
+        let response = report(service_token).await;
+        assert_eq!(StatusCode::OK, response.status());
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let parsed_body: callers::health::response::report::Response =
+            serde_json::from_slice(&body).unwrap();
+        let report = &parsed_body.data[0];
+        assert_eq!(report.migration_version, report.expected_migration_version);
+
+        let _ = db_mgr::drop_database(&tm_pool, &db_name).await;
+    }
 }
@@ -0,0 +1,69 @@
+//! Optional webhook told about registrations that need or got a moderator's decision.
+//! Delivery happens in the background and failures are only logged, so a slow or
+//! unreachable hook never holds up the request.
+
+/// Seconds to wait for the webhook to answer
+const TIMEOUT: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    /// A user registered and waits for approval
+    RegistrationPending,
+    RegistrationApproved,
+    RegistrationRejected,
+}
+
+/// Body posted to the webhook
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct Notification {
+    pub event: Event,
+    pub user_id: uuid::Uuid,
+    pub username: String,
+    pub email: String,
+}
+
+pub struct Notifier {
+    webhook: Option<(reqwest::Client, String)>,
+}
+
+impl Notifier {
+    pub fn new(settings: &crate::config::Registration) -> Self {
+        let webhook = settings.webhook_url.as_ref().map(|url| {
+            let client = reqwest::Client::builder()
+                .timeout(std::time::Duration::from_secs(TIMEOUT))
+                .build()
+                .unwrap_or_default();
+            (client, url.clone())
+        });
+
+        Notifier { webhook }
+    }
+
+    /// Posts the event for the user to the webhook, if one is configured
+    pub fn registration(&self, event: Event, user: &icarus_models::user::User) {
+        let Some((client, url)) = &self.webhook else {
+            return;
+        };
+
+        let request = client.post(url).json(&Notification {
+            event,
+            user_id: user.id,
+            username: user.username.clone(),
+            email: user.email.clone(),
+        });
+
+        tokio::spawn(async move {
+            match request
+                .send()
+                .await
+                .and_then(|resp| resp.error_for_status())
+            {
+                Ok(_resp) => tracing::debug!(?event, "Sent registration notification"),
+                Err(err) => {
+                    tracing::warn!(?event, error = %err, "Could not send registration notification")
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,602 @@
+//! Sign-in with external OpenID Connect providers using the authorization code flow
+//! with PKCE. Provider metadata and signing keys are fetched on first use and kept
+//! for the life of the process. Keys are fetched again when a token is signed with
+//! one that is not known yet, which is how providers roll them over.
+
+use base64::Engine;
+use rand::Rng;
+use sha2::Digest;
+
+#[cfg(test)]
+pub mod mock;
+
+/// Seconds to wait for a provider to answer
+const TIMEOUT: u64 = 10;
+/// Seconds of clock difference with the provider tolerated when checking ID tokens
+pub const LEEWAY: u64 = 60;
+/// Characters in a generated state, nonce or PKCE verifier
+const RANDOM_LENGTH: usize = 43;
+/// Requested when a provider does not configure its own scopes
+pub const DEFAULT_SCOPES: [&str; 3] = ["openid", "email", "profile"];
+/// ID token signature algorithms a provider can be configured to use. `HS*` tokens are
+/// signed with the client secret, the others with a key from the provider's key set
+pub const ALGORITHMS: [&str; 13] = [
+    "RS256", "RS384", "RS512", "PS256", "PS384", "PS512", "ES256", "ES384", "ES512", "EdDSA",
+    "HS256", "HS384", "HS512",
+];
+/// Accepted when a provider does not configure its own algorithms, as OpenID Connect
+/// requires providers to support it
+pub const DEFAULT_ALGORITHMS: [&str; 1] = ["RS256"];
+
+#[derive(Debug)]
+pub enum Error {
+    /// The provider's metadata or keys could not be fetched
+    Discovery(String),
+    /// The authorization code could not be exchanged for tokens
+    Exchange(String),
+    /// The ID token failed validation
+    InvalidToken(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Discovery(err) => write!(f, "Could not fetch provider metadata: {err}"),
+            Error::Exchange(err) => write!(f, "Could not exchange authorization code: {err}"),
+            Error::InvalidToken(err) => write!(f, "Invalid ID token: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// The parts of a provider's `/.well-known/openid-configuration` that are used
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct Metadata {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    pub jwks_uri: String,
+}
+
+/// Claims read from a validated ID token
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct Claims {
+    pub sub: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: Option<bool>,
+    #[serde(default)]
+    pub preferred_username: Option<String>,
+    #[serde(default)]
+    pub given_name: Option<String>,
+    #[serde(default)]
+    pub family_name: Option<String>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+impl Claims {
+    /// Email the provider vouches for, if any
+    pub fn verified_email(&self) -> Option<&str> {
+        match self.email_verified {
+            Some(true) => self.email.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    #[serde(default)]
+    id_token: Option<String>,
+}
+
+/// Random URL safe string used for the state, the nonce and the PKCE verifier
+pub fn random_string() -> String {
+    rand::rng()
+        .sample_iter(&rand::distr::Alphanumeric)
+        .take(RANDOM_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// S256 code challenge for a PKCE verifier, see RFC 7636
+pub fn pkce_challenge(verifier: &str) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(verifier))
+}
+
+pub struct Client {
+    http: reqwest::Client,
+    metadata: std::sync::RwLock<std::collections::HashMap<String, Metadata>>,
+    keys: std::sync::RwLock<std::collections::HashMap<String, josekit::jwk::JwkSet>>,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(TIMEOUT))
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap_or_default();
+
+        Client {
+            http,
+            metadata: Default::default(),
+            keys: Default::default(),
+        }
+    }
+
+    /// Endpoints of the provider. Discovery is skipped when all of them are configured
+    pub async fn metadata(
+        &self,
+        provider: &crate::config::OidcProvider,
+    ) -> Result<Metadata, Error> {
+        if let Some(metadata) = self.metadata.read().unwrap().get(&provider.name) {
+            return Ok(metadata.clone());
+        }
+
+        let metadata = match (
+            &provider.authorization_endpoint,
+            &provider.token_endpoint,
+            &provider.jwks_uri,
+        ) {
+            (Some(authorization_endpoint), Some(token_endpoint), Some(jwks_uri)) => Metadata {
+                issuer: provider.issuer.clone(),
+                authorization_endpoint: authorization_endpoint.clone(),
+                token_endpoint: token_endpoint.clone(),
+                jwks_uri: jwks_uri.clone(),
+            },
+            _ => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    provider.issuer.trim_end_matches('/')
+                );
+                let mut discovered: Metadata = self
+                    .http
+                    .get(&url)
+                    .send()
+                    .await
+                    .and_then(|resp| resp.error_for_status())
+                    .map_err(|err| Error::Discovery(err.to_string()))?
+                    .json()
+                    .await
+                    .map_err(|err| Error::Discovery(err.to_string()))?;
+
+                if discovered.issuer.trim_end_matches('/') != provider.issuer.trim_end_matches('/')
+                {
+                    return Err(Error::Discovery(format!(
+                        "Provider says its issuer is {}",
+                        discovered.issuer
+                    )));
+                }
+                if let Some(endpoint) = &provider.authorization_endpoint {
+                    discovered.authorization_endpoint = endpoint.clone();
+                }
+                if let Some(endpoint) = &provider.token_endpoint {
+                    discovered.token_endpoint = endpoint.clone();
+                }
+                if let Some(uri) = &provider.jwks_uri {
+                    discovered.jwks_uri = uri.clone();
+                }
+                discovered
+            }
+        };
+
+        self.metadata
+            .write()
+            .unwrap()
+            .insert(provider.name.clone(), metadata.clone());
+
+        Ok(metadata)
+    }
+
+    /// Where to send the user to sign in at the provider
+    pub async fn authorization_url(
+        &self,
+        provider: &crate::config::OidcProvider,
+        state: &str,
+        nonce: &str,
+        pkce_verifier: &str,
+    ) -> Result<String, Error> {
+        let metadata = self.metadata(provider).await?;
+        let scopes = if provider.scopes.is_empty() {
+            DEFAULT_SCOPES.join(" ")
+        } else if provider.scopes.iter().any(|scope| scope == "openid") {
+            provider.scopes.join(" ")
+        } else {
+            format!("openid {}", provider.scopes.join(" "))
+        };
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &provider.client_id),
+                ("redirect_uri", &provider.redirect_url),
+                ("scope", &scopes),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", &pkce_challenge(pkce_verifier)),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|err| Error::Discovery(err.to_string()))?;
+
+        Ok(url.to_string())
+    }
+
+    /// Trades the authorization code for an ID token and validates it
+    pub async fn sign_in(
+        &self,
+        provider: &crate::config::OidcProvider,
+        code: &str,
+        pkce_verifier: &str,
+        nonce: &str,
+    ) -> Result<Claims, Error> {
+        let metadata = self.metadata(provider).await?;
+
+        let response: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &provider.redirect_url),
+                ("client_id", &provider.client_id),
+                ("client_secret", &provider.client_secret),
+                ("code_verifier", pkce_verifier),
+            ])
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::Exchange(err.to_string()))?
+            .json()
+            .await
+            .map_err(|err| Error::Exchange(err.to_string()))?;
+
+        let id_token = response
+            .id_token
+            .ok_or_else(|| Error::Exchange(String::from("Provider did not return an ID token")))?;
+
+        self.validate(provider, &metadata, &id_token, nonce).await
+    }
+
+    /// Checks the signature, issuer, audience, expiry and nonce of an ID token. The token
+    /// must be signed with one of the algorithms configured for the provider
+    pub async fn validate(
+        &self,
+        provider: &crate::config::OidcProvider,
+        metadata: &Metadata,
+        id_token: &str,
+        nonce: &str,
+    ) -> Result<Claims, Error> {
+        let header = josekit::jwt::decode_header(id_token)
+            .map_err(|err| Error::InvalidToken(err.to_string()))?;
+        let algorithm = header.algorithm().unwrap_or_default();
+        if !provider.algorithms().any(|allowed| allowed == algorithm) {
+            return Err(Error::InvalidToken(format!(
+                "Token is signed with `{algorithm}`, which is not configured for the provider"
+            )));
+        }
+
+        let verifier = if algorithm.starts_with("HS") {
+            if provider.client_secret.is_empty() {
+                return Err(Error::InvalidToken(String::from(
+                    "Token is signed with a client secret but none is configured",
+                )));
+            }
+            hmac_verifier(algorithm, provider.client_secret.as_bytes())
+        } else {
+            let kid = header.claim("kid").and_then(|kid| kid.as_str());
+            let jwk = self.key(provider, metadata, algorithm, kid).await?;
+            jwk_verifier(algorithm, &jwk)
+        }
+        .map_err(|err| Error::InvalidToken(err.to_string()))?;
+
+        let (payload, _) = josekit::jwt::decode_with_verifier(id_token, verifier.as_ref())
+            .map_err(|err| Error::InvalidToken(err.to_string()))?;
+        check_payload(
+            &payload,
+            &metadata.issuer,
+            &provider.client_id,
+            std::time::SystemTime::now(),
+        )?;
+
+        let claims: Claims =
+            serde_json::from_value(serde_json::Value::Object(payload.claims_set().clone()))
+                .map_err(|err| Error::InvalidToken(err.to_string()))?;
+
+        if claims.nonce.as_deref() != Some(nonce) {
+            return Err(Error::InvalidToken(String::from("Nonce does not match")));
+        }
+
+        Ok(claims)
+    }
+
+    /// Provider key with the id for the algorithm, fetching the key set again when it is
+    /// not known
+    async fn key(
+        &self,
+        provider: &crate::config::OidcProvider,
+        metadata: &Metadata,
+        algorithm: &str,
+        kid: Option<&str>,
+    ) -> Result<josekit::jwk::Jwk, Error> {
+        let find = |keys: &josekit::jwk::JwkSet| {
+            let candidates: Vec<&josekit::jwk::Jwk> = match kid {
+                Some(kid) => keys.get(kid),
+                None => keys.keys(),
+            }
+            .into_iter()
+            .filter(|jwk| jwk.algorithm().is_none_or(|alg| alg == algorithm))
+            .collect();
+            // Without a key id the token can only be checked if there is one key
+            match candidates.as_slice() {
+                [jwk] => Some((*jwk).clone()),
+                [jwk, ..] if kid.is_some() => Some((*jwk).clone()),
+                _ => None,
+            }
+        };
+
+        let cached = self.keys.read().unwrap().get(&provider.name).and_then(find);
+        if let Some(jwk) = cached {
+            return Ok(jwk);
+        }
+
+        let body = self
+            .http
+            .get(&metadata.jwks_uri)
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| Error::Discovery(err.to_string()))?
+            .bytes()
+            .await
+            .map_err(|err| Error::Discovery(err.to_string()))?;
+        let keys = josekit::jwk::JwkSet::from_bytes(&body)
+            .map_err(|err| Error::Discovery(err.to_string()))?;
+
+        let jwk = find(&keys);
+        self.keys
+            .write()
+            .unwrap()
+            .insert(provider.name.clone(), keys);
+
+        jwk.ok_or_else(|| Error::InvalidToken(String::from("Token is signed with an unknown key")))
+    }
+}
+
+/// Verifier for an `HS*` algorithm keyed with the client secret
+fn hmac_verifier(
+    algorithm: &str,
+    secret: &[u8],
+) -> Result<Box<dyn josekit::jws::JwsVerifier>, josekit::JoseError> {
+    use josekit::jws::{HS256, HS384, HS512};
+
+    Ok(match algorithm {
+        "HS256" => Box::new(HS256.verifier_from_bytes(secret)?),
+        "HS384" => Box::new(HS384.verifier_from_bytes(secret)?),
+        _ => Box::new(HS512.verifier_from_bytes(secret)?),
+    })
+}
+
+/// Verifier for an asymmetric algorithm keyed with a key from the provider's key set
+fn jwk_verifier(
+    algorithm: &str,
+    jwk: &josekit::jwk::Jwk,
+) -> Result<Box<dyn josekit::jws::JwsVerifier>, josekit::JoseError> {
+    use josekit::jws::{ES256, ES384, ES512, EdDSA, PS256, PS384, PS512, RS256, RS384, RS512};
+
+    Ok(match algorithm {
+        "RS256" => Box::new(RS256.verifier_from_jwk(jwk)?),
+        "RS384" => Box::new(RS384.verifier_from_jwk(jwk)?),
+        "RS512" => Box::new(RS512.verifier_from_jwk(jwk)?),
+        "PS256" => Box::new(PS256.verifier_from_jwk(jwk)?),
+        "PS384" => Box::new(PS384.verifier_from_jwk(jwk)?),
+        "PS512" => Box::new(PS512.verifier_from_jwk(jwk)?),
+        "ES256" => Box::new(ES256.verifier_from_jwk(jwk)?),
+        "ES384" => Box::new(ES384.verifier_from_jwk(jwk)?),
+        "ES512" => Box::new(ES512.verifier_from_jwk(jwk)?),
+        _ => Box::new(EdDSA.verifier_from_jwk(jwk)?),
+    })
+}
+
+/// Checks the registered claims of an ID token whose signature has been verified,
+/// allowing [`LEEWAY`] seconds of clock difference
+fn check_payload(
+    payload: &josekit::jwt::JwtPayload,
+    issuer: &str,
+    client_id: &str,
+    now: std::time::SystemTime,
+) -> Result<(), Error> {
+    let invalid = |message: &str| Err(Error::InvalidToken(String::from(message)));
+    let leeway = std::time::Duration::from_secs(LEEWAY);
+
+    if payload.issuer() != Some(issuer) {
+        return invalid("Issuer does not match");
+    }
+    if !payload
+        .audience()
+        .is_some_and(|audience| audience.contains(&client_id))
+    {
+        return invalid("Audience does not match");
+    }
+    if payload.subject().is_none_or(str::is_empty) {
+        return invalid("Token has no subject");
+    }
+    match payload.expires_at() {
+        Some(expires_at) if expires_at + leeway > now => {}
+        Some(_) => return invalid("Token has expired"),
+        None => return invalid("Token has no expiry"),
+    }
+    if payload
+        .not_before()
+        .is_some_and(|not_before| not_before > now + leeway)
+    {
+        return invalid("Token is not valid yet");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce_challenge() {
+        // Example from RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_random_string() {
+        let value = random_string();
+        assert_eq!(value.len(), RANDOM_LENGTH);
+        assert!(value.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(value, random_string());
+    }
+
+    #[tokio::test]
+    async fn test_sign_in() {
+        let mock = mock::Provider::spawn().await;
+        let provider = crate::config::OidcProvider {
+            name: String::from("mock"),
+            issuer: mock.issuer.clone(),
+            client_id: String::from("icarus"),
+            ..Default::default()
+        };
+        let client = Client::new();
+
+        let url = client
+            .authorization_url(&provider, "state", "nonce", "verifier")
+            .await
+            .unwrap();
+        assert_eq!(
+            mock::query_param(&url, "scope").unwrap(),
+            "openid email profile"
+        );
+        assert_eq!(
+            mock::query_param(&url, "code_challenge").unwrap(),
+            pkce_challenge("verifier")
+        );
+
+        mock.sign_in_as(mock::Identity {
+            subject: String::from("alice"),
+            nonce: String::from("nonce"),
+            ..Default::default()
+        });
+        let claims = client
+            .sign_in(&provider, "code", "verifier", "nonce")
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "alice");
+
+        let replayed = client.sign_in(&provider, "code", "verifier", "other").await;
+        assert!(matches!(replayed, Err(Error::InvalidToken(_))));
+
+        mock.sign_in_as(mock::Identity {
+            subject: String::from("alice"),
+            nonce: String::from("nonce"),
+            audience: Some(String::from("someone-else")),
+            ..Default::default()
+        });
+        let other_client = client.sign_in(&provider, "code", "verifier", "nonce").await;
+        assert!(matches!(other_client, Err(Error::InvalidToken(_))));
+
+        // Only the configured algorithms are accepted, whatever the token header says
+        let provider = crate::config::OidcProvider {
+            client_secret: String::from("s3cret"),
+            ..provider
+        };
+        let hs256 = mock::Identity {
+            subject: String::from("mallory"),
+            nonce: String::from("nonce"),
+            client_secret: Some(String::from("s3cret")),
+            ..Default::default()
+        };
+        mock.sign_in_as(hs256.clone());
+        let forged = client.sign_in(&provider, "code", "verifier", "nonce").await;
+        assert!(matches!(forged, Err(Error::InvalidToken(_))));
+
+        let es256_only = crate::config::OidcProvider {
+            algorithms: vec![String::from("ES256")],
+            ..provider.clone()
+        };
+        mock.sign_in_as(mock::Identity {
+            subject: String::from("alice"),
+            nonce: String::from("nonce"),
+            ..Default::default()
+        });
+        let unexpected = client
+            .sign_in(&es256_only, "code", "verifier", "nonce")
+            .await;
+        assert!(matches!(unexpected, Err(Error::InvalidToken(_))));
+
+        let hs256_only = crate::config::OidcProvider {
+            algorithms: vec![String::from("HS256")],
+            ..provider
+        };
+        mock.sign_in_as(hs256);
+        let claims = client
+            .sign_in(&hs256_only, "code", "verifier", "nonce")
+            .await
+            .unwrap();
+        assert_eq!(claims.sub, "mallory");
+    }
+
+    #[test]
+    fn test_check_payload() {
+        let now = std::time::SystemTime::now();
+        let mut payload = josekit::jwt::JwtPayload::new();
+        payload.set_issuer("https://issuer.example.com");
+        payload.set_audience(vec!["icarus"]);
+        payload.set_subject("alice");
+        payload.set_expires_at(&(now + std::time::Duration::from_secs(300)));
+        let check = |payload: &josekit::jwt::JwtPayload, now| {
+            check_payload(payload, "https://issuer.example.com", "icarus", now)
+        };
+        assert!(check(&payload, now).is_ok());
+
+        // Within the leeway of expiring
+        let expired_at = now + std::time::Duration::from_secs(300 + LEEWAY / 2);
+        assert!(check(&payload, expired_at).is_ok());
+        let expired_at = now + std::time::Duration::from_secs(300 + LEEWAY);
+        assert!(check(&payload, expired_at).is_err());
+
+        let mut other = payload.clone();
+        other.set_issuer("https://evil.example.com");
+        assert!(check(&other, now).is_err());
+        let mut other = payload.clone();
+        other.set_audience(vec!["someone-else"]);
+        assert!(check(&other, now).is_err());
+        let mut other = payload.clone();
+        other.set_not_before(&(now + std::time::Duration::from_secs(2 * LEEWAY)));
+        assert!(check(&other, now).is_err());
+        let mut other = payload;
+        other.set_subject("");
+        assert!(check(&other, now).is_err());
+    }
+
+    #[test]
+    fn test_verified_email() {
+        let mut claims = Claims {
+            email: Some(String::from("bob@example.com")),
+            ..Default::default()
+        };
+        assert_eq!(claims.verified_email(), None);
+        claims.email_verified = Some(true);
+        assert_eq!(claims.verified_email(), Some("bob@example.com"));
+    }
+}
@@ -0,0 +1,294 @@
+//! Offline copy of the Pwned Passwords list. Lines are `SHA1:COUNT` with the
+//! uppercase hex SHA-1 of a breached password, sorted by hash, as produced by the
+//! official downloader. The list is tens of gigabytes, so it stays on disk and each
+//! lookup binary searches the file by byte offset, reading a few dozen lines.
+//! Reads are positional, so concurrent lookups share the file without a lock. They
+//! block, so callers run lookups on the blocking thread pool.
+
+use std::os::unix::fs::FileExt;
+
+use sha1::Digest;
+
+const HASH_LENGTH: usize = 40;
+/// Bytes read at a time while looking for a line. Lines are about 50 bytes long
+const CHUNK: usize = 128;
+
+pub struct Breached {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+    size: u64,
+}
+
+impl Breached {
+    /// Opens the list and checks its first and last lines, without reading the rest
+    pub fn load(path: &std::path::Path) -> Result<Breached, std::io::Error> {
+        let file = std::fs::File::open(path).map_err(|err| {
+            std::io::Error::other(format!(
+                "Could not read breached passwords file {}: {err}",
+                path.display()
+            ))
+        })?;
+        let size = file.metadata()?.len();
+        let breached = Breached {
+            path: path.to_path_buf(),
+            file,
+            size,
+        };
+
+        let invalid = |reason: &str| {
+            std::io::Error::other(format!(
+                "Invalid breached passwords file {}: {reason}",
+                path.display()
+            ))
+        };
+        let (first, _) = breached.line_at(0)?;
+        if hash_of(&first).is_none() {
+            return Err(invalid("line 1 is not SHA1:COUNT"));
+        }
+        let last_start = breached.last_line_start()?;
+        let (last, _) = breached.line_at(last_start)?;
+        match hash_of(&last) {
+            None => return Err(invalid("the last line is not SHA1:COUNT")),
+            Some(last) if hash_of(&first).is_some_and(|first| first > last) => {
+                return Err(invalid("lines are not sorted by hash"));
+            }
+            Some(_) => {}
+        }
+        Ok(breached)
+    }
+
+    /// Whether the password's hash is in the list. A file that can no longer be read
+    /// is logged and treated as not containing it
+    pub fn contains(&self, password: &str) -> bool {
+        let hash = sha1_hex(password);
+        match self.search(&hash) {
+            Ok(found) => found,
+            Err(err) => {
+                tracing::error!(
+                    "Could not search breached passwords file {}: {err}",
+                    self.path.display()
+                );
+                false
+            }
+        }
+    }
+
+    /// Binary search over line starts. Lines starting in `lo..hi` are the only ones
+    /// that can still hold the hash, and `lo` is always the start of a line
+    fn search(&self, hash: &str) -> Result<bool, std::io::Error> {
+        let (mut lo, mut hi) = (0, self.size);
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let start = self.next_line_start(mid)?;
+            if start >= hi {
+                hi = mid;
+                continue;
+            }
+
+            let (line, end) = self.line_at(start)?;
+            match hash_of(&line).as_deref().map(|line| line.cmp(hash)) {
+                Some(std::cmp::Ordering::Equal) => return Ok(true),
+                Some(std::cmp::Ordering::Greater) => hi = start,
+                Some(std::cmp::Ordering::Less) | None => lo = end,
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Start of the first line at or after the offset
+    fn next_line_start(&self, offset: u64) -> std::io::Result<u64> {
+        if offset == 0 {
+            return Ok(0);
+        }
+
+        // A line starts at the offset when the byte before it ends the previous one
+        let mut position = offset - 1;
+        let mut buffer = [0u8; CHUNK];
+        loop {
+            let read = read_at(&self.file, position, &mut buffer)?;
+            if read == 0 {
+                return Ok(self.size);
+            }
+            if let Some(index) = buffer[..read].iter().position(|byte| *byte == b'\n') {
+                return Ok(position + index as u64 + 1);
+            }
+            position += read as u64;
+        }
+    }
+
+    /// Start of the last non-empty line
+    fn last_line_start(&self) -> std::io::Result<u64> {
+        let mut end = self.size;
+        let mut buffer = [0u8; CHUNK];
+        // Trailing line breaks belong to the end of the file, not to a line
+        while end > 0 {
+            let position = end.saturating_sub(CHUNK as u64);
+            let read = read_at(
+                &self.file,
+                position,
+                &mut buffer[..(end - position) as usize],
+            )?;
+            if let Some(index) = buffer[..read]
+                .iter()
+                .rposition(|byte| !byte.is_ascii_whitespace())
+            {
+                return self.line_start_before(position + index as u64 + 1);
+            }
+            end = position;
+        }
+        Ok(0)
+    }
+
+    /// Start of the line that holds the byte before the offset
+    fn line_start_before(&self, offset: u64) -> std::io::Result<u64> {
+        let mut end = offset;
+        let mut buffer = [0u8; CHUNK];
+        while end > 0 {
+            let position = end.saturating_sub(CHUNK as u64);
+            let read = read_at(
+                &self.file,
+                position,
+                &mut buffer[..(end - position) as usize],
+            )?;
+            if let Some(index) = buffer[..read].iter().rposition(|byte| *byte == b'\n') {
+                return Ok(position + index as u64 + 1);
+            }
+            end = position;
+        }
+        Ok(0)
+    }
+
+    /// Line starting at the offset, without its line break, and where the next begins
+    fn line_at(&self, start: u64) -> std::io::Result<(String, u64)> {
+        let mut line: Vec<u8> = Vec::new();
+        let mut position = start;
+        let mut buffer = [0u8; CHUNK];
+        loop {
+            let read = read_at(&self.file, position, &mut buffer)?;
+            if read == 0 {
+                return Ok((String::from_utf8_lossy(&line).into_owned(), self.size));
+            }
+            if let Some(index) = buffer[..read].iter().position(|byte| *byte == b'\n') {
+                line.extend_from_slice(&buffer[..index]);
+                let next = position + index as u64 + 1;
+                return Ok((String::from_utf8_lossy(&line).into_owned(), next));
+            }
+            line.extend_from_slice(&buffer[..read]);
+            position += read as u64;
+        }
+    }
+}
+
+/// Reads up to the buffer's length from the offset, fewer only at the end of the file
+fn read_at(file: &std::fs::File, offset: u64, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read_at(&mut buffer[filled..], offset + filled as u64)? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
+
+/// Uppercase hash of a `SHA1:COUNT` line, or `None` when it is not one
+fn hash_of(line: &str) -> Option<String> {
+    let hash = line.trim().split(':').next().unwrap_or_default();
+    if hash.len() != HASH_LENGTH || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(hash.to_uppercase())
+}
+
+fn sha1_hex(password: &str) -> String {
+    sha1::Sha1::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_list(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "icarus-auth-breached-{}-{name}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_lookup() {
+        // Hashes of "password" and "123456" among filler, sorted, with CRLF line breaks
+        // and a mixed case line as some exports have them
+        let mut lines: Vec<String> = (0..500u32)
+            .map(|n| format!("{}:{n}", sha1_hex(&format!("filler {n}"))))
+            .collect();
+        lines.push(String::from(
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:10434004",
+        ));
+        lines.push(String::from(
+            "7c4a8d09ca3762af61e59520943dc26494f8941b:37359195",
+        ));
+        lines.sort_by_key(|line| line.to_uppercase());
+        let path = write_list("lookup", &(lines.join("\r\n") + "\r\n"));
+
+        let breached = Breached::load(&path).unwrap();
+        assert!(breached.contains("password"));
+        assert!(breached.contains("123456"));
+        assert!(breached.contains("filler 0"));
+        assert!(breached.contains("filler 499"));
+        assert!(!breached.contains("Raindown!"));
+        assert!(!breached.contains("filler 500"));
+
+        // Lookups from several threads share the file
+        let breached = std::sync::Arc::new(breached);
+        let threads: Vec<_> = (0..4u32)
+            .map(|n| {
+                let breached = breached.clone();
+                std::thread::spawn(move || breached.contains(&format!("filler {n}")))
+            })
+            .collect();
+        assert!(threads.into_iter().all(|thread| thread.join().unwrap()));
+
+        // The first and last lines are found too
+        let edges = write_list("edges", &lines[..2].join("\n"));
+        let breached = Breached::load(&edges).unwrap();
+        for line in &lines[..2] {
+            assert!(breached.search(&hash_of(line).unwrap()).unwrap());
+        }
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(edges).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let path = write_list(
+            "invalid",
+            "5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:1\nnot a hash:2\n",
+        );
+        let err = Breached::load(&path).err().unwrap().to_string();
+        assert!(err.contains("the last line is not SHA1:COUNT"), "{err}");
+
+        let path = write_list("invalid", "not a hash:2\n");
+        let err = Breached::load(&path).err().unwrap().to_string();
+        assert!(err.contains("line 1 is not SHA1:COUNT"), "{err}");
+
+        let path = write_list(
+            "invalid",
+            "7C4A8D09CA3762AF61E59520943DC26494F8941B:1\n\
+            5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8:1\n",
+        );
+        let err = Breached::load(&path).err().unwrap().to_string();
+        assert!(err.contains("not sorted"), "{err}");
+
+        std::fs::remove_file(path).unwrap();
+        assert!(Breached::load(std::path::Path::new("/nonexistent/pwned.txt")).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Rules new passwords have to satisfy at registration, password change and reset:
+//! length bounds, required character classes, no username, a minimum strength
+//! score and, when a breach list is loaded, not appearing in it.
+
+pub mod breached;
+pub mod strength;
+
+/// Configured rules plus the breach list loaded at startup
+pub struct Policy {
+    pub settings: crate::config::Password,
+    pub breached: Option<breached::Breached>,
+}
+
+impl Policy {
+    /// Policy without a breach list
+    pub fn new(settings: &crate::config::Password) -> Self {
+        Policy {
+            settings: settings.clone(),
+            breached: None,
+        }
+    }
+
+    /// Policy with the breach list named in the settings, when there is one
+    pub fn load(settings: &crate::config::Password) -> Result<Self, std::io::Error> {
+        let breached = match &settings.breached_file {
+            Some(path) => Some(breached::Breached::load(path)?),
+            None => None,
+        };
+
+        Ok(Policy {
+            settings: settings.clone(),
+            breached,
+        })
+    }
+
+    /// [`Policy::check`] on the blocking thread pool, for handlers. Searching the breach
+    /// list reads from disk, which must not hold up the async executor
+    pub async fn check_blocking(
+        self: std::sync::Arc<Self>,
+        password: String,
+        username: String,
+    ) -> Result<Vec<String>, tokio::task::JoinError> {
+        tokio::task::spawn_blocking(move || self.check(&password, &username)).await
+    }
+
+    /// Every rule the password breaks. Empty when it is acceptable
+    pub fn check(&self, password: &str, username: &str) -> Vec<String> {
+        let settings = &self.settings;
+        let mut problems: Vec<String> = Vec::new();
+        let length = password.chars().count();
+
+        if length < settings.min_length {
+            problems.push(format!(
+                "Password must be at least {} characters",
+                settings.min_length
+            ));
+        }
+        if length > settings.max_length {
+            problems.push(format!(
+                "Password must be at most {} characters",
+                settings.max_length
+            ));
+        }
+        if settings.require_lowercase && !password.chars().any(char::is_lowercase) {
+            problems.push(String::from("Password must contain a lowercase letter"));
+        }
+        if settings.require_uppercase && !password.chars().any(char::is_uppercase) {
+            problems.push(String::from("Password must contain an uppercase letter"));
+        }
+        if settings.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            problems.push(String::from("Password must contain a digit"));
+        }
+        if settings.require_symbol && !password.chars().any(is_symbol) {
+            problems.push(String::from("Password must contain a symbol"));
+        }
+        if settings.disallow_username
+            && !username.is_empty()
+            && password.to_lowercase().contains(&username.to_lowercase())
+        {
+            problems.push(String::from("Password must not contain the username"));
+        }
+        if length <= settings.max_length
+            && strength::score(password, &[username]) < settings.min_score
+        {
+            problems.push(String::from("Password is too easy to guess"));
+        }
+        if let Some(breached) = &self.breached
+            && breached.contains(password)
+        {
+            problems.push(String::from(
+                "Password has appeared in a data breach and cannot be used",
+            ));
+        }
+
+        problems
+    }
+}
+
+fn is_symbol(c: char) -> bool {
+    !c.is_alphanumeric() && !c.is_whitespace()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = Policy::new(&crate::config::Password::default());
+
+        assert!(policy.check("Raindown!", "somethingsss").is_empty());
+        assert!(
+            policy
+                .check("Sunny-Afternoon-77", "somethingsss")
+                .is_empty()
+        );
+        assert!(!policy.check("", "somethingsss").is_empty());
+        assert!(!policy.check("password", "somethingsss").is_empty());
+        assert!(!policy.check(&"a".repeat(200), "somethingsss").is_empty());
+        assert!(!policy.check("Bob-Rainy-Day-42", "rainy").is_empty());
+    }
+
+    #[test]
+    fn test_character_classes() {
+        let policy = Policy::new(&crate::config::Password {
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: true,
+            min_score: 0,
+            ..Default::default()
+        });
+
+        assert_eq!(policy.check("lowercaseonly", "").len(), 3);
+        assert!(policy.check("Upper-lower-9", "").is_empty());
+    }
+}
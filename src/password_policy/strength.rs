@@ -0,0 +1,29 @@
+//! Password strength estimated with zxcvbn. It matches the password against its
+//! dictionaries of common passwords, names and words, the user's own inputs, repeats,
+//! sequences, keyboard patterns and dates, and maps the guesses needed to a score
+//! from 0 to 4.
+
+/// Score from 0, too guessable, to 4, very unguessable. `user_inputs` such as the
+/// username count as known words
+pub fn score(password: &str, user_inputs: &[&str]) -> u8 {
+    u8::from(zxcvbn::zxcvbn(password, user_inputs).score())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scores() {
+        assert_eq!(score("", &[]), 0);
+        assert_eq!(score("password", &[]), 0);
+        assert_eq!(score("Password", &[]), 0);
+        assert_eq!(score("aaaaaaaa", &[]), 0);
+        assert_eq!(score("abcdefgh", &[]), 0);
+        assert_eq!(score("qwertyuiop", &[]), 0);
+        assert!(score("password2024", &[]) <= 1);
+        assert!(score("somethingsss1", &["somethingsss"]) <= 1);
+        assert!(score("Raindown!", &[]) >= 2);
+        assert_eq!(score("correct horse battery staple", &[]), 4);
+    }
+}
@@ -0,0 +1,166 @@
+use sqlx::Row;
+
+pub mod status {
+    /// Waiting for a user to enter the user code
+    pub const PENDING: &str = "pending";
+    pub const APPROVED: &str = "approved";
+    pub const DENIED: &str = "denied";
+}
+
+/// What a device learns when it polls, see RFC 8628 section 3.5
+#[derive(Debug, PartialEq)]
+pub enum Poll {
+    /// Not decided yet
+    Pending,
+    /// Polled before the interval was up. The interval has been raised by 5 seconds
+    SlowDown,
+    Expired,
+    Denied,
+    /// Approved by the user. The authorization is gone, so the token is handed out once
+    Approved(uuid::Uuid),
+}
+
+/// Seconds added to the interval of a device that polls too fast
+pub const SLOW_DOWN: i32 = 5;
+
+pub async fn insert(
+    pool: &sqlx::PgPool,
+    device_code_hash: &str,
+    user_code: &str,
+    client_id: &str,
+    interval: i32,
+    expires_at: &time::OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    // Frees the user codes of expired authorizations
+    sqlx::query(
+        r#"
+        DELETE FROM "device_authorization" WHERE expires_at <= NOW()
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO "device_authorization" (device_code_hash, user_code, client_id, poll_interval, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(device_code_hash)
+    .bind(user_code)
+    .bind(client_id)
+    .bind(interval)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        if !super::is_unique_violation(&e) {
+            tracing::error!(error = %e, "Error inserting device authorization");
+        }
+        e
+    })?;
+
+    tx.commit().await
+}
+
+/// Approves or denies the pending authorization with the user code, and returns the
+/// client it was for. `RowNotFound` when there is none, or it expired or was decided
+pub async fn decide(
+    pool: &sqlx::PgPool,
+    user_code: &str,
+    user_id: &uuid::Uuid,
+    approved: bool,
+) -> Result<String, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        UPDATE "device_authorization" SET status = $3, user_id = $4
+        WHERE user_code = $1 AND status = $2 AND expires_at > NOW()
+        RETURNING client_id
+        "#,
+    )
+    .bind(user_code)
+    .bind(status::PENDING)
+    .bind(if approved {
+        status::APPROVED
+    } else {
+        status::DENIED
+    })
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+
+    match row {
+        Some(r) => r.try_get("client_id"),
+        None => Err(sqlx::Error::RowNotFound),
+    }
+}
+
+/// Records a poll by the device and tells it where its authorization stands. Decided
+/// and expired authorizations are removed once the device has been told. `RowNotFound`
+/// when the device code is unknown or belongs to another client
+pub async fn poll(
+    pool: &sqlx::PgPool,
+    device_code_hash: &str,
+    client_id: &str,
+) -> Result<Poll, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query(
+        r#"
+        SELECT status, user_id, expires_at <= NOW() AS expired,
+            COALESCE(last_polled_at + make_interval(secs => poll_interval) > NOW(), FALSE) AS too_soon
+        FROM "device_authorization" WHERE device_code_hash = $1 AND client_id = $2
+        FOR UPDATE
+        "#,
+    )
+    .bind(device_code_hash)
+    .bind(client_id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(sqlx::Error::RowNotFound)?;
+
+    let status: String = row.try_get("status")?;
+    let user_id: Option<uuid::Uuid> = row.try_get("user_id")?;
+    let poll = if row.try_get("expired")? {
+        Poll::Expired
+    } else if let (status::APPROVED, Some(user_id)) = (status.as_str(), user_id) {
+        Poll::Approved(user_id)
+    } else if status == status::DENIED {
+        Poll::Denied
+    } else if row.try_get("too_soon")? {
+        Poll::SlowDown
+    } else {
+        Poll::Pending
+    };
+
+    match poll {
+        Poll::Pending | Poll::SlowDown => {
+            sqlx::query(
+                r#"
+                UPDATE "device_authorization"
+                SET last_polled_at = NOW(), poll_interval = poll_interval + $2
+                WHERE device_code_hash = $1
+                "#,
+            )
+            .bind(device_code_hash)
+            .bind(if poll == Poll::SlowDown { SLOW_DOWN } else { 0 })
+            .execute(&mut *tx)
+            .await?;
+        }
+        _ => {
+            sqlx::query(
+                r#"
+                DELETE FROM "device_authorization" WHERE device_code_hash = $1
+                "#,
+            )
+            .bind(device_code_hash)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(poll)
+}
@@ -0,0 +1,166 @@
+use sqlx::Row;
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct Invitation {
+    pub id: uuid::Uuid,
+    /// User or service account that issued it
+    pub created_by: uuid::Uuid,
+    /// Only this email can register with it, when set
+    pub email: Option<String>,
+    /// Role given to users registering with it
+    pub role: Option<String>,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: Option<time::OffsetDateTime>,
+    pub date_created: time::OffsetDateTime,
+    /// Users that registered with it
+    pub used_by: Vec<uuid::Uuid>,
+}
+
+impl Invitation {
+    /// Not expired and not used up
+    pub fn is_usable(&self, now: time::OffsetDateTime) -> bool {
+        self.uses < self.max_uses && self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+const SELECT: &str = r#"
+    SELECT i.id, i.created_by, i.email, i.role, i.max_uses, i.uses, i.expires_at, i.date_created,
+        COALESCE(array_agg(u.user_id ORDER BY u.date_used) FILTER (WHERE u.user_id IS NOT NULL), '{}') AS used_by
+    FROM "invitation" i LEFT JOIN "invitation_use" u ON u.invitation_id = i.id
+"#;
+
+fn from_row(r: &sqlx::postgres::PgRow) -> Result<Invitation, sqlx::Error> {
+    Ok(Invitation {
+        id: r.try_get("id")?,
+        created_by: r.try_get("created_by")?,
+        email: r.try_get("email")?,
+        role: r.try_get("role")?,
+        max_uses: r.try_get("max_uses")?,
+        uses: r.try_get("uses")?,
+        expires_at: r.try_get("expires_at")?,
+        date_created: r.try_get("date_created")?,
+        used_by: r.try_get("used_by")?,
+    })
+}
+
+pub async fn insert(
+    pool: &sqlx::PgPool,
+    code_hash: &str,
+    created_by: &uuid::Uuid,
+    email: Option<&str>,
+    role: Option<&str>,
+    max_uses: i32,
+    expires_at: Option<time::OffsetDateTime>,
+) -> Result<(uuid::Uuid, time::OffsetDateTime), sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        INSERT INTO "invitation" (code_hash, created_by, email, role, max_uses, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, date_created;
+        "#,
+    )
+    .bind(code_hash)
+    .bind(created_by)
+    .bind(email)
+    .bind(role)
+    .bind(max_uses)
+    .bind(expires_at)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Error inserting invitation");
+        e
+    })?;
+
+    Ok((row.try_get("id")?, row.try_get("date_created")?))
+}
+
+pub async fn get_by_code_hash(
+    pool: &sqlx::PgPool,
+    code_hash: &str,
+) -> Result<Invitation, sqlx::Error> {
+    let row = sqlx::query(&format!("{SELECT} WHERE i.code_hash = $1 GROUP BY i.id"))
+        .bind(code_hash)
+        .fetch_optional(pool)
+        .await?;
+
+    match row {
+        Some(r) => from_row(&r),
+        None => Err(sqlx::Error::RowNotFound),
+    }
+}
+
+/// Every invitation, or only those issued by `created_by`, newest first
+pub async fn list(
+    pool: &sqlx::PgPool,
+    created_by: Option<&uuid::Uuid>,
+) -> Result<Vec<Invitation>, sqlx::Error> {
+    let rows = sqlx::query(&format!(
+        "{SELECT} WHERE $1::UUID IS NULL OR i.created_by = $1 GROUP BY i.id ORDER BY i.date_created DESC"
+    ))
+    .bind(created_by)
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter().map(from_row).collect()
+}
+
+/// Counts a use of the invitation if it is still usable. The check and the increment
+/// are one statement so concurrent registrations cannot use it more than `max_uses`
+/// times. Returns `RowNotFound` when it is expired or used up
+pub async fn consume(
+    tx: &mut sqlx::PgConnection,
+    id: &uuid::Uuid,
+    user_id: &uuid::Uuid,
+) -> Result<(), sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        UPDATE "invitation" SET uses = uses + 1
+        WHERE id = $1 AND uses < max_uses AND (expires_at IS NULL OR expires_at > NOW())
+        RETURNING id
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    if row.is_none() {
+        return Err(sqlx::Error::RowNotFound);
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO "invitation_use" (invitation_id, user_id) VALUES ($1, $2)
+        "#,
+    )
+    .bind(id)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Deletes the invitation, only when issued by `created_by` if given
+pub async fn delete(
+    pool: &sqlx::PgPool,
+    id: &uuid::Uuid,
+    created_by: Option<&uuid::Uuid>,
+) -> Result<(), sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        DELETE FROM "invitation" WHERE id = $1 AND ($2::UUID IS NULL OR created_by = $2)
+        "#,
+    )
+    .bind(id)
+    .bind(created_by)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        Err(sqlx::Error::RowNotFound)
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use sqlx::Row;
+
+/// Stores a login link for the user. Links sent to the user before keep working until
+/// they expire or one of them is used
+pub async fn insert(
+    pool: &sqlx::PgPool,
+    token_hash: &str,
+    user_id: &uuid::Uuid,
+    expires_at: &time::OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query(
+        r#"
+        DELETE FROM "magic_link" WHERE expires_at <= NOW()
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO "magic_link" (token_hash, user_id, expires_at) VALUES ($1, $2, $3)
+        "#,
+    )
+    .bind(token_hash)
+    .bind(user_id)
+    .bind(expires_at)
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Error inserting magic link");
+        e
+    })?;
+
+    tx.commit().await
+}
+
+/// Removes the link and returns its user, so a link works once. Using a link also ends
+/// the user's other links. `RowNotFound` when it is unknown, used or expired
+pub async fn consume(pool: &sqlx::PgPool, token_hash: &str) -> Result<uuid::Uuid, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    let row = sqlx::query(
+        r#"
+        DELETE FROM "magic_link" WHERE token_hash = $1 RETURNING user_id, expires_at > NOW() AS valid
+        "#,
+    )
+    .bind(token_hash)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user_id: uuid::Uuid = match row {
+        Some(r) if r.try_get::<bool, _>("valid")? => r.try_get("user_id")?,
+        _ => return Err(sqlx::Error::RowNotFound),
+    };
+
+    sqlx::query(
+        r#"
+        DELETE FROM "magic_link" WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(user_id)
+}
@@ -1,14 +1,76 @@
+pub mod device;
+pub mod invitation;
+pub mod magic_link;
+pub mod oidc;
 pub mod service;
 
+/// Whether the error is a Postgres unique constraint violation (SQLSTATE 23505), as
+/// returned when a concurrent write claimed the same username or email first
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db_err) => db_err.is_unique_violation(),
+        _ => false,
+    }
+}
+
 pub mod user {
     use sqlx::Row;
 
+    /// Values of the `status` column
+    pub mod status {
+        pub const ACTIVE: &str = "Active";
+        pub const DISABLED: &str = "Disabled";
+        /// Registered while registration is moderated and not approved yet
+        pub const PENDING: &str = "Pending";
+    }
+
+    /// How a user was looked up when logging in
+    pub mod identifier {
+        pub const USERNAME: &str = "username";
+        pub const EMAIL: &str = "email";
+        /// Signed in through an external OpenID Connect provider
+        pub const OIDC: &str = "oidc";
+        /// Logged in with a link sent by email
+        pub const MAGIC_LINK: &str = "magic_link";
+        /// Approved a device authorization from another session
+        pub const DEVICE: &str = "device";
+
+        /// What the identifier looks like. Usernames cannot contain `@` and emails must
+        pub fn kind(identifier: &str) -> &'static str {
+            if identifier.contains('@') {
+                EMAIL
+            } else {
+                USERNAME
+            }
+        }
+    }
+
     #[derive(Debug, serde::Serialize, sqlx::FromRow)]
     pub struct InsertedData {
         pub id: uuid::Uuid,
         pub date_created: Option<time::OffsetDateTime>,
     }
 
+    fn from_row(r: &sqlx::postgres::PgRow) -> Result<icarus_models::user::User, sqlx::Error> {
+        Ok(icarus_models::user::User {
+            id: r.try_get("id")?,
+            username: r.try_get("username")?,
+            password: r.try_get("password")?,
+            email: r.try_get("email")?,
+            email_verified: r.try_get("email_verified")?,
+            phone: r.try_get("phone")?,
+            // Nullable so rows written once the salt table is dropped still read
+            salt_id: r
+                .try_get::<Option<uuid::Uuid>, _>("salt_id")?
+                .unwrap_or_default(),
+            firstname: r.try_get("firstname")?,
+            lastname: r.try_get("lastname")?,
+            date_created: r.try_get("date_created")?,
+            last_login: r.try_get("last_login")?,
+            status: r.try_get("status")?,
+        })
+    }
+
     pub async fn get(
         pool: &sqlx::PgPool,
         username: &String,
@@ -24,20 +86,61 @@ pub mod user {
 
         match result {
             Ok(r) => match r {
-                Some(r) => Ok(icarus_models::user::User {
-                    id: r.try_get("id")?,
-                    username: r.try_get("username")?,
-                    password: r.try_get("password")?,
-                    email: r.try_get("email")?,
-                    email_verified: r.try_get("email_verified")?,
-                    phone: r.try_get("phone")?,
-                    salt_id: r.try_get("salt_id")?,
-                    firstname: r.try_get("firstname")?,
-                    lastname: r.try_get("lastname")?,
-                    date_created: r.try_get("date_created")?,
-                    last_login: r.try_get("last_login")?,
-                    status: r.try_get("status")?,
-                }),
+                Some(r) => from_row(&r),
+                None => Err(sqlx::Error::RowNotFound),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// User whose username or email matches the identifier after case folding, and
+    /// which of the two matched. A username match wins over an email match. An empty
+    /// identifier matches nobody, even users without an email
+    pub async fn get_by_identifier(
+        pool: &sqlx::PgPool,
+        identifier: &str,
+    ) -> Result<(icarus_models::user::User, &'static str), sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+        SELECT *, username_normalized = $1 AS by_username FROM "user"
+        WHERE username_normalized = $1 OR (email_normalized = $1 AND $1 <> '')
+        ORDER BY by_username DESC LIMIT 1
+        "#,
+        )
+        .bind(crate::validation::fold(identifier))
+        .fetch_optional(pool)
+        .await?;
+
+        match row {
+            Some(r) => {
+                let by_username: bool = r.try_get("by_username")?;
+                let kind = if by_username {
+                    identifier::USERNAME
+                } else {
+                    identifier::EMAIL
+                };
+                Ok((from_row(&r)?, kind))
+            }
+            None => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    pub async fn get_by_id(
+        pool: &sqlx::PgPool,
+        id: &uuid::Uuid,
+    ) -> Result<icarus_models::user::User, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+        SELECT * FROM "user" WHERE id = $1
+        "#,
+        )
+        .bind(id)
+        .fetch_optional(pool)
+        .await;
+
+        match result {
+            Ok(r) => match r {
+                Some(r) => from_row(&r),
                 None => Err(sqlx::Error::RowNotFound),
             },
             Err(e) => Err(e),
@@ -59,7 +162,7 @@ pub mod user {
         .fetch_optional(pool)
         .await
         .map_err(|e| {
-            eprintln!("Error updating time: {e}");
+            tracing::error!(error = %e, "Error updating time");
             e
         });
 
@@ -77,123 +180,256 @@ pub mod user {
         }
     }
 
-    pub async fn exists(pool: &sqlx::PgPool, username: &String) -> Result<bool, sqlx::Error> {
+    /// `RowNotFound` when there is no user with the id
+    pub async fn update_status(
+        pool: &sqlx::PgPool,
+        id: &uuid::Uuid,
+        status: &str,
+    ) -> Result<(), sqlx::Error> {
         let result = sqlx::query(
             r#"
-        SELECT 1 FROM "user" WHERE username = $1
-        "#,
+            UPDATE "user" SET status = $1 WHERE id = $2
+            "#,
         )
-        .bind(username)
-        .fetch_optional(pool)
-        .await;
+        .bind(status)
+        .bind(id)
+        .execute(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Error updating status");
+            e
+        })?;
 
-        match result {
-            Ok(r) => Ok(r.is_some()),
-            Err(e) => Err(e),
+        match result.rows_affected() {
+            0 => Err(sqlx::Error::RowNotFound),
+            _ => Ok(()),
         }
     }
 
-    pub async fn insert(
+    pub async fn update_password(
         pool: &sqlx::PgPool,
-        user: &icarus_models::user::User,
-    ) -> Result<(uuid::Uuid, std::option::Option<time::OffsetDateTime>), sqlx::Error> {
-        let row = sqlx::query(
+        id: &uuid::Uuid,
+        password: &String,
+    ) -> Result<(), sqlx::Error> {
+        let result = sqlx::query(
             r#"
-                INSERT INTO "user" (username, password, email, phone, firstname, lastname, email_verified, status, salt_id) 
-                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
-                RETURNING id, date_created;
-            "#)
-            .bind(&user.username)
-            .bind(&user.password)
-            .bind(&user.email)
-            .bind(&user.phone)
-            .bind(&user.firstname)
-            .bind(&user.lastname)
-            .bind(user.email_verified)
-            .bind(&user.status)
-            .bind(user.salt_id)
-        .fetch_one(pool)
+            UPDATE "user" SET password = $1 WHERE id = $2
+            "#,
+        )
+        .bind(password)
+        .bind(id)
+        .execute(pool)
         .await
         .map_err(|e| {
-            eprintln!("Error inserting item: {e}");
+            tracing::error!(error = %e, "Error updating password");
             e
         })?;
 
-        let result = InsertedData {
-            id: row.try_get("id").map_err(|_e| sqlx::Error::RowNotFound)?,
-            date_created: row
-                .try_get("date_created")
-                .map_err(|_e| sqlx::Error::RowNotFound)?,
-        };
-
-        if result.id.is_nil() && result.date_created.is_none() {
+        if result.rows_affected() == 0 {
             Err(sqlx::Error::RowNotFound)
         } else {
-            Ok((result.id, result.date_created))
+            Ok(())
         }
     }
-}
 
-pub mod salt {
-    use sqlx::Row;
+    /// Replaces the contact details and names, e.g. with what a directory says about
+    /// the user
+    pub async fn update_profile(
+        pool: &sqlx::PgPool,
+        user: &icarus_models::user::User,
+    ) -> Result<icarus_models::user::User, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            UPDATE "user" SET email = $1, email_normalized = $2, phone = $3, firstname = $4, lastname = $5
+            WHERE id = $6 RETURNING *
+            "#,
+        )
+        .bind(&user.email)
+        .bind(crate::validation::fold(&user.email))
+        .bind(&user.phone)
+        .bind(&user.firstname)
+        .bind(&user.lastname)
+        .bind(user.id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            if !super::is_unique_violation(&e) {
+                tracing::error!(error = %e, "Error updating profile");
+            }
+            e
+        })?;
 
-    #[derive(Debug, serde::Serialize, sqlx::FromRow)]
-    pub struct InsertedData {
-        pub id: uuid::Uuid,
+        match row {
+            Some(r) => from_row(&r),
+            None => Err(sqlx::Error::RowNotFound),
+        }
     }
 
-    pub async fn get(
+    /// Users with the status, oldest first
+    pub async fn list_by_status(
         pool: &sqlx::PgPool,
+        status: &str,
+    ) -> Result<Vec<icarus_models::user::User>, sqlx::Error> {
+        let rows = sqlx::query(
+            r#"
+            SELECT * FROM "user" WHERE status = $1 ORDER BY date_created
+            "#,
+        )
+        .bind(status)
+        .fetch_all(pool)
+        .await?;
+
+        rows.iter().map(from_row).collect()
+    }
+
+    /// Activates a pending user. `RowNotFound` when there is no pending user with the id
+    pub async fn approve(
+        pool: &sqlx::PgPool,
+        id: &uuid::Uuid,
+    ) -> Result<icarus_models::user::User, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            UPDATE "user" SET status = $1 WHERE id = $2 AND status = $3 RETURNING *
+            "#,
+        )
+        .bind(status::ACTIVE)
+        .bind(id)
+        .bind(status::PENDING)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Error approving user");
+            e
+        })?;
+
+        match row {
+            Some(r) => from_row(&r),
+            None => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    /// Deletes a pending user so the username and email can be registered again.
+    /// `RowNotFound` when there is no pending user with the id
+    pub async fn reject(
+        pool: &sqlx::PgPool,
+        id: &uuid::Uuid,
+    ) -> Result<icarus_models::user::User, sqlx::Error> {
+        let row = sqlx::query(
+            r#"
+            DELETE FROM "user" WHERE id = $1 AND status = $2 RETURNING *
+            "#,
+        )
+        .bind(id)
+        .bind(status::PENDING)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Error rejecting user");
+            e
+        })?;
+
+        match row {
+            Some(r) => from_row(&r),
+            None => Err(sqlx::Error::RowNotFound),
+        }
+    }
+
+    /// Sets the role given by the invitation the user registered with
+    pub async fn update_role(
+        tx: &mut sqlx::PgConnection,
         id: &uuid::Uuid,
-    ) -> Result<icarus_models::user::salt::Salt, sqlx::Error> {
+        role: &str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            r#"
+            UPDATE "user" SET role = $1 WHERE id = $2
+            "#,
+        )
+        .bind(role)
+        .bind(id)
+        .execute(tx)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "Error updating role");
+            e
+        })?;
+
+        Ok(())
+    }
+
+    /// Whether a user already has this username or email, compared by their case
+    /// folded form. An empty email only checks the username
+    pub async fn exists(
+        pool: &sqlx::PgPool,
+        username: &str,
+        email: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let email = crate::validation::fold(email);
         let result = sqlx::query(
             r#"
-        SELECT * FROM "salt" WHERE id = $1
+        SELECT 1 FROM "user" WHERE username_normalized = $1 OR ($2 <> '' AND email_normalized = $2)
         "#,
         )
-        .bind(id)
+        .bind(crate::validation::fold(username))
+        .bind(email)
         .fetch_optional(pool)
         .await;
 
         match result {
-            Ok(r) => match r {
-                Some(r) => Ok(icarus_models::user::salt::Salt {
-                    id: r.try_get("id")?,
-                    salt: r.try_get("salt")?,
-                }),
-                None => Err(sqlx::Error::RowNotFound),
-            },
+            Ok(r) => Ok(r.is_some()),
             Err(e) => Err(e),
         }
     }
 
-    pub async fn insert(
-        pool: &sqlx::PgPool,
-        salt: &icarus_models::user::salt::Salt,
-    ) -> Result<uuid::Uuid, sqlx::Error> {
+    /// Takes a pool or a transaction so the insert can be part of a larger write
+    pub async fn insert<'e, E: sqlx::PgExecutor<'e>>(
+        executor: E,
+        user: &icarus_models::user::User,
+    ) -> Result<(uuid::Uuid, std::option::Option<time::OffsetDateTime>), sqlx::Error> {
+        // The previous release reads salt_id as required, so new users still get a salt
+        // row holding the salt embedded in their hash until the table is dropped
+        let salt = argon2::PasswordHash::new(&user.password)
+            .ok()
+            .and_then(|hash| hash.salt.map(|salt| salt.to_string()))
+            .unwrap_or_default();
         let row = sqlx::query(
             r#"
-                INSERT INTO "salt" (salt) 
-                VALUES ($1)
-                RETURNING id;
-            "#,
-        )
-        .bind(&salt.salt)
-        .fetch_one(pool)
+                WITH salt AS (INSERT INTO "salt" (salt) VALUES ($11) RETURNING id)
+                INSERT INTO "user" (username, password, email, phone, firstname, lastname, email_verified, status, username_normalized, email_normalized, salt_id)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, (SELECT id FROM salt))
+                RETURNING id, date_created;
+            "#)
+            .bind(&user.username)
+            .bind(&user.password)
+            .bind(&user.email)
+            .bind(&user.phone)
+            .bind(&user.firstname)
+            .bind(&user.lastname)
+            .bind(user.email_verified)
+            .bind(&user.status)
+            .bind(crate::validation::fold(&user.username))
+            .bind(crate::validation::fold(&user.email))
+            .bind(salt)
+        .fetch_one(executor)
         .await
         .map_err(|e| {
-            eprintln!("Error inserting item: {e}");
+            if !super::is_unique_violation(&e) {
+                tracing::error!(error = %e, "Error inserting item");
+            }
             e
         })?;
 
         let result = InsertedData {
             id: row.try_get("id").map_err(|_e| sqlx::Error::RowNotFound)?,
+            date_created: row
+                .try_get("date_created")
+                .map_err(|_e| sqlx::Error::RowNotFound)?,
         };
 
-        if !result.id.is_nil() {
-            Ok(result.id)
-        } else {
+        if result.id.is_nil() && result.date_created.is_none() {
             Err(sqlx::Error::RowNotFound)
+        } else {
+            Ok((result.id, result.date_created))
         }
     }
 }
@@ -0,0 +1,115 @@
+use sqlx::Row;
+
+/// What is remembered about a sign-in while the user is at the provider
+pub struct LoginState {
+    pub provider: String,
+    pub nonce: String,
+    pub pkce_verifier: String,
+}
+
+pub async fn insert_login_state(
+    pool: &sqlx::PgPool,
+    state: &str,
+    login: &LoginState,
+    expires_at: &time::OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO "oidc_login" (state, provider, nonce, pkce_verifier, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+    )
+    .bind(state)
+    .bind(&login.provider)
+    .bind(&login.nonce)
+    .bind(&login.pkce_verifier)
+    .bind(expires_at)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Error inserting OIDC login state");
+        e
+    })?;
+
+    Ok(())
+}
+
+/// Removes and returns the sign-in so a state can only be used once. Expired ones are
+/// cleaned up along the way. `RowNotFound` when it is unknown or expired
+pub async fn take_login_state(pool: &sqlx::PgPool, state: &str) -> Result<LoginState, sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM "oidc_login" WHERE expires_at <= NOW()
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    let row = sqlx::query(
+        r#"
+        DELETE FROM "oidc_login" WHERE state = $1 RETURNING provider, nonce, pkce_verifier
+        "#,
+    )
+    .bind(state)
+    .fetch_optional(pool)
+    .await?;
+
+    match row {
+        Some(r) => Ok(LoginState {
+            provider: r.try_get("provider")?,
+            nonce: r.try_get("nonce")?,
+            pkce_verifier: r.try_get("pkce_verifier")?,
+        }),
+        None => Err(sqlx::Error::RowNotFound),
+    }
+}
+
+/// User the provider's subject is linked to
+pub async fn get_linked_user(
+    pool: &sqlx::PgPool,
+    provider: &str,
+    subject: &str,
+) -> Result<uuid::Uuid, sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        UPDATE "user_identity" SET last_login = NOW()
+        WHERE provider = $1 AND subject = $2 RETURNING user_id
+        "#,
+    )
+    .bind(provider)
+    .bind(subject)
+    .fetch_optional(pool)
+    .await?;
+
+    match row {
+        Some(r) => r.try_get("user_id"),
+        None => Err(sqlx::Error::RowNotFound),
+    }
+}
+
+pub async fn link<'e, E: sqlx::PgExecutor<'e>>(
+    executor: E,
+    provider: &str,
+    subject: &str,
+    user_id: &uuid::Uuid,
+    email: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO "user_identity" (provider, subject, user_id, email, last_login)
+        VALUES ($1, $2, $3, $4, NOW())
+        "#,
+    )
+    .bind(provider)
+    .bind(subject)
+    .bind(user_id)
+    .bind(email)
+    .execute(executor)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Error linking identity");
+        e
+    })?;
+
+    Ok(())
+}
@@ -48,3 +48,119 @@ pub async fn get_passphrase(
         Err(err) => Err(err),
     }
 }
+
+pub async fn insert(
+    pool: &sqlx::PgPool,
+    username: &String,
+    passphrase: &String,
+    is_admin: bool,
+) -> Result<(uuid::Uuid, time::OffsetDateTime), sqlx::Error> {
+    let row = sqlx::query(
+        r#"
+        INSERT INTO "passphrase" (username, passphrase, is_admin) VALUES ($1, $2, $3) RETURNING id, date_created;
+        "#,
+    )
+    .bind(username)
+    .bind(passphrase)
+    .bind(is_admin)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Error inserting passphrase");
+        e
+    })?;
+
+    let id: uuid::Uuid = row.try_get("id")?;
+    let date_created: time::OffsetDateTime = row.try_get("date_created")?;
+    Ok((id, date_created))
+}
+
+pub async fn update_passphrase(
+    pool: &sqlx::PgPool,
+    id: &uuid::Uuid,
+    passphrase: &String,
+) -> Result<(), sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE "passphrase" SET passphrase = $1 WHERE id = $2;
+        "#,
+    )
+    .bind(passphrase)
+    .bind(id)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Error updating passphrase");
+        e
+    })?;
+
+    if result.rows_affected() == 0 {
+        Err(sqlx::Error::RowNotFound)
+    } else {
+        Ok(())
+    }
+}
+
+/// Every service account as `(id, username, is_admin, date_created)`, oldest first
+pub async fn list(
+    pool: &sqlx::PgPool,
+) -> Result<Vec<(uuid::Uuid, String, bool, time::OffsetDateTime)>, sqlx::Error> {
+    let rows = sqlx::query(
+        r#"
+        SELECT id, username, is_admin, date_created FROM "passphrase" ORDER BY date_created;
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok((
+                row.try_get("id")?,
+                row.try_get("username")?,
+                row.try_get("is_admin")?,
+                row.try_get("date_created")?,
+            ))
+        })
+        .collect()
+}
+
+/// Whether the service account may use administrative endpoints. `RowNotFound` when
+/// there is no such account
+pub async fn is_admin(pool: &sqlx::PgPool, id: &uuid::Uuid) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT is_admin FROM "passphrase" WHERE id = $1;
+        "#,
+    )
+    .bind(id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Grants or revokes access to administrative endpoints
+pub async fn set_admin(
+    pool: &sqlx::PgPool,
+    id: &uuid::Uuid,
+    is_admin: bool,
+) -> Result<(), sqlx::Error> {
+    let result = sqlx::query(
+        r#"
+        UPDATE "passphrase" SET is_admin = $1 WHERE id = $2;
+        "#,
+    )
+    .bind(is_admin)
+    .bind(id)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!(error = %e, "Error updating service account");
+        e
+    })?;
+
+    if result.rows_affected() == 0 {
+        Err(sqlx::Error::RowNotFound)
+    } else {
+        Ok(())
+    }
+}
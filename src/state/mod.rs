@@ -0,0 +1,96 @@
+/// State shared with every handler
+#[derive(Clone)]
+pub struct AppState {
+    pub pool: sqlx::PgPool,
+    pub keys: std::sync::Arc<Keys>,
+    pub config: std::sync::Arc<crate::config::Config>,
+    /// Runs password hashing off the async executor
+    pub hasher: std::sync::Arc<crate::hashing::workers::Hasher>,
+    /// Rules new passwords are checked against
+    pub password_policy: std::sync::Arc<crate::password_policy::Policy>,
+    /// Tells the registration webhook about moderated registrations
+    pub notifier: std::sync::Arc<crate::notify::Notifier>,
+    /// Backends login credentials are checked against
+    pub authentication: std::sync::Arc<crate::authentication::Chain>,
+    /// Sends email, e.g. login links
+    pub mailer: std::sync::Arc<dyn crate::mail::Mailer>,
+    /// Counts login link requests per email and per client address
+    pub magic_link_throttle: std::sync::Arc<crate::throttle::Throttle>,
+    /// Talks to the configured OpenID Connect providers and caches their keys
+    pub oidc: std::sync::Arc<crate::oidc::Client>,
+    /// Set once shutdown has started so readiness checks fail while requests drain
+    pub draining: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+/// Keys used to sign and verify tokens
+pub struct Keys {
+    pub secret: String,
+}
+
+impl AppState {
+    pub fn new(pool: sqlx::PgPool, keys: Keys, config: crate::config::Config) -> Self {
+        let hasher = std::sync::Arc::new(crate::hashing::workers::Hasher::new(&config.hashing));
+
+        AppState {
+            authentication: std::sync::Arc::new(crate::authentication::Chain::from_config(
+                &config, &pool, &hasher,
+            )),
+            pool,
+            keys: std::sync::Arc::new(keys),
+            hasher,
+            password_policy: std::sync::Arc::new(crate::password_policy::Policy::new(
+                &config.password,
+            )),
+            notifier: std::sync::Arc::new(crate::notify::Notifier::new(&config.registration)),
+            mailer: crate::mail::from_config(&config.mail),
+            magic_link_throttle: std::sync::Arc::new(crate::throttle::Throttle::new(
+                std::time::Duration::from_secs(config.magic_link.limit_window),
+            )),
+            oidc: std::sync::Arc::new(crate::oidc::Client::new()),
+            config: std::sync::Arc::new(config),
+            draining: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false)),
+        }
+    }
+
+    /// Replaces the password policy, e.g. with one holding a breach list
+    pub fn with_password_policy(mut self, policy: crate::password_policy::Policy) -> Self {
+        self.password_policy = std::sync::Arc::new(policy);
+        self
+    }
+
+    /// Replaces the authentication backends, e.g. with a directory stand-in in tests
+    pub fn with_authentication(mut self, chain: crate::authentication::Chain) -> Self {
+        self.authentication = std::sync::Arc::new(chain);
+        self
+    }
+
+    /// Replaces the mailer, e.g. with an outbox in tests
+    pub fn with_mailer(mut self, mailer: std::sync::Arc<dyn crate::mail::Mailer>) -> Self {
+        self.mailer = mailer;
+        self
+    }
+
+    pub fn start_draining(&self) {
+        self.draining
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(std::sync::atomic::Ordering::SeqCst)
+    }
+}
+
+impl Keys {
+    /// Loads the signing keys from the environment
+    pub async fn load() -> Result<Keys, std::io::Error> {
+        let secret = icarus_envy::environment::get_secret_key().await.value;
+        if secret.is_empty() {
+            Err(std::io::Error::other(format!(
+                "{} is not set",
+                crate::token_stuff::KEY_ENV
+            )))
+        } else {
+            Ok(Keys { secret })
+        }
+    }
+}
@@ -0,0 +1,148 @@
+pub mod logging;
+
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+/// Names of the exported metrics
+pub mod names {
+    pub const LOGINS: &str = "icarus_auth_logins_total";
+    pub const REGISTRATIONS: &str = "icarus_auth_registrations_total";
+    pub const SERVICE_LOGINS: &str = "icarus_auth_service_logins_total";
+    pub const TOKEN_REFRESHES: &str = "icarus_auth_token_refreshes_total";
+    pub const TOKEN_EXCHANGES: &str = "icarus_auth_token_exchanges_total";
+    pub const TOKEN_VERIFICATION_FAILURES: &str = "icarus_auth_token_verification_failures_total";
+    pub const HASH_DURATION: &str = "icarus_auth_argon2_duration_seconds";
+    pub const HASH_REJECTIONS: &str = "icarus_auth_argon2_rejections_total";
+    pub const REQUEST_DURATION: &str = "icarus_auth_http_request_duration_seconds";
+    pub const POOL_CONNECTIONS: &str = "icarus_auth_db_pool_connections";
+    pub const POOL_MAX_CONNECTIONS: &str = "icarus_auth_db_pool_max_connections";
+}
+
+/// Tracing target of audit events, so they can be filtered or routed separately
+pub const AUDIT_TARGET: &str = "audit";
+
+pub const SUCCESS: &str = "success";
+pub const FAILURE: &str = "failure";
+
+const HASH_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+const REQUEST_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+static HANDLE: std::sync::OnceLock<PrometheusHandle> = std::sync::OnceLock::new();
+
+/// Returns the handle of the process wide recorder, installing it on first use
+pub fn handle() -> &'static PrometheusHandle {
+    HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full(String::from(names::HASH_DURATION)),
+                HASH_BUCKETS,
+            )
+            .and_then(|builder| {
+                builder.set_buckets_for_metric(
+                    Matcher::Full(String::from(names::REQUEST_DURATION)),
+                    REQUEST_BUCKETS,
+                )
+            })
+            .and_then(|builder| builder.install_recorder())
+            .expect("Failed to install metrics recorder")
+    })
+}
+
+/// `identifier` is how the user was looked up, see `repo::user::identifier`
+pub fn login(outcome: &'static str, reason: &'static str, identifier: &'static str) {
+    metrics::counter!(names::LOGINS, "outcome" => outcome, "reason" => reason, "identifier" => identifier)
+        .increment(1);
+    tracing::info!(target: AUDIT_TARGET, event = "login", outcome, reason, identifier);
+}
+
+pub fn registration(outcome: &'static str, reason: &'static str) {
+    metrics::counter!(names::REGISTRATIONS, "outcome" => outcome, "reason" => reason).increment(1);
+}
+
+pub fn service_login(outcome: &'static str) {
+    metrics::counter!(names::SERVICE_LOGINS, "outcome" => outcome).increment(1);
+}
+
+pub fn token_refresh(outcome: &'static str, reason: &'static str) {
+    metrics::counter!(names::TOKEN_REFRESHES, "outcome" => outcome, "reason" => reason)
+        .increment(1);
+}
+
+/// A service asked to act for a user
+pub fn token_exchange(outcome: &'static str, reason: &'static str) {
+    metrics::counter!(names::TOKEN_EXCHANGES, "outcome" => outcome, "reason" => reason)
+        .increment(1);
+    tracing::info!(target: AUDIT_TARGET, event = "token_exchange", outcome, reason);
+}
+
+pub fn token_verification_failure() {
+    metrics::counter!(names::TOKEN_VERIFICATION_FAILURES).increment(1);
+}
+
+pub fn hash_duration(operation: &'static str, elapsed: std::time::Duration) {
+    metrics::histogram!(names::HASH_DURATION, "operation" => operation)
+        .record(elapsed.as_secs_f64());
+}
+
+/// A hash was refused because every hashing slot stayed busy
+pub fn hash_rejected() {
+    metrics::counter!(names::HASH_REJECTIONS).increment(1);
+}
+
+pub fn pool(pool: &sqlx::PgPool, max_connections: u32) {
+    let size = pool.size() as usize;
+    let idle = pool.num_idle();
+    metrics::gauge!(names::POOL_CONNECTIONS, "state" => "active")
+        .set(size.saturating_sub(idle) as f64);
+    metrics::gauge!(names::POOL_CONNECTIONS, "state" => "idle").set(idle as f64);
+    metrics::gauge!(names::POOL_MAX_CONNECTIONS).set(max_connections as f64);
+}
+
+/// Middleware recording the latency of every request by route and status
+pub async fn track_latency(
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let start = std::time::Instant::now();
+    let method = request.method().to_string();
+    // Use the route template so path parameters don't create new series
+    let path = match request.extensions().get::<axum::extract::MatchedPath>() {
+        Some(matched) => matched.as_str().to_string(),
+        None => String::from("unmatched"),
+    };
+
+    let response = next.run(request).await;
+
+    metrics::histogram!(
+        names::REQUEST_DURATION,
+        "method" => method,
+        "path" => path,
+        "status" => response.status().as_u16().to_string()
+    )
+    .record(start.elapsed().as_secs_f64());
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters() {
+        let handle = handle();
+        login(FAILURE, "invalid_password", "username");
+        hash_duration("hash", std::time::Duration::from_millis(20));
+
+        let rendered = handle.render();
+        assert!(
+            rendered.contains(names::LOGINS),
+            "Login counter missing: {rendered}"
+        );
+        assert!(
+            rendered.contains(&format!("{}_bucket", names::HASH_DURATION)),
+            "Hash histogram missing: {rendered}"
+        );
+    }
+}
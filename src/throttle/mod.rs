@@ -0,0 +1,71 @@
+//! In-memory request counters for endpoints anyone can call, such as asking for a
+//! login link. Counts are per instance and reset when it restarts, which is enough to
+//! stop a single client from flooding an inbox or the mail server.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Counted keys kept before those whose window has passed are dropped
+const PRUNE_AT: usize = 10_000;
+
+/// Allows a number of hits per key within a fixed window
+pub struct Throttle {
+    window: Duration,
+    hits: std::sync::Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl Throttle {
+    pub fn new(window: Duration) -> Self {
+        Throttle {
+            window,
+            hits: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a hit for the key, and whether it is within the limit
+    pub fn allow(&self, key: &str, limit: u32) -> bool {
+        self.allow_at(key, limit, Instant::now())
+    }
+
+    fn allow_at(&self, key: &str, limit: u32, now: Instant) -> bool {
+        let mut hits = self.hits.lock().unwrap_or_else(|err| err.into_inner());
+        if hits.len() >= PRUNE_AT {
+            hits.retain(|_, (start, _)| now.duration_since(*start) < self.window);
+        }
+
+        let (start, count) = hits.entry(String::from(key)).or_insert((now, 0));
+        if now.duration_since(*start) >= self.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= limit {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow() {
+        let throttle = Throttle::new(Duration::from_secs(60));
+        let now = Instant::now();
+
+        assert!(throttle.allow_at("bob", 2, now));
+        assert!(throttle.allow_at("bob", 2, now));
+        assert!(!throttle.allow_at("bob", 2, now));
+        assert!(throttle.allow_at("alice", 2, now));
+
+        // A new window starts the count over
+        let later = now + Duration::from_secs(60);
+        assert!(throttle.allow_at("bob", 2, later));
+        assert!(throttle.allow_at("bob", 2, later));
+        assert!(!throttle.allow_at("bob", 2, later));
+
+        assert!(!throttle.allow_at("carol", 0, now));
+    }
+}
@@ -11,98 +11,422 @@ pub const MESSAGE: &str = "Something random";
 pub const ISSUER: &str = "icarus_auth";
 pub const AUDIENCE: &str = "icarus";
 
+/// Claim holding the unix time the session was started at, carried over on refresh
+pub const SESSION_START_CLAIM: &str = "auth_time";
+/// Claim naming the party acting for the user in a delegated token, see RFC 8693 section 4.1
+pub const ACTOR_CLAIM: &str = "act";
+/// Claim holding the space separated scopes a delegated token was asked for
+pub const SCOPE_CLAIM: &str = "scope";
+/// Claim telling app, service and delegated tokens apart
+pub const TOKEN_TYPE_CLAIM: &str = "token_type";
+/// Claim legacy tokens carried the user or service id in
+pub const LEGACY_ID_CLAIM: &str = "id";
+
+/// Kinds of tokens issued by the service, each with its own lifetime
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenKind {
+    App,
+    Service,
+    ServiceRefresh,
+    /// Issued to a service acting for a user, see [`create_delegated_token`]
+    Delegated,
+}
+
+impl TokenKind {
+    /// Value of the `token_type` claim
+    pub fn token_type(&self) -> &'static str {
+        match self {
+            TokenKind::App => APP_TOKEN_TYPE,
+            TokenKind::Service | TokenKind::ServiceRefresh => SERVICE_TOKEN_TYPE,
+            TokenKind::Delegated => DELEGATED_TOKEN_TYPE,
+        }
+    }
+}
+
 pub fn get_issued() -> time::Result<time::OffsetDateTime> {
     Ok(time::OffsetDateTime::now_utc())
 }
 
-pub fn get_expiration(issued: &time::OffsetDateTime) -> Result<time::OffsetDateTime, time::Error> {
-    let duration_expire = time::Duration::hours(4);
-    Ok(*issued + duration_expire)
+pub fn get_expiration(
+    policy: &crate::config::Token,
+    kind: TokenKind,
+    issued: &time::OffsetDateTime,
+) -> Result<time::OffsetDateTime, time::Error> {
+    Ok(*issued + policy.ttl(kind))
+}
+
+/// Expiration of a refreshed token, or `None` when the session can no longer be extended.
+///
+/// With sliding expiration every refresh grants a full lifetime from now, otherwise the
+/// lifetime is counted from the start of the session. Either way the result never exceeds
+/// the maximum session lifetime.
+pub fn get_refresh_expiration(
+    policy: &crate::config::Token,
+    session_start: &time::OffsetDateTime,
+    now: &time::OffsetDateTime,
+) -> Option<time::OffsetDateTime> {
+    let ttl = policy.ttl(TokenKind::ServiceRefresh);
+    let mut expiration = if policy.sliding_expiration {
+        *now + ttl
+    } else {
+        *session_start + ttl
+    };
+
+    if let Some(max_session) = policy.max_session() {
+        expiration = expiration.min(*session_start + max_session);
+    }
+
+    if expiration > *now {
+        Some(expiration)
+    } else {
+        None
+    }
 }
 
 pub fn create_token(
     provided_key: &String,
     id: &uuid::Uuid,
+    policy: &crate::config::Token,
 ) -> Result<(String, i64), josekit::JoseError> {
-    let resource = icarus_models::token::TokenResource {
-        message: String::from(MESSAGE),
-        issuer: String::from(ISSUER),
-        audiences: vec![String::from(AUDIENCE)],
-        id: *id,
-    };
-    icarus_models::token::create_token(provided_key, &resource, time::Duration::hours(4))
+    let issued = time::OffsetDateTime::now_utc();
+    let expiration = issued + policy.ttl(TokenKind::App);
+    encode(provided_key, TokenKind::App, id, &issued, &expiration)
 }
 
 pub fn create_service_token(
     provided: &String,
     id: &uuid::Uuid,
+    policy: &crate::config::Token,
 ) -> Result<(String, i64), josekit::JoseError> {
-    let resource = icarus_models::token::TokenResource {
-        message: String::from(SERVICE_SUBJECT),
-        issuer: String::from(ISSUER),
-        audiences: vec![String::from(AUDIENCE)],
-        id: *id,
-    };
-    icarus_models::token::create_token(provided, &resource, time::Duration::hours(1))
+    let issued = time::OffsetDateTime::now_utc();
+    let expiration = issued + policy.ttl(TokenKind::Service);
+    encode(provided, TokenKind::Service, id, &issued, &expiration)
 }
 
+/// Creates a service token continuing the session started at `session_start`
 pub fn create_service_refresh_token(
     key: &String,
     id: &uuid::Uuid,
+    session_start: &time::OffsetDateTime,
+    expiration: &time::OffsetDateTime,
 ) -> Result<(String, i64), josekit::JoseError> {
-    let resource = icarus_models::token::TokenResource {
-        message: String::from(SERVICE_SUBJECT),
-        issuer: String::from(ISSUER),
-        audiences: vec![String::from(AUDIENCE)],
-        id: *id,
-    };
-    icarus_models::token::create_token(key, &resource, time::Duration::hours(4))
+    encode(
+        key,
+        TokenKind::ServiceRefresh,
+        id,
+        session_start,
+        expiration,
+    )
 }
 
-pub fn verify_token(key: &String, token: &String) -> bool {
-    match get_payload(key, token) {
-        Ok((payload, _header)) => match payload.subject() {
-            Some(_sub) => true,
-            None => false,
-        },
-        Err(_err) => false,
+/// Creates a token for the service `actor` to act as the user `id`. It belongs to the
+/// user's session, carries the actor in its `act` claim and the requested scopes, and
+/// is only accepted where delegated tokens are
+pub fn create_delegated_token(
+    key: &String,
+    id: &uuid::Uuid,
+    actor: &uuid::Uuid,
+    scope: Option<&str>,
+    session_start: &time::OffsetDateTime,
+    expiration: &time::OffsetDateTime,
+) -> Result<(String, i64), josekit::JoseError> {
+    let mut payload = claims(TokenKind::Delegated, id, session_start, expiration)?;
+    payload.set_claim(ACTOR_CLAIM, Some(serde_json::json!({ "sub": actor })))?;
+    if let Some(scope) = scope {
+        payload.set_claim(SCOPE_CLAIM, Some(serde_json::json!(scope)))?;
     }
+    sign(key, &payload, expiration)
+}
+
+fn encode(
+    key: &String,
+    kind: TokenKind,
+    id: &uuid::Uuid,
+    session_start: &time::OffsetDateTime,
+    expiration: &time::OffsetDateTime,
+) -> Result<(String, i64), josekit::JoseError> {
+    let payload = claims(kind, id, session_start, expiration)?;
+    sign(key, &payload, expiration)
+}
+
+fn claims(
+    kind: TokenKind,
+    id: &uuid::Uuid,
+    session_start: &time::OffsetDateTime,
+    expiration: &time::OffsetDateTime,
+) -> Result<jwt::JwtPayload, josekit::JoseError> {
+    let now = std::time::SystemTime::from(time::OffsetDateTime::now_utc());
+
+    let mut payload = jwt::JwtPayload::new();
+    payload.set_subject(id.to_string());
+    payload.set_issuer(ISSUER);
+    payload.set_audience(vec![AUDIENCE]);
+    payload.set_jwt_id(uuid::Uuid::new_v4().to_string());
+    payload.set_issued_at(&now);
+    payload.set_not_before(&now);
+    payload.set_expires_at(&std::time::SystemTime::from(*expiration));
+    payload.set_claim(TOKEN_TYPE_CLAIM, Some(serde_json::json!(kind.token_type())))?;
+    // Kept while services that read the id from it move to `sub`
+    payload.set_claim(LEGACY_ID_CLAIM, Some(serde_json::json!(id)))?;
+    payload.set_claim(
+        SESSION_START_CLAIM,
+        Some(serde_json::json!(session_start.unix_timestamp())),
+    )?;
+
+    Ok(payload)
+}
+
+fn sign(
+    key: &String,
+    payload: &jwt::JwtPayload,
+    expiration: &time::OffsetDateTime,
+) -> Result<(String, i64), josekit::JoseError> {
+    let mut header = josekit::jws::JwsHeader::new();
+    header.set_token_type("JWT");
+
+    let signer = Hs256.signer_from_bytes(key.as_bytes())?;
+    let token = jwt::encode_with_signer(payload, &header, &signer)?;
+    Ok((token, expiration.unix_timestamp()))
+}
+
+/// Claims of a token that passed [`decode`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claims {
+    pub iss: String,
+    pub aud: Vec<String>,
+    /// Id of the user or service the token is for
+    pub sub: uuid::Uuid,
+    /// One of [`APP_TOKEN_TYPE`], [`SERVICE_TOKEN_TYPE`] or [`DELEGATED_TOKEN_TYPE`]
+    pub token_type: String,
+    /// `None` in legacy tokens
+    pub jti: Option<String>,
+    pub iat: time::OffsetDateTime,
+    /// `None` in legacy tokens
+    pub nbf: Option<time::OffsetDateTime>,
+    pub exp: time::OffsetDateTime,
+    /// Start of the session, the issue time in tokens from before the claim existed
+    pub auth_time: time::OffsetDateTime,
+    /// Service acting for the user in a delegated token
+    pub act: Option<uuid::Uuid>,
+    pub scope: Option<String>,
+    /// Issued before typed claims, with the token type in a magic subject
+    pub legacy: bool,
+}
+
+/// Why a token was refused
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// Not a token signed with the key
+    Signature,
+    /// A required claim is missing or has the wrong type
+    Malformed(&'static str),
+    Issuer,
+    Audience,
+    Expired,
+    NotYetValid,
+    /// A legacy token while they are not accepted
+    Legacy,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Signature => write!(f, "Invalid token signature"),
+            Error::Malformed(claim) => write!(f, "Missing or invalid `{claim}` claim"),
+            Error::Issuer => write!(f, "Token was not issued by {ISSUER}"),
+            Error::Audience => write!(f, "Token is not meant for {AUDIENCE}"),
+            Error::Expired => write!(f, "Token has expired"),
+            Error::NotYetValid => write!(f, "Token is not valid yet"),
+            Error::Legacy => write!(f, "Legacy tokens are no longer accepted"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        std::io::Error::other(err.to_string())
+    }
+}
+
+/// Checks the signature, issuer, audience and validity period of a token and returns
+/// its claims. Times are allowed to be off by the policy's clock skew. Tokens from
+/// before typed claims are read while the policy accepts them
+pub fn decode(
+    key: &String,
+    token: &String,
+    policy: &crate::config::Token,
+) -> Result<Claims, Error> {
+    let (payload, _header) = get_payload(key, token).map_err(|_err| Error::Signature)?;
+    validate(&payload, policy, time::OffsetDateTime::now_utc())
+}
+
+fn validate(
+    payload: &jwt::JwtPayload,
+    policy: &crate::config::Token,
+    now: time::OffsetDateTime,
+) -> Result<Claims, Error> {
+    let skew = time::Duration::seconds(policy.clock_skew);
+
+    if payload.issuer() != Some(ISSUER) {
+        return Err(Error::Issuer);
+    }
+    let aud: Vec<String> = payload
+        .audience()
+        .unwrap_or_default()
+        .into_iter()
+        .map(String::from)
+        .collect();
+    if !aud.iter().any(|aud| aud == AUDIENCE) {
+        return Err(Error::Audience);
+    }
+
+    let exp = payload
+        .expires_at()
+        .map(time::OffsetDateTime::from)
+        .ok_or(Error::Malformed("exp"))?;
+    if now >= exp + skew {
+        return Err(Error::Expired);
+    }
+    let nbf = payload.not_before().map(time::OffsetDateTime::from);
+    if nbf.is_some_and(|nbf| now + skew < nbf) {
+        return Err(Error::NotYetValid);
+    }
+    let iat = payload
+        .issued_at()
+        .map(time::OffsetDateTime::from)
+        .ok_or(Error::Malformed("iat"))?;
+
+    let subject = payload.subject().ok_or(Error::Malformed("sub"))?;
+    let (sub, token_type, legacy) = match payload.claim(TOKEN_TYPE_CLAIM) {
+        Some(token_type) => {
+            let token_type = token_type
+                .as_str()
+                .filter(|token_type| {
+                    [APP_TOKEN_TYPE, SERVICE_TOKEN_TYPE, DELEGATED_TOKEN_TYPE].contains(token_type)
+                })
+                .ok_or(Error::Malformed(TOKEN_TYPE_CLAIM))?;
+            let sub = uuid::Uuid::parse_str(subject).map_err(|_err| Error::Malformed("sub"))?;
+            (sub, String::from(token_type), false)
+        }
+        None => {
+            if !policy.accept_legacy {
+                return Err(Error::Legacy);
+            }
+            let token_type = match subject {
+                APP_SUBJECT => APP_TOKEN_TYPE,
+                SERVICE_SUBJECT => SERVICE_TOKEN_TYPE,
+                _ => return Err(Error::Malformed("sub")),
+            };
+            let sub = payload
+                .claim(LEGACY_ID_CLAIM)
+                .and_then(|id| id.as_str())
+                .and_then(|id| uuid::Uuid::parse_str(id).ok())
+                .ok_or(Error::Malformed(LEGACY_ID_CLAIM))?;
+            (sub, String::from(token_type), true)
+        }
+    };
+
+    let auth_time = match payload.claim(SESSION_START_CLAIM) {
+        Some(auth_time) => auth_time
+            .as_i64()
+            .and_then(|timestamp| time::OffsetDateTime::from_unix_timestamp(timestamp).ok())
+            .ok_or(Error::Malformed(SESSION_START_CLAIM))?,
+        None => iat,
+    };
+    let act = match payload.claim(ACTOR_CLAIM) {
+        Some(actor) => Some(
+            actor
+                .get("sub")
+                .and_then(|sub| sub.as_str())
+                .and_then(|sub| uuid::Uuid::parse_str(sub).ok())
+                .ok_or(Error::Malformed(ACTOR_CLAIM))?,
+        ),
+        None => None,
+    };
+    if (token_type == DELEGATED_TOKEN_TYPE) != act.is_some() {
+        return Err(Error::Malformed(ACTOR_CLAIM));
+    }
+
+    Ok(Claims {
+        iss: String::from(ISSUER),
+        aud,
+        sub,
+        token_type,
+        jti: payload.jwt_id().map(String::from),
+        iat,
+        nbf,
+        exp,
+        auth_time,
+        act,
+        scope: payload
+            .claim(SCOPE_CLAIM)
+            .and_then(|scope| scope.as_str())
+            .map(String::from),
+        legacy,
+    })
 }
 
-pub fn extract_id_from_token(key: &String, token: &String) -> Result<uuid::Uuid, std::io::Error> {
-    match get_payload(key, token) {
-        Ok((payload, _header)) => match payload.claim("id") {
-            Some(id) => match uuid::Uuid::parse_str(id.as_str().unwrap()) {
-                Ok(extracted) => Ok(extracted),
-                Err(err) => Err(std::io::Error::other(err.to_string())),
-            },
-            None => Err(std::io::Error::other("No claim found")),
-        },
-        Err(err) => Err(std::io::Error::other(err.to_string())),
+pub fn verify_token(key: &String, token: &String, policy: &crate::config::Token) -> bool {
+    let verified = decode(key, token, policy).is_ok();
+
+    if !verified {
+        crate::telemetry::token_verification_failure();
     }
+
+    verified
+}
+
+pub fn extract_id_from_token(
+    key: &String,
+    token: &String,
+    policy: &crate::config::Token,
+) -> Result<uuid::Uuid, std::io::Error> {
+    Ok(decode(key, token, policy)?.sub)
+}
+
+/// Start of the session a token belongs to. Tokens issued before the claim existed
+/// fall back to their issue time
+pub fn extract_session_start(
+    key: &String,
+    token: &String,
+    policy: &crate::config::Token,
+) -> Result<time::OffsetDateTime, std::io::Error> {
+    Ok(decode(key, token, policy)?.auth_time)
+}
+
+/// Expiration of a token
+pub fn extract_expiration(
+    key: &String,
+    token: &String,
+    policy: &crate::config::Token,
+) -> Result<time::OffsetDateTime, std::io::Error> {
+    Ok(decode(key, token, policy)?.exp)
+}
+
+/// Service acting for the user in a delegated token, `None` in other tokens
+pub fn extract_actor(
+    key: &String,
+    token: &String,
+    policy: &crate::config::Token,
+) -> Result<Option<uuid::Uuid>, std::io::Error> {
+    Ok(decode(key, token, policy)?.act)
 }
 
 pub const APP_TOKEN_TYPE: &str = "Icarus_App";
-pub const APP_SUBJECT: &str = "Something random";
 pub const SERVICE_TOKEN_TYPE: &str = "Icarus_Service";
+pub const DELEGATED_TOKEN_TYPE: &str = "Icarus_Delegated";
+/// Subjects legacy tokens told their type with, instead of naming the user or service
+pub const APP_SUBJECT: &str = "Something random";
 pub const SERVICE_SUBJECT: &str = "Service random";
 
-pub fn get_token_type(key: &String, token: &String) -> Result<String, std::io::Error> {
-    match get_payload(key, token) {
-        Ok((payload, _header)) => match payload.subject() {
-            Some(subject) => {
-                if subject == APP_SUBJECT {
-                    Ok(String::from(APP_TOKEN_TYPE))
-                } else if subject == SERVICE_SUBJECT {
-                    Ok(String::from(SERVICE_TOKEN_TYPE))
-                } else {
-                    Err(std::io::Error::other(String::from("Invalid subject")))
-                }
-            }
-            None => Err(std::io::Error::other(String::from("Invalid payload"))),
-        },
-        Err(err) => Err(std::io::Error::other(err.to_string())),
-    }
+pub fn get_token_type(
+    key: &String,
+    token: &String,
+    policy: &crate::config::Token,
+) -> Result<String, std::io::Error> {
+    Ok(decode(key, token, policy)?.token_type)
 }
 
 pub fn is_token_type_valid(token_type: &String) -> bool {
@@ -128,9 +452,10 @@ mod tests {
             .block_on(icarus_envy::environment::get_secret_key())
             .value;
         let id = uuid::Uuid::new_v4();
-        match create_token(&special_key, &id) {
+        let policy = crate::config::Token::default();
+        match create_token(&special_key, &id, &policy) {
             Ok((token, _duration)) => {
-                let result = verify_token(&special_key, &token);
+                let result = verify_token(&special_key, &token, &policy);
                 assert!(result, "Token not verified");
             }
             Err(err) => {
@@ -138,4 +463,181 @@ mod tests {
             }
         };
     }
+
+    #[test]
+    fn test_delegated_token() {
+        let key = String::from("delegation-test-key");
+        let id = uuid::Uuid::new_v4();
+        let actor = uuid::Uuid::new_v4();
+        let session_start = time::OffsetDateTime::now_utc() - time::Duration::hours(1);
+        let expiration = time::OffsetDateTime::now_utc() + time::Duration::minutes(5);
+
+        let (token, _expiration) = create_delegated_token(
+            &key,
+            &id,
+            &actor,
+            Some("songs:read"),
+            &session_start,
+            &expiration,
+        )
+        .unwrap();
+        let policy = crate::config::Token::default();
+        let claims = decode(&key, &token, &policy).unwrap();
+        assert_eq!(claims.token_type, DELEGATED_TOKEN_TYPE);
+        assert_eq!(claims.sub, id);
+        assert_eq!(claims.act, Some(actor));
+        assert_eq!(claims.scope.as_deref(), Some("songs:read"));
+        assert_eq!(
+            claims.auth_time.unix_timestamp(),
+            session_start.unix_timestamp()
+        );
+
+        let (app_token, _expiration) = create_token(&key, &id, &policy).unwrap();
+        assert_eq!(extract_actor(&key, &app_token, &policy).unwrap(), None);
+    }
+
+    fn payload_at(now: time::OffsetDateTime) -> jwt::JwtPayload {
+        let id = uuid::Uuid::new_v4();
+        claims(
+            TokenKind::App,
+            &id,
+            &now,
+            &(now + time::Duration::minutes(5)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_validate() {
+        let policy = crate::config::Token::default();
+        let skew = time::Duration::seconds(policy.clock_skew);
+        let issued = time::OffsetDateTime::now_utc();
+
+        let payload = payload_at(issued);
+        let claims = validate(&payload, &policy, issued).unwrap();
+        assert_eq!(claims.token_type, APP_TOKEN_TYPE);
+        assert_eq!(claims.sub.to_string(), payload.subject().unwrap());
+        assert!(claims.jti.is_some());
+        assert!(!claims.legacy);
+
+        // Clocks may be off by the skew either way
+        assert!(
+            validate(
+                &payload,
+                &policy,
+                issued - skew + time::Duration::seconds(1)
+            )
+            .is_ok()
+        );
+        assert_eq!(
+            validate(
+                &payload,
+                &policy,
+                issued - skew - time::Duration::seconds(1)
+            ),
+            Err(Error::NotYetValid)
+        );
+        let expiry = claims.exp;
+        assert!(
+            validate(
+                &payload,
+                &policy,
+                expiry + skew - time::Duration::seconds(1)
+            )
+            .is_ok()
+        );
+        assert_eq!(
+            validate(&payload, &policy, expiry + skew),
+            Err(Error::Expired)
+        );
+
+        let mut payload = payload_at(issued);
+        payload.set_issuer("someone_else");
+        assert_eq!(validate(&payload, &policy, issued), Err(Error::Issuer));
+
+        let mut payload = payload_at(issued);
+        payload.set_audience(vec!["another_service"]);
+        assert_eq!(validate(&payload, &policy, issued), Err(Error::Audience));
+    }
+
+    #[test]
+    fn test_legacy_token() {
+        let issued = time::OffsetDateTime::now_utc();
+        let id = uuid::Uuid::new_v4();
+
+        // As tokens were issued before typed claims
+        let mut payload = jwt::JwtPayload::new();
+        payload.set_subject(APP_SUBJECT);
+        payload.set_issuer(ISSUER);
+        payload.set_audience(vec![AUDIENCE]);
+        payload.set_issued_at(&std::time::SystemTime::from(issued));
+        payload.set_expires_at(&std::time::SystemTime::from(
+            issued + time::Duration::hours(1),
+        ));
+        payload
+            .set_claim(LEGACY_ID_CLAIM, Some(serde_json::json!(id)))
+            .unwrap();
+
+        let policy = crate::config::Token::default();
+        let claims = validate(&payload, &policy, issued).unwrap();
+        assert!(claims.legacy);
+        assert_eq!(claims.sub, id);
+        assert_eq!(claims.token_type, APP_TOKEN_TYPE);
+        assert_eq!(claims.auth_time, claims.iat);
+
+        let policy = crate::config::Token {
+            accept_legacy: false,
+            ..Default::default()
+        };
+        assert_eq!(validate(&payload, &policy, issued), Err(Error::Legacy));
+    }
+
+    #[test]
+    fn test_sliding_refresh_expiration() {
+        let policy = crate::config::Token {
+            sliding_expiration: true,
+            ..Default::default()
+        };
+        let session_start = time::OffsetDateTime::now_utc() - time::Duration::hours(10);
+        let now = time::OffsetDateTime::now_utc();
+
+        let expiration = get_refresh_expiration(&policy, &session_start, &now).unwrap();
+        assert_eq!(expiration, now + policy.ttl(TokenKind::ServiceRefresh));
+    }
+
+    #[test]
+    fn test_fixed_refresh_expiration() {
+        let policy = crate::config::Token {
+            sliding_expiration: false,
+            ..Default::default()
+        };
+        let now = time::OffsetDateTime::now_utc();
+
+        let session_start = now - time::Duration::hours(1);
+        let expiration = get_refresh_expiration(&policy, &session_start, &now).unwrap();
+        assert_eq!(
+            expiration,
+            session_start + policy.ttl(TokenKind::ServiceRefresh)
+        );
+
+        let session_start = now - policy.ttl(TokenKind::ServiceRefresh);
+        assert!(get_refresh_expiration(&policy, &session_start, &now).is_none());
+    }
+
+    #[test]
+    fn test_max_session_enforced() {
+        let policy = crate::config::Token {
+            sliding_expiration: true,
+            max_session: Some(time::Duration::hours(8).whole_seconds()),
+            ..Default::default()
+        };
+        let now = time::OffsetDateTime::now_utc();
+
+        let session_start = now - time::Duration::hours(6);
+        let expiration = get_refresh_expiration(&policy, &session_start, &now).unwrap();
+        assert_eq!(expiration, session_start + time::Duration::hours(8));
+
+        let session_start = now - time::Duration::hours(9);
+        assert!(get_refresh_expiration(&policy, &session_start, &now).is_none());
+    }
 }
@@ -0,0 +1,182 @@
+//! Validation and normalization of user supplied identity fields. Values are NFKC
+//! normalized and trimmed before they are checked and stored. Usernames and emails
+//! are also compared by their case folded form, see [`fold`].
+
+use unicode_normalization::UnicodeNormalization;
+
+pub const USERNAME_MIN_LENGTH: usize = 3;
+pub const USERNAME_MAX_LENGTH: usize = 32;
+/// Longest address allowed by RFC 5321
+pub const EMAIL_MAX_LENGTH: usize = 254;
+pub const NAME_MAX_LENGTH: usize = 100;
+/// E.164 allows at most 15 digits after the `+`
+const PHONE_MAX_DIGITS: usize = 15;
+const PHONE_MIN_DIGITS: usize = 7;
+
+/// A rejected request field
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: String::from(field),
+            message: message.into(),
+        }
+    }
+}
+
+/// NFKC normalized and trimmed
+pub fn normalize(value: &str) -> String {
+    value.nfkc().collect::<String>().trim().to_string()
+}
+
+/// Key two values are considered equal by: NFKC with full Unicode case folding, so
+/// `Straße`, `STRASSE` and `ｓｔｒａｓｓｅ` all fold to `strasse`
+pub fn fold(value: &str) -> String {
+    let folded = caseless::default_case_fold_str(&normalize(value));
+    folded.nfkc().collect()
+}
+
+/// Letters and digits plus `.`, `_` and `-`, starting with a letter or digit
+pub fn username(value: &str) -> Result<String, String> {
+    let username = normalize(value);
+    let length = username.chars().count();
+
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&length) {
+        return Err(format!(
+            "Username must be between {USERNAME_MIN_LENGTH} and {USERNAME_MAX_LENGTH} characters"
+        ));
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_alphanumeric() || matches!(c, '.' | '_' | '-'))
+    {
+        return Err(String::from(
+            "Username may only contain letters, digits, '.', '_' and '-'",
+        ));
+    }
+    if !username.starts_with(char::is_alphanumeric) {
+        return Err(String::from("Username must start with a letter or digit"));
+    }
+
+    Ok(username)
+}
+
+pub fn email(value: &str) -> Result<String, String> {
+    let email = normalize(value);
+
+    if email.is_empty() {
+        return Err(String::from("Email is required"));
+    }
+    if email.chars().count() > EMAIL_MAX_LENGTH {
+        return Err(format!(
+            "Email must be at most {EMAIL_MAX_LENGTH} characters"
+        ));
+    }
+    if !email_address::EmailAddress::is_valid(&email) {
+        return Err(String::from("Email is not a valid address"));
+    }
+
+    Ok(email)
+}
+
+/// Optional phone number in E.164 form, e.g. `+12025550123`. Spaces, dashes, dots and
+/// parentheses used for grouping are removed
+pub fn phone(value: &str) -> Result<String, String> {
+    let phone: String = normalize(value)
+        .chars()
+        .filter(|c| !matches!(c, ' ' | '-' | '.' | '(' | ')'))
+        .collect();
+
+    if phone.is_empty() {
+        return Ok(phone);
+    }
+
+    let valid = match phone.strip_prefix('+') {
+        Some(digits) => {
+            (PHONE_MIN_DIGITS..=PHONE_MAX_DIGITS).contains(&digits.len())
+                && digits.chars().all(|c| c.is_ascii_digit())
+                && !digits.starts_with('0')
+        }
+        None => false,
+    };
+
+    if valid {
+        Ok(phone)
+    } else {
+        Err(String::from(
+            "Phone must be in E.164 format, e.g. +12025550123",
+        ))
+    }
+}
+
+/// Optional first or last name
+pub fn name(value: &str) -> Result<String, String> {
+    let name = normalize(value);
+
+    if name.chars().count() > NAME_MAX_LENGTH {
+        return Err(format!("Must be at most {NAME_MAX_LENGTH} characters"));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(String::from("Must not contain control characters"));
+    }
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(fold("Straße"), "strasse");
+        assert_eq!(fold("STRASSE"), "strasse");
+        assert_eq!(fold("ｓｔｒａｓｓｅ"), "strasse");
+        assert_eq!(fold(" Dev@Null.com "), "dev@null.com");
+        // Composed and decomposed forms fold the same
+        assert_eq!(fold("Jos\u{e9}"), fold("Jose\u{301}"));
+    }
+
+    #[test]
+    fn test_username() {
+        assert_eq!(username(" somethingsss ").unwrap(), "somethingsss");
+        assert_eq!(username("ｂｏｂ").unwrap(), "bob");
+        assert!(username("José_1.x-y").is_ok());
+        assert!(username("ab").is_err());
+        assert!(username(&"a".repeat(33)).is_err());
+        assert!(username("bob smith").is_err());
+        assert!(username("bob@home").is_err());
+        assert!(username("_bob").is_err());
+    }
+
+    #[test]
+    fn test_email() {
+        assert_eq!(email(" dev@null.com").unwrap(), "dev@null.com");
+        assert!(email("").is_err());
+        assert!(email("dev").is_err());
+        assert!(email("dev@").is_err());
+        assert!(email("@null.com").is_err());
+    }
+
+    #[test]
+    fn test_phone() {
+        assert_eq!(phone("").unwrap(), "");
+        assert_eq!(phone("+1 (202) 555-0123").unwrap(), "+12025550123");
+        assert!(phone("2025550123").is_err());
+        assert!(phone("+0123456789").is_err());
+        assert!(phone("+1202555012345678").is_err());
+        assert!(phone("+1202abc0123").is_err());
+    }
+
+    #[test]
+    fn test_name() {
+        assert_eq!(name(" Bob ").unwrap(), "Bob");
+        assert!(name("Bo\u{7}b").is_err());
+        assert!(name(&"a".repeat(101)).is_err());
+    }
+}